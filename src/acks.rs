@@ -0,0 +1,106 @@
+use crate::config::DeviceConfig;
+use crate::record::Record;
+use std::mem::discriminant;
+use std::time::{Duration, Instant};
+
+/// Serials handed to tracked records start here, clear of the small ones used elsewhere.
+const FIRST_SERIAL: u32 = 1 << 24;
+
+struct Pending {
+    record: Record,
+    attempts: u32,
+    due: Instant,
+}
+
+/// Keeps records that need an ack until the keyboard confirms them, and tracks whether the
+/// keyboard's state can still be trusted. Only the newest record of each kind is chased, an
+/// older mute state isn't worth retransmitting once a newer one went out.
+pub(crate) struct Acks {
+    pending: Vec<Pending>,
+    next_serial: u32,
+    /// Set when a record may not have landed, cleared by reconciling.
+    doubt: bool,
+    last_reconcile: Instant,
+}
+
+impl Acks {
+    pub(crate) fn new() -> Self {
+        Self {
+            pending: Vec::new(),
+            next_serial: FIRST_SERIAL,
+            doubt: false,
+            last_reconcile: Instant::now(),
+        }
+    }
+
+    /// Starts waiting for `record`'s ack and returns it as it should be written. New records
+    /// get a serial of their own so the ack can be matched; retransmissions keep theirs.
+    pub(crate) fn track(&mut self, record: Record, config: &DeviceConfig) -> Record {
+        if let Some(pending) = self
+            .pending
+            .iter_mut()
+            .find(|pending| pending.record == record)
+        {
+            pending.attempts += 1;
+            pending.due = Instant::now() + Self::backoff(pending.attempts, config);
+            return record;
+        }
+
+        let record = Record::new(self.next_serial, record.data);
+        self.next_serial = self.next_serial.wrapping_add(1).max(FIRST_SERIAL);
+        self.pending
+            .retain(|pending| discriminant(&pending.record.data) != discriminant(&record.data));
+        self.pending.push(Pending {
+            record,
+            attempts: 0,
+            due: Instant::now() + Self::backoff(0, config),
+        });
+
+        record
+    }
+
+    pub(crate) fn ack(&mut self, serial: u32) {
+        self.pending
+            .retain(|pending| pending.record.serial != serial);
+    }
+
+    /// Records whose ack is overdue and should go out again. Ones out of retries are given
+    /// up on, which puts the keyboard's state in doubt.
+    pub(crate) fn overdue(&mut self, config: &DeviceConfig) -> Vec<Record> {
+        let now = Instant::now();
+        let before = self.pending.len();
+        self.pending
+            .retain(|pending| pending.due > now || pending.attempts < config.ack_retries);
+        if self.pending.len() < before {
+            self.doubt = true;
+        }
+
+        self.pending
+            .iter()
+            .filter(|pending| pending.due <= now)
+            .map(|pending| pending.record)
+            .collect()
+    }
+
+    pub(crate) fn doubt(&mut self) {
+        self.doubt = true;
+    }
+
+    /// Whether to re-send the keyboard's state now: every `device.reconcile_interval_s` while
+    /// there is doubt about it.
+    pub(crate) fn reconcile_due(&mut self, config: &DeviceConfig) -> bool {
+        if !self.doubt
+            || self.last_reconcile.elapsed() < Duration::from_secs(config.reconcile_interval_s)
+        {
+            return false;
+        }
+
+        self.doubt = false;
+        self.last_reconcile = Instant::now();
+        true
+    }
+
+    fn backoff(attempts: u32, config: &DeviceConfig) -> Duration {
+        Duration::from_millis(config.ack_timeout_ms) * 2u32.saturating_pow(attempts.min(16))
+    }
+}
@@ -1,224 +1,342 @@
-use std::ops::Mul;
-use windows::core::{Error, GUID};
-use windows::Win32::Devices::FunctionDiscovery::PKEY_Device_FriendlyName;
-use windows::Win32::Media::Audio::Endpoints::IAudioEndpointVolume;
-use windows::Win32::Media::Audio::{
-    eCapture, eCommunications, EDataFlow, ERole, IMMDevice, IMMDeviceCollection,
-    IMMDeviceEnumerator, MMDeviceEnumerator, DEVICE_STATE_ACTIVE,
-};
-use windows::Win32::System::Com::{
-    CoCreateInstance, CoInitializeEx, CLSCTX_ALL, CLSCTX_INPROC_SERVER, COINIT_MULTITHREADED,
-    STGM_READ,
-};
+use std::fmt::Display;
+
+#[cfg(windows)]
+mod wasapi;
+
+#[cfg(windows)]
+pub use wasapi::AudioManager;
 
 #[derive(Debug)]
 pub enum AudioError {
-    ComInitialize(Error),
-    DeviceEnumeratorError(Error),
+    #[cfg(windows)]
+    ComInitialize(windows::core::Error),
+    #[cfg(windows)]
+    DeviceEnumeratorError(windows::core::Error),
     OpenDevice(),
-    GetDevice(Error),
+    #[cfg(windows)]
+    GetDevice(windows::core::Error),
+    #[cfg(windows)]
+    SetDefaultDevice(windows::core::Error),
+    #[cfg(windows)]
+    Device(wasapi::AudioDeviceError),
+    #[cfg(windows)]
+    Session(wasapi::AudioSessionError),
+    /// There's no audio stack to drive, see [`NullAudio`].
+    Unsupported,
 }
 
-type AudioResult<T> = Result<T, AudioError>;
+pub(crate) type AudioResult<T> = Result<T, AudioError>;
 
-pub struct AudioManager {
-    immdevice_enumerator: IMMDeviceEnumerator,
+/// The parts of the system's audio stack the app drives, in plain IDs and snapshots so a fake
+/// one can stand in for it.
+pub(crate) trait AudioBackend {
+    /// Endpoints in one of `states`, for `data_flow` or both directions if it's `None`.
+    fn devices(
+        &self,
+        data_flow: Option<DataFlow>,
+        states: DeviceStateFilter,
+    ) -> AudioResult<Vec<AudioDeviceInfo>>;
+    fn default_device(
+        &self,
+        data_flow: DataFlow,
+        role: EndpointRole,
+    ) -> AudioResult<AudioDeviceInfo>;
+    fn make_default(&self, device_id: &str, role: EndpointRole) -> AudioResult<()>;
+    /// The endpoint `selector` currently stands for.
+    fn endpoint(
+        &self,
+        data_flow: DataFlow,
+        selector: &EndpointSelector,
+    ) -> AudioResult<AudioDeviceInfo>;
+    fn volume(&self, device_id: &str) -> AudioResult<u8>;
+    fn set_volume(&self, device_id: &str, percent: u8) -> AudioResult<()>;
+    fn muted(&self, device_id: &str) -> AudioResult<bool>;
+    fn set_muted(&self, device_id: &str, muted: bool) -> AudioResult<()>;
+    /// Peak level since the previous call, 0-100 on a logarithmic scale.
+    fn peak(&self, device_id: &str) -> AudioResult<u8>;
+    /// Sessions playing on any active output.
+    fn sessions(&self) -> AudioResult<Vec<AudioSessionInfo>>;
+    /// Applies to every output session of the process.
+    fn set_session_volume(&self, process_id: u32, percent: u8) -> AudioResult<()>;
+    fn set_session_muted(&self, process_id: u32, muted: bool) -> AudioResult<()>;
+    fn foreground_process_id(&self) -> Option<u32>;
+    fn process_executable(&self, process_id: u32) -> Option<String>;
 }
 
-impl AudioManager {
-    pub unsafe fn new() -> AudioResult<Self> {
-        CoInitializeEx(None, COINIT_MULTITHREADED)
-            .ok()
-            .map_err(|e2| AudioError::ComInitialize(e2))?;
+/// The platform's audio stack. Falls back to [`NullAudio`] where there is none or it fails to
+/// start, so the keyboard side keeps working without audio control.
+pub(crate) fn system_backend() -> Box<dyn AudioBackend> {
+    #[cfg(windows)]
+    match unsafe { AudioManager::new() } {
+        Ok(manager) => return Box::new(manager),
+        Err(e) => tracing::error!("Failed to start the audio backend, audio control is off: {e:?}"),
+    }
 
-        let enumerator: IMMDeviceEnumerator =
-            CoCreateInstance(&MMDeviceEnumerator, None, CLSCTX_INPROC_SERVER)
-                .map_err(|e1| AudioError::DeviceEnumeratorError(e1))?;
+    Box::new(NullAudio)
+}
 
-        Ok(Self {
-            immdevice_enumerator: enumerator,
-        })
+/// Backend without any endpoints or sessions, for platforms the app can't drive audio on.
+pub(crate) struct NullAudio;
+
+impl AudioBackend for NullAudio {
+    fn devices(
+        &self,
+        _data_flow: Option<DataFlow>,
+        _states: DeviceStateFilter,
+    ) -> AudioResult<Vec<AudioDeviceInfo>> {
+        Ok(Vec::new())
     }
 
-    pub fn get_devices(
+    fn default_device(
         &self,
-        data_flow: EDataFlow,
-        all_states: Option<bool>,
-    ) -> Result<AudioDeviceCollection, AudioError> {
-        let device_state = match all_states {
-            Some(true) => DEVICE_STATE_ACTIVE,
-            Some(false) => DEVICE_STATE_ACTIVE,
-            None => DEVICE_STATE_ACTIVE,
-        };
-        unsafe {
-            let device_collection = self
-                .immdevice_enumerator
-                .EnumAudioEndpoints(data_flow, device_state)
-                .map_err(|e| AudioError::DeviceEnumeratorError(e))?;
+        _data_flow: DataFlow,
+        _role: EndpointRole,
+    ) -> AudioResult<AudioDeviceInfo> {
+        Err(AudioError::Unsupported)
+    }
 
-            Ok(AudioDeviceCollection::from(device_collection))
-        }
+    fn make_default(&self, _device_id: &str, _role: EndpointRole) -> AudioResult<()> {
+        Err(AudioError::Unsupported)
     }
 
-    pub fn get_default_device(
+    fn endpoint(
         &self,
-        data_flow: EDataFlow,
-        role: ERole,
-    ) -> AudioResult<AudioDevice<Deactivated>> {
-        unsafe {
-            Ok(self
-                .immdevice_enumerator
-                .GetDefaultAudioEndpoint(data_flow, role)
-                .map_err(|e| AudioError::GetDevice(e))?
-                .into())
-        }
+        _data_flow: DataFlow,
+        _selector: &EndpointSelector,
+    ) -> AudioResult<AudioDeviceInfo> {
+        Err(AudioError::Unsupported)
     }
 
-    pub fn get_mic(&self) -> AudioResult<AudioDevice<Deactivated>> {
-        self.get_default_device(eCapture, eCommunications)
+    fn volume(&self, _device_id: &str) -> AudioResult<u8> {
+        Err(AudioError::Unsupported)
     }
-}
 
-type AudioDeviceResult<T> = Result<T, AudioDeviceError>;
-#[derive(Debug)]
-pub enum AudioDeviceError {
-    Activate(Error),
-    Volume(Error),
-    Mute(Error),
-}
-
-#[derive(Debug)]
-pub struct AudioDevice<S: AudioDeviceState> {
-    name: String,
-    id: String,
-    device: IMMDevice,
-    state: S,
-}
+    fn set_volume(&self, _device_id: &str, _percent: u8) -> AudioResult<()> {
+        Err(AudioError::Unsupported)
+    }
 
-trait AudioDeviceState {}
+    fn muted(&self, _device_id: &str) -> AudioResult<bool> {
+        Err(AudioError::Unsupported)
+    }
 
-#[derive(Debug)]
-pub struct Activated {
-    interface: IAudioEndpointVolume,
-}
+    fn set_muted(&self, _device_id: &str, _muted: bool) -> AudioResult<()> {
+        Err(AudioError::Unsupported)
+    }
 
-#[derive(Debug)]
-pub struct Deactivated {}
+    fn peak(&self, _device_id: &str) -> AudioResult<u8> {
+        Err(AudioError::Unsupported)
+    }
 
-impl AudioDeviceState for Activated {}
-impl AudioDeviceState for Deactivated {}
+    fn sessions(&self) -> AudioResult<Vec<AudioSessionInfo>> {
+        Ok(Vec::new())
+    }
 
-impl AudioDevice<Deactivated> {
-    pub fn activate(self) -> AudioDeviceResult<AudioDevice<Activated>> {
-        let interface = unsafe {
-            self.device
-                .Activate::<IAudioEndpointVolume>(CLSCTX_ALL, None)
-        }
-        .map_err(|e| AudioDeviceError::Activate(e))?;
+    fn set_session_volume(&self, _process_id: u32, _percent: u8) -> AudioResult<()> {
+        Err(AudioError::Unsupported)
+    }
 
-        Ok(AudioDevice::<Activated> {
-            name: self.name,
-            id: self.id,
-            device: self.device,
-            state: Activated { interface },
-        })
+    fn set_session_muted(&self, _process_id: u32, _muted: bool) -> AudioResult<()> {
+        Err(AudioError::Unsupported)
     }
-}
 
-impl AudioDevice<Activated> {
-    pub fn get_volume(&self) -> AudioDeviceResult<u8> {
-        unsafe {
-            Ok(self
-                .state
-                .interface
-                .GetMasterVolumeLevelScalar()
-                .map_err(|e| AudioDeviceError::Volume(e))?
-                .mul(100f32)
-                .round()
-                .clamp(0f32, 100f32) as u8)
-        }
+    fn foreground_process_id(&self) -> Option<u32> {
+        None
     }
 
-    pub fn get_muted(&self) -> AudioDeviceResult<bool> {
-        unsafe {
-            Ok(self
-                .state
-                .interface
-                .GetMute()
-                .map_err(|e| AudioDeviceError::Mute(e))?
-                .into())
-        }
+    fn process_executable(&self, _process_id: u32) -> Option<String> {
+        None
     }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EndpointRole {
+    Console,
+    Multimedia,
+    Communications,
+}
+
+impl EndpointRole {
+    pub const ALL: [EndpointRole; 3] = [
+        EndpointRole::Console,
+        EndpointRole::Multimedia,
+        EndpointRole::Communications,
+    ];
+}
 
-    pub fn set_muted(&self, muted: bool) {
-        unsafe {
-            self.state
-                .interface
-                .SetMute(muted, &GUID::new().unwrap())
-                .unwrap()
+impl Display for EndpointRole {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EndpointRole::Console => write!(f, "Console"),
+            EndpointRole::Multimedia => write!(f, "Multimedia"),
+            EndpointRole::Communications => write!(f, "Communications"),
         }
     }
 }
 
-impl From<IMMDevice> for AudioDevice<Deactivated> {
-    fn from(value: IMMDevice) -> Self {
-        let property_store = unsafe { value.OpenPropertyStore(STGM_READ) }
-            .expect("Failed to open property store for audio device");
+/// How a tracked endpoint is chosen: whatever Windows has as the default for a role, or a
+/// specific device that falls back to a role's default while it's unavailable.
+#[derive(Debug, Clone, PartialEq)]
+pub enum EndpointSelector {
+    Default(EndpointRole),
+    Pinned {
+        device_id: String,
+        fallback: EndpointRole,
+    },
+}
 
-        let name = unsafe { property_store.GetValue(&PKEY_Device_FriendlyName) }
-            .expect("Failed to get device name from property store");
+/// Which endpoints [`AudioBackend::devices`] returns, by their current state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceStateFilter {
+    Active,
+    Disabled,
+    NotPresent,
+    Unplugged,
+    Any,
+}
 
-        let id = unsafe {
-            value
-                .GetId()
-                .unwrap()
-                .to_string()
-                .expect("Failed to get audio device ID")
-        };
+impl DeviceStateFilter {
+    pub const ALL: [DeviceStateFilter; 5] = [
+        DeviceStateFilter::Active,
+        DeviceStateFilter::Disabled,
+        DeviceStateFilter::NotPresent,
+        DeviceStateFilter::Unplugged,
+        DeviceStateFilter::Any,
+    ];
+}
 
-        Self {
-            device: value,
-            name: name.to_string(),
-            id,
-            state: Deactivated {},
+impl Display for DeviceStateFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DeviceStateFilter::Any => write!(f, "Any"),
+            DeviceStateFilter::Active => DeviceState::Active.fmt(f),
+            DeviceStateFilter::Disabled => DeviceState::Disabled.fmt(f),
+            DeviceStateFilter::NotPresent => DeviceState::NotPresent.fmt(f),
+            DeviceStateFilter::Unplugged => DeviceState::Unplugged.fmt(f),
         }
     }
 }
 
-pub struct AudioDeviceCollection {
-    inner_collection: IMMDeviceCollection,
-    num_devices: u32,
-    current: u32,
-    next: u32,
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceState {
+    Active,
+    Disabled,
+    NotPresent,
+    Unplugged,
 }
 
-impl From<IMMDeviceCollection> for AudioDeviceCollection {
-    fn from(value: IMMDeviceCollection) -> Self {
-        let num_devices = unsafe { value.GetCount().unwrap() };
-        Self {
-            inner_collection: value,
-            num_devices,
-            current: 0,
-            next: 1,
+impl Display for DeviceState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DeviceState::Active => write!(f, "Active"),
+            DeviceState::Disabled => write!(f, "Disabled"),
+            DeviceState::NotPresent => write!(f, "Not present"),
+            DeviceState::Unplugged => write!(f, "Unplugged"),
         }
     }
 }
 
-impl Iterator for AudioDeviceCollection {
-    type Item = AudioDevice<Deactivated>;
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DataFlow {
+    Render,
+    Capture,
+}
+
+impl Display for DataFlow {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DataFlow::Render => write!(f, "Output"),
+            DataFlow::Capture => write!(f, "Input"),
+        }
+    }
+}
 
-    fn next(&mut self) -> Option<Self::Item> {
-        let current = self.current;
+/// Physical kind of an endpoint, mirroring the `EndpointFormFactor` values Windows stores in
+/// `PKEY_AudioEndpoint_FormFactor`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FormFactor {
+    RemoteNetworkDevice,
+    Speakers,
+    LineLevel,
+    Headphones,
+    Microphone,
+    Headset,
+    Handset,
+    DigitalPassthrough,
+    Spdif,
+    DigitalDisplay,
+    Unknown,
+}
 
-        if current >= self.num_devices {
-            return None;
+impl From<u32> for FormFactor {
+    fn from(value: u32) -> Self {
+        match value {
+            0 => FormFactor::RemoteNetworkDevice,
+            1 => FormFactor::Speakers,
+            2 => FormFactor::LineLevel,
+            3 => FormFactor::Headphones,
+            4 => FormFactor::Microphone,
+            5 => FormFactor::Headset,
+            6 => FormFactor::Handset,
+            7 => FormFactor::DigitalPassthrough,
+            8 => FormFactor::Spdif,
+            9 => FormFactor::DigitalDisplay,
+            _ => FormFactor::Unknown,
         }
+    }
+}
 
-        let device = unsafe { self.inner_collection.Item(current).unwrap() };
+impl Display for FormFactor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            FormFactor::RemoteNetworkDevice => "Remote network device",
+            FormFactor::Speakers => "Speakers",
+            FormFactor::LineLevel => "Line level",
+            FormFactor::Headphones => "Headphones",
+            FormFactor::Microphone => "Microphone",
+            FormFactor::Headset => "Headset",
+            FormFactor::Handset => "Handset",
+            FormFactor::DigitalPassthrough => "Digital passthrough",
+            FormFactor::Spdif => "S/PDIF",
+            FormFactor::DigitalDisplay => "Digital display",
+            FormFactor::Unknown => "Unknown",
+        };
+        write!(f, "{name}")
+    }
+}
 
-        self.current = self.next;
-        self.next = self.current + 1;
+/// Plain snapshot of an endpoint, safe to hand to other threads.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AudioDeviceInfo {
+    pub id: String,
+    pub name: String,
+    pub state: Option<DeviceState>,
+    pub data_flow: Option<DataFlow>,
+    pub form_factor: FormFactor,
+}
+
+/// Plain snapshot of an application's audio session, safe to hand to other threads.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AudioSessionInfo {
+    pub process_id: u32,
+    pub executable: Option<String>,
+    pub display_name: String,
+    pub volume: u8,
+    pub muted: bool,
+}
+
+impl AudioSessionInfo {
+    /// Matches the session's executable against `name`, ignoring case and the `.exe` suffix.
+    pub fn is_executable(&self, name: &str) -> bool {
+        self.executable.as_deref().is_some_and(|executable| {
+            normalize_executable(executable) == normalize_executable(name)
+        })
+    }
+}
 
-        Some(AudioDevice::from(device))
+fn normalize_executable(name: &str) -> String {
+    let name = name.to_lowercase();
+    match name.strip_suffix(".exe") {
+        Some(stripped) => stripped.to_string(),
+        None => name,
     }
 }
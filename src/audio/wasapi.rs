@@ -0,0 +1,800 @@
+use super::{
+    AudioBackend, AudioDeviceInfo, AudioError, AudioResult, AudioSessionInfo, DataFlow,
+    DeviceState, DeviceStateFilter, EndpointRole, EndpointSelector, FormFactor,
+};
+use std::cell::RefCell;
+use std::ffi::c_void;
+use std::ops::{Div, Mul};
+use std::path::Path;
+use tracing::debug;
+use windows::core::{
+    interface, Error, IUnknown, IUnknown_Vtbl, Interface, GUID, HRESULT, HSTRING, PCWSTR, PWSTR,
+};
+use windows::Win32::Devices::FunctionDiscovery::PKEY_Device_FriendlyName;
+use windows::Win32::Foundation::{CloseHandle, BOOL, S_OK};
+use windows::Win32::Media::Audio::Endpoints::{IAudioEndpointVolume, IAudioMeterInformation};
+use windows::Win32::Media::Audio::{
+    eAll, eCapture, eCommunications, eConsole, eMultimedia, eRender, AudioSessionStateExpired,
+    EDataFlow, ERole, IAudioSessionControl2, IAudioSessionManager2, IMMDevice, IMMDeviceCollection,
+    IMMDeviceEnumerator, IMMEndpoint, ISimpleAudioVolume, MMDeviceEnumerator,
+    PKEY_AudioEndpoint_FormFactor, DEVICE_STATE, DEVICE_STATEMASK_ALL, DEVICE_STATE_ACTIVE,
+    DEVICE_STATE_DISABLED, DEVICE_STATE_NOTPRESENT, DEVICE_STATE_UNPLUGGED,
+};
+use windows::Win32::System::Com::{
+    CoCreateInstance, CoInitializeEx, CoTaskMemFree, CLSCTX_ALL, CLSCTX_INPROC_SERVER,
+    COINIT_MULTITHREADED, STGM_READ,
+};
+use windows::Win32::System::Threading::{
+    OpenProcess, QueryFullProcessImageNameW, PROCESS_NAME_WIN32, PROCESS_QUERY_LIMITED_INFORMATION,
+};
+use windows::Win32::UI::WindowsAndMessaging::{GetForegroundWindow, GetWindowThreadProcessId};
+
+impl From<AudioDeviceError> for AudioError {
+    fn from(value: AudioDeviceError) -> Self {
+        AudioError::Device(value)
+    }
+}
+
+impl From<AudioSessionError> for AudioError {
+    fn from(value: AudioSessionError) -> Self {
+        AudioError::Session(value)
+    }
+}
+
+pub struct AudioManager {
+    immdevice_enumerator: IMMDeviceEnumerator,
+    /// Meter of the endpoint [`AudioBackend::peak`] was last asked about.
+    level_meter: RefCell<Option<LevelMeter>>,
+}
+
+impl AudioManager {
+    pub unsafe fn new() -> AudioResult<Self> {
+        CoInitializeEx(None, COINIT_MULTITHREADED)
+            .ok()
+            .map_err(|e2| AudioError::ComInitialize(e2))?;
+
+        let enumerator: IMMDeviceEnumerator =
+            CoCreateInstance(&MMDeviceEnumerator, None, CLSCTX_INPROC_SERVER)
+                .map_err(|e1| AudioError::DeviceEnumeratorError(e1))?;
+
+        Ok(Self {
+            immdevice_enumerator: enumerator,
+            level_meter: RefCell::new(None),
+        })
+    }
+
+    pub fn get_devices(
+        &self,
+        data_flow: EDataFlow,
+        states: DeviceStateFilter,
+    ) -> Result<AudioDeviceCollection, AudioError> {
+        unsafe {
+            let device_collection = self
+                .immdevice_enumerator
+                .EnumAudioEndpoints(data_flow, states.into())
+                .map_err(|e| AudioError::DeviceEnumeratorError(e))?;
+
+            Ok(AudioDeviceCollection::from(device_collection))
+        }
+    }
+
+    pub fn get_default_device(
+        &self,
+        data_flow: EDataFlow,
+        role: ERole,
+    ) -> AudioResult<AudioDevice<Deactivated>> {
+        unsafe {
+            self.immdevice_enumerator
+                .GetDefaultAudioEndpoint(data_flow, role)
+                .and_then(AudioDevice::try_from)
+                .map_err(|e| AudioError::GetDevice(e))
+        }
+    }
+
+    /// Makes `device_id` the default endpoint for `role`, like "Set as Default Device" in the
+    /// Sound control panel.
+    pub fn set_default_device(&self, device_id: &str, role: ERole) -> AudioResult<()> {
+        let device_id = HSTRING::from(device_id);
+
+        unsafe {
+            let policy_config: IPolicyConfig =
+                CoCreateInstance(&CLSID_POLICY_CONFIG_CLIENT, None, CLSCTX_ALL)
+                    .map_err(|e| AudioError::SetDefaultDevice(e))?;
+
+            policy_config
+                .SetDefaultEndpoint(PCWSTR(device_id.as_ptr()), role)
+                .ok()
+                .map_err(|e| AudioError::SetDefaultDevice(e))
+        }
+    }
+
+    pub fn get_mic(&self) -> AudioResult<AudioDevice<Deactivated>> {
+        self.get_default_device(eCapture, eCommunications)
+    }
+
+    pub fn get_device(&self, device_id: &str) -> AudioResult<AudioDevice<Deactivated>> {
+        let device_id = HSTRING::from(device_id);
+
+        unsafe {
+            self.immdevice_enumerator
+                .GetDevice(PCWSTR(device_id.as_ptr()))
+                .and_then(AudioDevice::try_from)
+                .map_err(|e| AudioError::GetDevice(e))
+        }
+    }
+
+    /// Resolves `selector` to a concrete endpoint. A pinned device that is missing or no longer
+    /// active falls back to the default endpoint for the selector's fallback role.
+    pub fn resolve_endpoint(
+        &self,
+        data_flow: EDataFlow,
+        selector: &EndpointSelector,
+    ) -> AudioResult<AudioDevice<Deactivated>> {
+        match selector {
+            EndpointSelector::Default(role) => self.get_default_device(data_flow, (*role).into()),
+            EndpointSelector::Pinned {
+                device_id,
+                fallback,
+            } => match self.get_device(device_id) {
+                Ok(device) if device.device_state == Some(DeviceState::Active) => Ok(device),
+                _ => self.get_default_device(data_flow, (*fallback).into()),
+            },
+        }
+    }
+
+    /// Collects the audio sessions of every active endpoint for `data_flow`. Endpoints whose
+    /// session manager can't be activated are skipped rather than failing the whole listing.
+    pub fn get_sessions(&self, data_flow: EDataFlow) -> AudioResult<Vec<AudioSession>> {
+        Ok(self
+            .get_devices(data_flow, DeviceStateFilter::Active)?
+            .filter_map(|device| device.get_sessions().ok())
+            .flatten()
+            .collect())
+    }
+
+    fn process_sessions(&self, process_id: u32) -> AudioResult<Vec<AudioSession>> {
+        Ok(self
+            .get_sessions(eRender)?
+            .into_iter()
+            .filter(|session| session.process_id() == process_id)
+            .collect())
+    }
+}
+
+impl AudioBackend for AudioManager {
+    fn devices(
+        &self,
+        data_flow: Option<DataFlow>,
+        states: DeviceStateFilter,
+    ) -> AudioResult<Vec<AudioDeviceInfo>> {
+        let data_flow = data_flow.map_or(eAll, EDataFlow::from);
+        Ok(self
+            .get_devices(data_flow, states)?
+            .map(|device| AudioDeviceInfo::from(&device))
+            .collect())
+    }
+
+    fn default_device(
+        &self,
+        data_flow: DataFlow,
+        role: EndpointRole,
+    ) -> AudioResult<AudioDeviceInfo> {
+        let device = self.get_default_device(data_flow.into(), role.into())?;
+        Ok(AudioDeviceInfo::from(&device))
+    }
+
+    fn make_default(&self, device_id: &str, role: EndpointRole) -> AudioResult<()> {
+        self.set_default_device(device_id, role.into())
+    }
+
+    fn endpoint(
+        &self,
+        data_flow: DataFlow,
+        selector: &EndpointSelector,
+    ) -> AudioResult<AudioDeviceInfo> {
+        let device = self.resolve_endpoint(data_flow.into(), selector)?;
+        Ok(AudioDeviceInfo::from(&device))
+    }
+
+    fn volume(&self, device_id: &str) -> AudioResult<u8> {
+        Ok(self.get_device(device_id)?.activate()?.get_volume()?)
+    }
+
+    fn set_volume(&self, device_id: &str, percent: u8) -> AudioResult<()> {
+        Ok(self
+            .get_device(device_id)?
+            .activate()?
+            .set_volume(percent)?)
+    }
+
+    fn muted(&self, device_id: &str) -> AudioResult<bool> {
+        Ok(self.get_device(device_id)?.activate()?.get_muted()?)
+    }
+
+    fn set_muted(&self, device_id: &str, muted: bool) -> AudioResult<()> {
+        Ok(self.get_device(device_id)?.activate()?.set_muted(muted)?)
+    }
+
+    fn peak(&self, device_id: &str) -> AudioResult<u8> {
+        // The meter is bound to one endpoint, so follow the tracked device around
+        let meter = match self.level_meter.take() {
+            Some(meter) if meter.device_id() == device_id => meter,
+            _ => self.get_device(device_id)?.get_level_meter()?,
+        };
+        let peak = meter.get_peak();
+        self.level_meter.replace(Some(meter));
+
+        Ok(peak?)
+    }
+
+    fn sessions(&self) -> AudioResult<Vec<AudioSessionInfo>> {
+        Ok(self
+            .get_sessions(eRender)?
+            .iter()
+            .map(AudioSession::info)
+            .collect())
+    }
+
+    fn set_session_volume(&self, process_id: u32, percent: u8) -> AudioResult<()> {
+        self.process_sessions(process_id)?
+            .iter()
+            .try_for_each(|session| session.set_volume(percent))?;
+        Ok(())
+    }
+
+    fn set_session_muted(&self, process_id: u32, muted: bool) -> AudioResult<()> {
+        self.process_sessions(process_id)?
+            .iter()
+            .try_for_each(|session| session.set_muted(muted))?;
+        Ok(())
+    }
+
+    fn foreground_process_id(&self) -> Option<u32> {
+        foreground_process_id()
+    }
+
+    fn process_executable(&self, process_id: u32) -> Option<String> {
+        process_executable(process_id)
+    }
+}
+
+type AudioDeviceResult<T> = Result<T, AudioDeviceError>;
+#[derive(Debug)]
+pub enum AudioDeviceError {
+    Activate(Error),
+    Volume(Error),
+    Mute(Error),
+    Meter(Error),
+}
+
+impl From<EndpointRole> for ERole {
+    fn from(value: EndpointRole) -> Self {
+        match value {
+            EndpointRole::Console => eConsole,
+            EndpointRole::Multimedia => eMultimedia,
+            EndpointRole::Communications => eCommunications,
+        }
+    }
+}
+
+/// Peak level of the signal currently flowing through an endpoint.
+pub struct LevelMeter {
+    device_id: String,
+    meter: IAudioMeterInformation,
+}
+
+impl LevelMeter {
+    /// Lowest level the meter distinguishes from silence, in dBFS.
+    const FLOOR_DB: f32 = -60f32;
+
+    pub fn device_id(&self) -> &str {
+        &self.device_id
+    }
+
+    /// Peak since the previous call, scaled to 0-100 on a logarithmic scale so quiet signals
+    /// still register on the LED bar.
+    pub fn get_peak(&self) -> AudioDeviceResult<u8> {
+        let peak = unsafe { self.meter.GetPeakValue() }.map_err(|e| AudioDeviceError::Meter(e))?;
+
+        if peak <= 0f32 {
+            return Ok(0);
+        }
+
+        Ok((20f32 * peak.log10() - Self::FLOOR_DB)
+            .div(-Self::FLOOR_DB)
+            .mul(100f32)
+            .round()
+            .clamp(0f32, 100f32) as u8)
+    }
+}
+
+impl From<DeviceStateFilter> for DEVICE_STATE {
+    fn from(value: DeviceStateFilter) -> Self {
+        match value {
+            DeviceStateFilter::Active => DEVICE_STATE_ACTIVE,
+            DeviceStateFilter::Disabled => DEVICE_STATE_DISABLED,
+            DeviceStateFilter::NotPresent => DEVICE_STATE_NOTPRESENT,
+            DeviceStateFilter::Unplugged => DEVICE_STATE_UNPLUGGED,
+            DeviceStateFilter::Any => DEVICE_STATE(DEVICE_STATEMASK_ALL),
+        }
+    }
+}
+
+impl TryFrom<DEVICE_STATE> for DeviceState {
+    type Error = DEVICE_STATE;
+
+    fn try_from(value: DEVICE_STATE) -> Result<Self, Self::Error> {
+        match value {
+            DEVICE_STATE_ACTIVE => Ok(DeviceState::Active),
+            DEVICE_STATE_DISABLED => Ok(DeviceState::Disabled),
+            DEVICE_STATE_NOTPRESENT => Ok(DeviceState::NotPresent),
+            DEVICE_STATE_UNPLUGGED => Ok(DeviceState::Unplugged),
+            other => Err(other),
+        }
+    }
+}
+
+impl TryFrom<EDataFlow> for DataFlow {
+    type Error = EDataFlow;
+
+    fn try_from(value: EDataFlow) -> Result<Self, Self::Error> {
+        match value {
+            eRender => Ok(DataFlow::Render),
+            eCapture => Ok(DataFlow::Capture),
+            other => Err(other),
+        }
+    }
+}
+
+impl From<DataFlow> for EDataFlow {
+    fn from(value: DataFlow) -> Self {
+        match value {
+            DataFlow::Render => eRender,
+            DataFlow::Capture => eCapture,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct AudioDevice<S: AudioDeviceState> {
+    name: String,
+    id: String,
+    device_state: Option<DeviceState>,
+    data_flow: Option<DataFlow>,
+    form_factor: FormFactor,
+    device: IMMDevice,
+    state: S,
+}
+
+pub trait AudioDeviceState {}
+
+#[derive(Debug)]
+pub struct Activated {
+    interface: IAudioEndpointVolume,
+}
+
+#[derive(Debug)]
+pub struct Deactivated {}
+
+impl AudioDeviceState for Activated {}
+impl AudioDeviceState for Deactivated {}
+
+impl AudioDevice<Deactivated> {
+    pub fn activate(self) -> AudioDeviceResult<AudioDevice<Activated>> {
+        let interface = unsafe {
+            self.device
+                .Activate::<IAudioEndpointVolume>(CLSCTX_ALL, None)
+        }
+        .map_err(|e| AudioDeviceError::Activate(e))?;
+
+        Ok(AudioDevice::<Activated> {
+            name: self.name,
+            id: self.id,
+            device_state: self.device_state,
+            data_flow: self.data_flow,
+            form_factor: self.form_factor,
+            device: self.device,
+            state: Activated { interface },
+        })
+    }
+}
+
+impl AudioDevice<Activated> {
+    pub fn get_volume(&self) -> AudioDeviceResult<u8> {
+        unsafe {
+            Ok(self
+                .state
+                .interface
+                .GetMasterVolumeLevelScalar()
+                .map_err(|e| AudioDeviceError::Volume(e))?
+                .mul(100f32)
+                .round()
+                .clamp(0f32, 100f32) as u8)
+        }
+    }
+
+    pub fn set_volume(&self, percent: u8) -> AudioDeviceResult<()> {
+        unsafe {
+            self.state
+                .interface
+                .SetMasterVolumeLevelScalar(percent.min(100) as f32 / 100f32, &GUID::zeroed())
+                .map_err(|e| AudioDeviceError::Volume(e))
+        }
+    }
+
+    pub fn get_muted(&self) -> AudioDeviceResult<bool> {
+        unsafe {
+            Ok(self
+                .state
+                .interface
+                .GetMute()
+                .map_err(|e| AudioDeviceError::Mute(e))?
+                .into())
+        }
+    }
+
+    pub fn set_muted(&self, muted: bool) -> AudioDeviceResult<()> {
+        unsafe {
+            self.state
+                .interface
+                .SetMute(muted, &GUID::new().unwrap())
+                .map_err(|e| AudioDeviceError::Mute(e))
+        }
+    }
+}
+
+impl<S: AudioDeviceState> AudioDevice<S> {
+    pub fn get_level_meter(&self) -> AudioDeviceResult<LevelMeter> {
+        let meter = unsafe {
+            self.device
+                .Activate::<IAudioMeterInformation>(CLSCTX_ALL, None)
+                .map_err(|e| AudioDeviceError::Meter(e))?
+        };
+
+        Ok(LevelMeter {
+            device_id: self.id.clone(),
+            meter,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn device_state(&self) -> Option<DeviceState> {
+        self.device_state
+    }
+
+    pub fn data_flow(&self) -> Option<DataFlow> {
+        self.data_flow
+    }
+
+    pub fn form_factor(&self) -> FormFactor {
+        self.form_factor
+    }
+
+    pub fn get_sessions(&self) -> AudioSessionResult<Vec<AudioSession>> {
+        let enumerator = unsafe {
+            self.device
+                .Activate::<IAudioSessionManager2>(CLSCTX_ALL, None)
+                .map_err(|e| AudioSessionError::Activate(e))?
+                .GetSessionEnumerator()
+                .map_err(|e| AudioSessionError::Enumerate(e))?
+        };
+        let count =
+            unsafe { enumerator.GetCount() }.map_err(|e| AudioSessionError::Enumerate(e))?;
+
+        Ok((0..count)
+            .filter_map(|i| unsafe { enumerator.GetSession(i) }.ok())
+            .filter_map(|control| {
+                AudioSession::try_from(control.cast::<IAudioSessionControl2>().ok()?).ok()
+            })
+            .collect())
+    }
+}
+
+impl TryFrom<IMMDevice> for AudioDevice<Deactivated> {
+    type Error = Error;
+
+    fn try_from(value: IMMDevice) -> Result<Self, Self::Error> {
+        let property_store = unsafe { value.OpenPropertyStore(STGM_READ) }?;
+
+        // Endpoints that are not present can be missing their friendly name entirely
+        let name = unsafe { property_store.GetValue(&PKEY_Device_FriendlyName) }
+            .map(|name| name.to_string())
+            .unwrap_or_default();
+
+        let form_factor = unsafe { property_store.GetValue(&PKEY_AudioEndpoint_FormFactor) }
+            .ok()
+            .and_then(|form_factor| u32::try_from(&form_factor).ok())
+            .map_or(FormFactor::Unknown, FormFactor::from);
+
+        let id = unsafe { take_co_string(value.GetId()?) };
+
+        let device_state = unsafe { value.GetState() }
+            .ok()
+            .and_then(|state| DeviceState::try_from(state).ok());
+
+        let data_flow = unsafe { value.cast::<IMMEndpoint>().and_then(|e| e.GetDataFlow()) }
+            .ok()
+            .and_then(|data_flow| DataFlow::try_from(data_flow).ok());
+
+        Ok(Self {
+            device: value,
+            name,
+            id,
+            device_state,
+            data_flow,
+            form_factor,
+            state: Deactivated {},
+        })
+    }
+}
+
+/// Windows has no public API for changing the default endpoint. `IPolicyConfig` is the
+/// undocumented interface the Sound control panel uses; only `SetDefaultEndpoint` is called, the
+/// remaining methods are declared to keep the vtable layout intact.
+#[interface("f8679f50-850a-41cf-9c72-430f290290c8")]
+unsafe trait IPolicyConfig: IUnknown {
+    fn GetMixFormat(&self, device_id: PCWSTR, format: *mut *mut c_void) -> HRESULT;
+    fn GetDeviceFormat(
+        &self,
+        device_id: PCWSTR,
+        default: BOOL,
+        format: *mut *mut c_void,
+    ) -> HRESULT;
+    fn ResetDeviceFormat(&self, device_id: PCWSTR) -> HRESULT;
+    fn SetDeviceFormat(
+        &self,
+        device_id: PCWSTR,
+        endpoint_format: *mut c_void,
+        mix_format: *mut c_void,
+    ) -> HRESULT;
+    fn GetProcessingPeriod(
+        &self,
+        device_id: PCWSTR,
+        default: BOOL,
+        default_period: *mut i64,
+        minimum_period: *mut i64,
+    ) -> HRESULT;
+    fn SetProcessingPeriod(&self, device_id: PCWSTR, period: *mut i64) -> HRESULT;
+    fn GetShareMode(&self, device_id: PCWSTR, mode: *mut c_void) -> HRESULT;
+    fn SetShareMode(&self, device_id: PCWSTR, mode: *mut c_void) -> HRESULT;
+    fn GetPropertyValue(
+        &self,
+        device_id: PCWSTR,
+        key: *const c_void,
+        value: *mut c_void,
+    ) -> HRESULT;
+    fn SetPropertyValue(
+        &self,
+        device_id: PCWSTR,
+        key: *const c_void,
+        value: *mut c_void,
+    ) -> HRESULT;
+    fn SetDefaultEndpoint(&self, device_id: PCWSTR, role: ERole) -> HRESULT;
+    fn SetEndpointVisibility(&self, device_id: PCWSTR, visible: BOOL) -> HRESULT;
+}
+
+const CLSID_POLICY_CONFIG_CLIENT: GUID = GUID::from_u128(0x870af99c_171d_4f9e_af0d_e63df40c2bc9);
+
+impl<S: AudioDeviceState> From<&AudioDevice<S>> for AudioDeviceInfo {
+    fn from(value: &AudioDevice<S>) -> Self {
+        Self {
+            id: value.id.clone(),
+            name: value.name.clone(),
+            state: value.device_state,
+            data_flow: value.data_flow,
+            form_factor: value.form_factor,
+        }
+    }
+}
+
+/// Endpoints from an enumeration. Ones that fail to report their ID or properties, which
+/// happens with endpoints that aren't present, are left out.
+pub struct AudioDeviceCollection {
+    inner_collection: IMMDeviceCollection,
+    num_devices: u32,
+    current: u32,
+}
+
+impl From<IMMDeviceCollection> for AudioDeviceCollection {
+    fn from(value: IMMDeviceCollection) -> Self {
+        let num_devices = unsafe { value.GetCount().unwrap() };
+        Self {
+            inner_collection: value,
+            num_devices,
+            current: 0,
+        }
+    }
+}
+
+impl Iterator for AudioDeviceCollection {
+    type Item = AudioDevice<Deactivated>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.current < self.num_devices {
+            let index = self.current;
+            self.current += 1;
+
+            match unsafe { self.inner_collection.Item(index) }.and_then(AudioDevice::try_from) {
+                Ok(device) => return Some(device),
+                Err(e) => debug!("Skipping audio endpoint {index}: {e}"),
+            }
+        }
+
+        None
+    }
+}
+
+type AudioSessionResult<T> = Result<T, AudioSessionError>;
+#[derive(Debug)]
+pub enum AudioSessionError {
+    Activate(Error),
+    Enumerate(Error),
+    Expired,
+    Volume(Error),
+    Mute(Error),
+}
+
+/// A single application's stream on an endpoint, as shown in the Windows volume mixer.
+pub struct AudioSession {
+    process_id: u32,
+    executable: Option<String>,
+    display_name: String,
+    volume: ISimpleAudioVolume,
+}
+
+impl TryFrom<IAudioSessionControl2> for AudioSession {
+    type Error = AudioSessionError;
+
+    fn try_from(value: IAudioSessionControl2) -> AudioSessionResult<Self> {
+        unsafe {
+            if value
+                .GetState()
+                .map_err(|e| AudioSessionError::Enumerate(e))?
+                == AudioSessionStateExpired
+            {
+                return Err(AudioSessionError::Expired);
+            }
+
+            let process_id = value
+                .GetProcessId()
+                .map_err(|e| AudioSessionError::Enumerate(e))?;
+            let executable = process_executable(process_id);
+            let display_name = match value.GetDisplayName() {
+                Ok(name) => take_co_string(name),
+                Err(_) => String::new(),
+            };
+            // Most applications never set a display name, the volume mixer falls back to the
+            // executable in that case and so do we.
+            let display_name = if value.IsSystemSoundsSession() == S_OK {
+                "System Sounds".to_string()
+            } else if display_name.is_empty() || display_name.starts_with('@') {
+                executable
+                    .clone()
+                    .unwrap_or_else(|| format!("PID {process_id}"))
+            } else {
+                display_name
+            };
+
+            Ok(Self {
+                process_id,
+                executable,
+                display_name,
+                volume: value
+                    .cast::<ISimpleAudioVolume>()
+                    .map_err(|e| AudioSessionError::Volume(e))?,
+            })
+        }
+    }
+}
+
+impl AudioSession {
+    pub fn process_id(&self) -> u32 {
+        self.process_id
+    }
+
+    pub fn executable(&self) -> Option<&str> {
+        self.executable.as_deref()
+    }
+
+    pub fn get_volume(&self) -> AudioSessionResult<u8> {
+        unsafe {
+            Ok(self
+                .volume
+                .GetMasterVolume()
+                .map_err(|e| AudioSessionError::Volume(e))?
+                .mul(100f32)
+                .round()
+                .clamp(0f32, 100f32) as u8)
+        }
+    }
+
+    pub fn set_volume(&self, percent: u8) -> AudioSessionResult<()> {
+        unsafe {
+            self.volume
+                .SetMasterVolume(percent.min(100) as f32 / 100f32, &GUID::zeroed())
+                .map_err(|e| AudioSessionError::Volume(e))
+        }
+    }
+
+    pub fn get_muted(&self) -> AudioSessionResult<bool> {
+        unsafe {
+            Ok(self
+                .volume
+                .GetMute()
+                .map_err(|e| AudioSessionError::Mute(e))?
+                .into())
+        }
+    }
+
+    pub fn set_muted(&self, muted: bool) -> AudioSessionResult<()> {
+        unsafe {
+            self.volume
+                .SetMute(muted, &GUID::zeroed())
+                .map_err(|e| AudioSessionError::Mute(e))
+        }
+    }
+
+    pub fn info(&self) -> AudioSessionInfo {
+        AudioSessionInfo {
+            process_id: self.process_id,
+            executable: self.executable.clone(),
+            display_name: self.display_name.clone(),
+            volume: self.get_volume().unwrap_or_default(),
+            muted: self.get_muted().unwrap_or_default(),
+        }
+    }
+}
+
+/// Process owning the window that currently has keyboard focus.
+pub fn foreground_process_id() -> Option<u32> {
+    let mut process_id = 0u32;
+    unsafe {
+        let window = GetForegroundWindow();
+        if window.is_invalid() {
+            return None;
+        }
+        GetWindowThreadProcessId(window, Some(&mut process_id));
+    }
+
+    (process_id != 0).then_some(process_id)
+}
+
+/// File name of the executable backing `process_id`, e.g. `Discord.exe`.
+pub fn process_executable(process_id: u32) -> Option<String> {
+    if process_id == 0 {
+        return None;
+    }
+
+    unsafe {
+        let process = OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, false, process_id).ok()?;
+        let mut buffer = [0u16; 1024];
+        let mut size = buffer.len() as u32;
+        let result = QueryFullProcessImageNameW(
+            process,
+            PROCESS_NAME_WIN32,
+            PWSTR(buffer.as_mut_ptr()),
+            &mut size,
+        );
+        let _ = CloseHandle(process);
+        result.ok()?;
+
+        let path = String::from_utf16_lossy(&buffer[..size as usize]);
+        Path::new(&path)
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+    }
+}
+
+/// Converts a COM-allocated string into an owned one and releases the original.
+unsafe fn take_co_string(value: PWSTR) -> String {
+    let string = value.to_string().unwrap_or_default();
+    CoTaskMemFree(Some(value.0 as *const _));
+    string
+}
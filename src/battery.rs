@@ -0,0 +1,165 @@
+use crate::config::BatteryConfig;
+use serde::Serialize;
+use std::time::{Duration, Instant};
+
+/// How worried to be about the keyboard's charge.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum BatteryLevel {
+    #[default]
+    Normal,
+    Low,
+    Critical,
+}
+
+/// Decides when to ask the keyboard for its battery and what the answers mean. A level is only
+/// left once the charge is `hysteresis` points past its threshold, so a reading that wobbles
+/// around a threshold doesn't raise an alert on every poll.
+pub(crate) struct BatteryMonitor {
+    level: BatteryLevel,
+    last_poll: Instant,
+    flashes_left: u8,
+    last_flash: Option<Instant>,
+}
+
+impl BatteryMonitor {
+    const FLASHES: u8 = 3;
+    pub(crate) const FLASH_INTERVAL: Duration = Duration::from_millis(600);
+
+    /// The keyboard is asked once when it connects, so the first poll waits a full interval.
+    pub(crate) fn new() -> Self {
+        Self {
+            level: BatteryLevel::Normal,
+            last_poll: Instant::now(),
+            flashes_left: 0,
+            last_flash: None,
+        }
+    }
+
+    /// Whether to send a `BatteryRequest` now. Polls come faster once the battery is low.
+    pub(crate) fn poll_due(&mut self, config: &BatteryConfig) -> bool {
+        let interval = match self.level {
+            BatteryLevel::Normal => config.poll_interval_s,
+            BatteryLevel::Low | BatteryLevel::Critical => config.low_poll_interval_s,
+        };
+        if self.last_poll.elapsed() < Duration::from_secs(interval) {
+            return false;
+        }
+
+        self.last_poll = Instant::now();
+        true
+    }
+
+    /// Takes in a reading and returns the new level if it changed. Dropping to critical starts
+    /// a round of flashes.
+    pub(crate) fn update(&mut self, percent: u8, config: &BatteryConfig) -> Option<BatteryLevel> {
+        let recovered = |threshold: u8| percent >= threshold.saturating_add(config.hysteresis);
+        let level = match self.level {
+            _ if percent <= config.critical_percent => BatteryLevel::Critical,
+            BatteryLevel::Critical if !recovered(config.critical_percent) => BatteryLevel::Critical,
+            _ if percent <= config.low_percent => BatteryLevel::Low,
+            BatteryLevel::Low | BatteryLevel::Critical if !recovered(config.low_percent) => {
+                BatteryLevel::Low
+            }
+            _ => BatteryLevel::Normal,
+        };
+
+        if level == self.level {
+            return None;
+        }
+        if level == BatteryLevel::Critical {
+            self.flashes_left = Self::FLASHES;
+        }
+
+        self.level = level;
+        Some(level)
+    }
+
+    /// Whether the next flash of a critical alert is due.
+    pub(crate) fn flash_due(&mut self) -> bool {
+        if self.flashes_left == 0
+            || self
+                .last_flash
+                .is_some_and(|last_flash| last_flash.elapsed() < Self::FLASH_INTERVAL)
+        {
+            return false;
+        }
+
+        self.flashes_left -= 1;
+        self.last_flash = Some(Instant::now());
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn readings(monitor: &mut BatteryMonitor, percents: &[u8]) -> Vec<Option<BatteryLevel>> {
+        let config = BatteryConfig::default();
+        percents
+            .iter()
+            .map(|percent| monitor.update(*percent, &config))
+            .collect()
+    }
+
+    #[test]
+    fn wobble_around_a_threshold_alerts_once() {
+        let mut monitor = BatteryMonitor::new();
+        assert_eq!(
+            readings(&mut monitor, &[21, 20, 21, 20, 22, 23, 22]),
+            [
+                None,
+                Some(BatteryLevel::Low),
+                None,
+                None,
+                None,
+                Some(BatteryLevel::Normal),
+                None
+            ]
+        );
+    }
+
+    #[test]
+    fn critical_recovers_through_low() {
+        let mut monitor = BatteryMonitor::new();
+        assert_eq!(
+            readings(&mut monitor, &[5, 7, 8, 20, 22, 23]),
+            [
+                Some(BatteryLevel::Critical),
+                None,
+                Some(BatteryLevel::Low),
+                None,
+                None,
+                Some(BatteryLevel::Normal),
+            ]
+        );
+    }
+
+    #[test]
+    fn critical_flashes_a_few_times() {
+        let mut monitor = BatteryMonitor::new();
+        assert!(!monitor.flash_due());
+
+        readings(&mut monitor, &[4]);
+        assert!(monitor.flash_due());
+        assert!(!monitor.flash_due(), "flashes are spaced out");
+
+        let mut flashes = 1;
+        while flashes < 10 {
+            monitor.last_flash = Some(Instant::now() - BatteryMonitor::FLASH_INTERVAL);
+            if !monitor.flash_due() {
+                break;
+            }
+            flashes += 1;
+        }
+        assert_eq!(flashes, BatteryMonitor::FLASHES);
+
+        // Staying critical doesn't start another round, dropping back into it does
+        monitor.last_flash = None;
+        readings(&mut monitor, &[3]);
+        assert!(!monitor.flash_due());
+        readings(&mut monitor, &[10, 4]);
+        assert!(monitor.flash_due());
+    }
+}
@@ -0,0 +1,578 @@
+use crate::config::{self, FileWatcher};
+use crate::record::RecordData;
+use crate::steelseries::api::sonar::types::RedirectionId;
+use crate::{BindingsRequest, BindingsResponse};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::path::Path;
+use tracing::{info, warn};
+
+/// Device record a binding reacts to.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "record", rename_all = "kebab-case")]
+pub(crate) enum Trigger {
+    Key {
+        code: u16,
+        #[serde(default = "default_pressed")]
+        pressed: bool,
+    },
+    LayerChanged {
+        layer: Option<u8>,
+    },
+    ToggleOutputMute,
+    ToggleInputMute,
+    InputMuteKeyPressed,
+    InputMuteKeyReleased,
+    TogglePrivacyMode,
+    CycleOutputDevice,
+    ToggleSessionMute {
+        target: Option<u8>,
+    },
+    SetSessionVolume {
+        target: Option<u8>,
+    },
+}
+
+fn default_pressed() -> bool {
+    true
+}
+
+/// Records that have built-in handling when nothing is bound to them.
+static BUILT_IN_TRIGGERS: [Trigger; 8] = [
+    Trigger::ToggleOutputMute,
+    Trigger::ToggleInputMute,
+    Trigger::InputMuteKeyPressed,
+    Trigger::InputMuteKeyReleased,
+    Trigger::TogglePrivacyMode,
+    Trigger::CycleOutputDevice,
+    Trigger::ToggleSessionMute { target: None },
+    Trigger::SetSessionVolume { target: None },
+];
+
+impl Trigger {
+    pub(crate) fn matches(&self, data: &RecordData) -> bool {
+        let target_matches = |target: &Option<u8>, actual: &u8| match target {
+            Some(target) => target == actual,
+            None => true,
+        };
+
+        match (self, data) {
+            (
+                Trigger::Key { code, pressed },
+                RecordData::KeyEvent {
+                    keycode,
+                    pressed: is_pressed,
+                },
+            ) => code == keycode && pressed == is_pressed,
+            (Trigger::LayerChanged { layer }, RecordData::LayerChanged { layer: actual }) => {
+                target_matches(layer, actual)
+            }
+            (Trigger::ToggleOutputMute, RecordData::ToggleOutputMute)
+            | (Trigger::ToggleInputMute, RecordData::ToggleInputMute)
+            | (Trigger::InputMuteKeyPressed, RecordData::InputMuteKeyPressed)
+            | (Trigger::InputMuteKeyReleased, RecordData::InputMuteKeyReleased)
+            | (Trigger::TogglePrivacyMode, RecordData::TogglePrivacyMode)
+            | (Trigger::CycleOutputDevice, RecordData::CycleOutputDevice) => true,
+            (
+                Trigger::ToggleSessionMute { target },
+                RecordData::ToggleSessionMute { target: actual },
+            )
+            | (
+                Trigger::SetSessionVolume { target },
+                RecordData::SetSessionVolume { target: actual, .. },
+            ) => target_matches(target, actual),
+            _ => false,
+        }
+    }
+}
+
+impl Display for Trigger {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let slot = |target: &Option<u8>| match target {
+            Some(target) => format!("slot {target}"),
+            None => "any slot".to_string(),
+        };
+
+        match self {
+            Trigger::Key { code, pressed } => write!(
+                f,
+                "Key {code:#06x} {}",
+                if *pressed { "pressed" } else { "released" }
+            ),
+            Trigger::LayerChanged { layer: Some(layer) } => write!(f, "Layer changed to {layer}"),
+            Trigger::LayerChanged { layer: None } => write!(f, "Layer changed"),
+            Trigger::ToggleOutputMute => write!(f, "Toggle output mute"),
+            Trigger::ToggleInputMute => write!(f, "Toggle input mute"),
+            Trigger::InputMuteKeyPressed => write!(f, "Mic key pressed"),
+            Trigger::InputMuteKeyReleased => write!(f, "Mic key released"),
+            Trigger::TogglePrivacyMode => write!(f, "Toggle privacy mode"),
+            Trigger::CycleOutputDevice => write!(f, "Cycle output device"),
+            Trigger::ToggleSessionMute { target } => {
+                write!(f, "Toggle session mute ({})", slot(target))
+            }
+            Trigger::SetSessionVolume { target } => {
+                write!(f, "Set session volume ({})", slot(target))
+            }
+        }
+    }
+}
+
+/// What to do when a binding's trigger arrives.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "action", rename_all = "kebab-case")]
+pub(crate) enum Action {
+    /// Whatever the record does without any bindings.
+    Default,
+    /// Swallow the record.
+    Ignore,
+    ToggleOutputMute,
+    ToggleInputMute,
+    InputKey {
+        pressed: bool,
+    },
+    TogglePrivacyMode,
+    CycleOutputDevice,
+    SetVolume {
+        percent: u8,
+    },
+    ToggleSessionMute {
+        target: u8,
+    },
+    SetSessionVolume {
+        target: u8,
+        percent: u8,
+    },
+    SonarRedirect {
+        redirection: RedirectionId,
+        device: String,
+    },
+    RunCommand {
+        command: String,
+        #[serde(default)]
+        args: Vec<String>,
+    },
+    SendRecord {
+        record: RecordData,
+    },
+}
+
+impl Action {
+    /// The action `Default` stands for on the given record.
+    pub(crate) fn built_in(data: &RecordData) -> Option<Action> {
+        match *data {
+            RecordData::ToggleOutputMute => Some(Action::ToggleOutputMute),
+            RecordData::ToggleInputMute => Some(Action::ToggleInputMute),
+            RecordData::InputMuteKeyPressed => Some(Action::InputKey { pressed: true }),
+            RecordData::InputMuteKeyReleased => Some(Action::InputKey { pressed: false }),
+            RecordData::TogglePrivacyMode => Some(Action::TogglePrivacyMode),
+            RecordData::CycleOutputDevice => Some(Action::CycleOutputDevice),
+            RecordData::ToggleSessionMute { target } => Some(Action::ToggleSessionMute { target }),
+            RecordData::SetSessionVolume { target, percent } => {
+                Some(Action::SetSessionVolume { target, percent })
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Display for Action {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Action::Default => write!(f, "Built-in"),
+            Action::Ignore => write!(f, "Ignore"),
+            Action::ToggleOutputMute => write!(f, "Toggle output mute"),
+            Action::ToggleInputMute => write!(f, "Toggle input mute"),
+            Action::InputKey { pressed: true } => write!(f, "Press mic key"),
+            Action::InputKey { pressed: false } => write!(f, "Release mic key"),
+            Action::TogglePrivacyMode => write!(f, "Toggle privacy mode"),
+            Action::CycleOutputDevice => write!(f, "Cycle output device"),
+            Action::SetVolume { percent } => write!(f, "Set volume to {percent}%"),
+            Action::ToggleSessionMute { target } => write!(f, "Toggle mute of slot {target}"),
+            Action::SetSessionVolume { target, percent } => {
+                write!(f, "Set slot {target} volume to {percent}%")
+            }
+            Action::SonarRedirect {
+                redirection,
+                device,
+            } => write!(f, "Redirect Sonar {redirection} to {device}"),
+            Action::RunCommand { command, args } if args.is_empty() => write!(f, "Run {command}"),
+            Action::RunCommand { command, args } => write!(f, "Run {command} {}", args.join(" ")),
+            Action::SendRecord { record } => write!(f, "Send {record:?}"),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub(crate) struct Binding {
+    pub(crate) trigger: Trigger,
+    pub(crate) action: Action,
+}
+
+/// Bindings for every layer plus overrides for specific layers, keyed by layer number.
+#[derive(Deserialize, Debug, Clone, Default)]
+struct BindingSet {
+    #[serde(default)]
+    bindings: Vec<Binding>,
+    #[serde(default)]
+    layers: BTreeMap<String, Vec<Binding>>,
+}
+
+impl BindingSet {
+    fn layer(&self, layer: u8) -> &[Binding] {
+        self.layers
+            .get(&layer.to_string())
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+
+    fn check_layers(&self) -> Result<(), String> {
+        match self.layers.keys().find(|key| key.parse::<u8>().is_err()) {
+            Some(key) => Err(format!("'{key}' is not a layer number")),
+            None => Ok(()),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+struct BindingsConfig {
+    profile: Option<String>,
+    #[serde(flatten)]
+    global: BindingSet,
+    #[serde(default)]
+    profiles: BTreeMap<String, BindingSet>,
+}
+
+/// Where a binding comes from, most specific first.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum BindingScope {
+    ProfileLayer(String, u8),
+    Profile(String),
+    Layer(u8),
+    Global,
+    BuiltIn,
+}
+
+impl Display for BindingScope {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BindingScope::ProfileLayer(profile, layer) => write!(f, "{profile}, layer {layer}"),
+            BindingScope::Profile(profile) => write!(f, "{profile}"),
+            BindingScope::Layer(layer) => write!(f, "Layer {layer}"),
+            BindingScope::Global => write!(f, "Global"),
+            BindingScope::BuiltIn => write!(f, "Built-in"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct ActiveBinding {
+    pub(crate) scope: BindingScope,
+    pub(crate) trigger: Trigger,
+    pub(crate) action: Action,
+}
+
+/// Maps device records to actions. Loaded from `bindings.toml` in the config directory, and
+/// reloaded when the file changes:
+///
+/// ```toml
+/// profile = "gaming"
+///
+/// [[bindings]]
+/// trigger = { record = "key", code = 0x1E }
+/// action = { action = "run-command", command = "obs64.exe" }
+///
+/// [[layers.1]]
+/// trigger = { record = "toggle-output-mute" }
+/// action = { action = "sonar-redirect", redirection = "game", device = "{0.0.0.00000000}.{...}" }
+///
+/// [[profiles.gaming.bindings]]
+/// trigger = { record = "cycle-output-device" }
+/// action = { action = "ignore" }
+/// ```
+///
+/// For a record the first match wins in the order: active profile's current layer, active
+/// profile, current layer, global. Records without a match keep their built-in handling.
+#[derive(Default)]
+pub(crate) struct Bindings {
+    file: FileWatcher,
+    config: BindingsConfig,
+    profile: Option<String>,
+    error: Option<String>,
+}
+
+impl Bindings {
+    const FILE_NAME: &str = "bindings.toml";
+
+    pub(crate) fn load() -> Self {
+        let mut bindings = Self {
+            file: FileWatcher::new(Self::FILE_NAME),
+            config: Default::default(),
+            profile: None,
+            error: None,
+        };
+
+        bindings.reload();
+        bindings
+    }
+
+    /// Re-reads the config file. A broken file keeps the previous bindings.
+    pub(crate) fn reload(&mut self) {
+        let Some(path) = self.file.path() else {
+            self.error = Some("No config directory on this system".to_string());
+            return;
+        };
+
+        let config = config::read_toml(path, |config: &BindingsConfig| {
+            config.global.check_layers()?;
+            config
+                .profiles
+                .values()
+                .try_for_each(BindingSet::check_layers)
+        });
+
+        match config {
+            Ok(config) => {
+                self.profile = config.profile.clone();
+                self.config = config;
+                self.error = None;
+            }
+            Err(e) => {
+                warn!("{e}, keeping the previous bindings");
+                self.error = Some(e.to_string());
+            }
+        }
+    }
+
+    /// Reloads the file when it changed since the last call. Returns whether it did.
+    pub(crate) fn poll(&mut self) -> bool {
+        let Some(path) = self.file.changed() else {
+            return false;
+        };
+
+        info!("Reloading {}", path.display());
+        self.reload();
+        true
+    }
+
+    pub(crate) fn set_profile(&mut self, profile: Option<String>) {
+        self.profile = profile.filter(|profile| self.config.profiles.contains_key(profile));
+    }
+
+    fn scopes(&self, layer: u8) -> Vec<(BindingScope, &[Binding])> {
+        let mut scopes = Vec::new();
+        if let Some((name, profile)) = self
+            .profile
+            .as_ref()
+            .and_then(|name| Some((name, self.config.profiles.get(name)?)))
+        {
+            scopes.push((
+                BindingScope::ProfileLayer(name.clone(), layer),
+                profile.layer(layer),
+            ));
+            scopes.push((
+                BindingScope::Profile(name.clone()),
+                profile.bindings.as_slice(),
+            ));
+        }
+        scopes.push((BindingScope::Layer(layer), self.config.global.layer(layer)));
+        scopes.push((BindingScope::Global, self.config.global.bindings.as_slice()));
+
+        scopes
+    }
+
+    /// The action bound to a record on the given layer, `Action::Default` if nothing is.
+    pub(crate) fn resolve(&self, data: &RecordData, layer: u8) -> Action {
+        self.scopes(layer)
+            .into_iter()
+            .flat_map(|(_, bindings)| bindings)
+            .find(|binding| binding.trigger.matches(data))
+            .map(|binding| binding.action.clone())
+            .unwrap_or(Action::Default)
+    }
+
+    /// Every binding in effect on the given layer, without the ones a more specific scope hides.
+    pub(crate) fn active(&self, layer: u8) -> Vec<ActiveBinding> {
+        let mut active: Vec<ActiveBinding> = Vec::new();
+        self.scopes(layer)
+            .into_iter()
+            .flat_map(|(scope, bindings)| {
+                bindings
+                    .iter()
+                    .map(move |binding| (scope.clone(), &binding.trigger, &binding.action))
+            })
+            .chain(
+                BUILT_IN_TRIGGERS
+                    .iter()
+                    .map(|trigger| (BindingScope::BuiltIn, trigger, &Action::Default)),
+            )
+            .for_each(|(scope, trigger, action)| {
+                if active.iter().all(|a| &a.trigger != trigger) {
+                    active.push(ActiveBinding {
+                        scope,
+                        trigger: trigger.clone(),
+                        action: action.clone(),
+                    });
+                }
+            });
+
+        active
+    }
+
+    pub(crate) fn process_request(
+        &mut self,
+        request: BindingsRequest,
+        layer: u8,
+    ) -> Option<BindingsResponse> {
+        match request {
+            BindingsRequest::FetchBindings => {}
+            BindingsRequest::SetProfile(profile) => self.set_profile(profile),
+            BindingsRequest::Reload => self.reload(),
+        }
+
+        Some(self.response(layer))
+    }
+
+    pub(crate) fn response(&self, layer: u8) -> BindingsResponse {
+        BindingsResponse::FetchBindings {
+            path: self.file.path().map(Path::to_path_buf),
+            error: self.error.clone(),
+            profile: self.profile.clone(),
+            profiles: self.config.profiles.keys().cloned().collect(),
+            layer,
+            bindings: self.active(layer),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bindings(toml: &str) -> Bindings {
+        let config: BindingsConfig = toml::from_str(toml).unwrap();
+        Bindings {
+            profile: config.profile.clone(),
+            config,
+            ..Default::default()
+        }
+    }
+
+    fn set_volume(percent: u8) -> Action {
+        Action::SetVolume { percent }
+    }
+
+    #[test]
+    fn trigger_matches_record() {
+        let key = Trigger::Key {
+            code: 0x1E,
+            pressed: true,
+        };
+        assert!(key.matches(&RecordData::KeyEvent {
+            keycode: 0x1E,
+            pressed: true
+        }));
+        assert!(!key.matches(&RecordData::KeyEvent {
+            keycode: 0x1E,
+            pressed: false
+        }));
+        assert!(!key.matches(&RecordData::KeyEvent {
+            keycode: 0x1F,
+            pressed: true
+        }));
+
+        let any_layer = Trigger::LayerChanged { layer: None };
+        let layer_2 = Trigger::LayerChanged { layer: Some(2) };
+        assert!(any_layer.matches(&RecordData::LayerChanged { layer: 1 }));
+        assert!(layer_2.matches(&RecordData::LayerChanged { layer: 2 }));
+        assert!(!layer_2.matches(&RecordData::LayerChanged { layer: 1 }));
+
+        let any_slot = Trigger::SetSessionVolume { target: None };
+        let slot_1 = Trigger::ToggleSessionMute { target: Some(1) };
+        assert!(any_slot.matches(&RecordData::SetSessionVolume {
+            target: 3,
+            percent: 50
+        }));
+        assert!(slot_1.matches(&RecordData::ToggleSessionMute { target: 1 }));
+        assert!(!slot_1.matches(&RecordData::ToggleSessionMute { target: 0 }));
+        assert!(!slot_1.matches(&RecordData::SetSessionVolume {
+            target: 1,
+            percent: 50
+        }));
+
+        assert!(Trigger::ToggleOutputMute.matches(&RecordData::ToggleOutputMute));
+        assert!(!Trigger::ToggleOutputMute.matches(&RecordData::ToggleInputMute));
+    }
+
+    #[test]
+    fn resolve_prefers_the_most_specific_scope() {
+        let bindings = bindings(
+            r#"
+            profile = "gaming"
+
+            [[bindings]]
+            trigger = { record = "toggle-output-mute" }
+            action = { action = "set-volume", percent = 1 }
+
+            [[layers.1]]
+            trigger = { record = "toggle-output-mute" }
+            action = { action = "set-volume", percent = 2 }
+
+            [[profiles.gaming.bindings]]
+            trigger = { record = "toggle-output-mute" }
+            action = { action = "set-volume", percent = 3 }
+
+            [[profiles.gaming.layers.1]]
+            trigger = { record = "toggle-output-mute" }
+            action = { action = "set-volume", percent = 4 }
+
+            [[profiles.gaming.layers.2]]
+            trigger = { record = "toggle-input-mute" }
+            action = { action = "ignore" }
+
+            [[layers.3]]
+            trigger = { record = "toggle-input-mute" }
+            action = { action = "ignore" }
+            "#,
+        );
+        let mute = RecordData::ToggleOutputMute;
+
+        // Profile layer, then profile, before the global layer and the global bindings
+        assert_eq!(bindings.resolve(&mute, 1), set_volume(4));
+        assert_eq!(bindings.resolve(&mute, 0), set_volume(3));
+        assert_eq!(bindings.resolve(&mute, 2), set_volume(3));
+
+        let mut bindings = bindings;
+        bindings.set_profile(None);
+        assert_eq!(bindings.resolve(&mute, 1), set_volume(2));
+        assert_eq!(bindings.resolve(&mute, 0), set_volume(1));
+
+        // Unbound records keep their built-in handling
+        assert_eq!(
+            bindings.resolve(&RecordData::ToggleInputMute, 0),
+            Action::Default
+        );
+        assert_eq!(
+            bindings.resolve(&RecordData::ToggleInputMute, 3),
+            Action::Ignore
+        );
+    }
+
+    #[test]
+    fn unknown_profile_is_ignored() {
+        let mut bindings = bindings(
+            r#"
+            [[bindings]]
+            trigger = { record = "cycle-output-device" }
+            action = { action = "ignore" }
+            "#,
+        );
+
+        bindings.set_profile(Some("missing".to_string()));
+        assert_eq!(
+            bindings.resolve(&RecordData::CycleOutputDevice, 0),
+            Action::Ignore
+        );
+        assert_eq!(bindings.active(0)[0].scope, BindingScope::Global);
+    }
+}
@@ -0,0 +1,369 @@
+use crate::Event;
+use std::collections::VecDeque;
+use std::fmt::{Display, Formatter};
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+use tracing::warn;
+
+/// Events a subscriber can hold before it drops or refuses them.
+const CAPACITY: usize = 256;
+
+/// What an [`Event`] is about, so subscribers only get the ones they handle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Topic {
+    /// Connection changes, link statistics and records read from the keyboard.
+    Device,
+    /// Records for the device thread to write to the keyboard.
+    DeviceCommand,
+    /// Records the device thread has written to the keyboard.
+    DeviceSent,
+    /// Low and critical battery alerts.
+    Battery,
+    AudioRequest,
+    AudioResponse,
+    SonarRequest,
+    SonarResponse,
+    BindingsRequest,
+    BindingsResponse,
+    /// Queries for the history thread.
+    HistoryRequest,
+    HistoryResponse,
+    Config,
+    /// Changes to the [`crate::state::AppState`].
+    State,
+}
+
+impl Topic {
+    pub(crate) const ALL: [Topic; 14] = [
+        Topic::Device,
+        Topic::DeviceCommand,
+        Topic::DeviceSent,
+        Topic::Battery,
+        Topic::AudioRequest,
+        Topic::AudioResponse,
+        Topic::SonarRequest,
+        Topic::SonarResponse,
+        Topic::BindingsRequest,
+        Topic::BindingsResponse,
+        Topic::HistoryRequest,
+        Topic::HistoryResponse,
+        Topic::Config,
+        Topic::State,
+    ];
+
+    /// Requests someone has to act on. Unlike notifications they are never dropped, a full
+    /// subscriber refuses new ones instead.
+    pub(crate) fn is_command(self) -> bool {
+        matches!(
+            self,
+            Topic::DeviceCommand
+                | Topic::AudioRequest
+                | Topic::BindingsRequest
+                | Topic::SonarRequest
+                | Topic::HistoryRequest
+        )
+    }
+}
+
+impl Event {
+    pub(crate) fn topic(&self) -> Topic {
+        match self {
+            Event::DeviceConnected(_)
+            | Event::DeviceDisconnected
+            | Event::Heartbeat(_)
+            | Event::WriteQueue(_)
+            | Event::RecordFromDevice(_) => Topic::Device,
+            Event::RecordToDevice(_) => Topic::DeviceCommand,
+            Event::RecordSentToDevice(_) => Topic::DeviceSent,
+            Event::BatteryAlert { .. } => Topic::Battery,
+            Event::AudioRequest(_) => Topic::AudioRequest,
+            Event::AudioResponse(_) => Topic::AudioResponse,
+            Event::SonarRequest(_) => Topic::SonarRequest,
+            Event::SonarResponse(_) => Topic::SonarResponse,
+            Event::BindingsRequest(_) => Topic::BindingsRequest,
+            Event::BindingsResponse(_) => Topic::BindingsResponse,
+            Event::HistoryRequest(_) => Topic::HistoryRequest,
+            Event::HistoryResponse(_) => Topic::HistoryResponse,
+            Event::ConfigChanged(_) => Topic::Config,
+            Event::StateChanged(_) => Topic::State,
+        }
+    }
+}
+
+/// Why [`EventBus::send`] couldn't hand an event over.
+#[derive(Debug)]
+pub(crate) enum SendError {
+    /// Nothing takes the event's topic, e.g. because the thread that handles it has died.
+    NoSubscriber(Topic),
+    /// The subscriber's queue is full of requests it hasn't got to yet.
+    Full {
+        subscriber: &'static str,
+        topic: Topic,
+    },
+}
+
+impl Display for SendError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SendError::NoSubscriber(topic) => write!(f, "Nothing handles {topic:?} events"),
+            SendError::Full { subscriber, topic } => {
+                write!(f, "{subscriber} is too far behind to take {topic:?} events")
+            }
+        }
+    }
+}
+
+/// Publish/subscribe hub every thread talks through. Each subscriber has its own bounded queue,
+/// so one slow consumer only loses its own oldest notifications and never blocks the publisher.
+/// Commands are never dropped, see [`Topic::is_command`].
+#[derive(Clone, Default)]
+pub(crate) struct EventBus {
+    subscribers: Arc<Mutex<Vec<Weak<Queue>>>>,
+}
+
+impl EventBus {
+    /// Hands `event` to everyone subscribed to its topic, if anyone is.
+    pub(crate) fn publish(&self, event: Event) {
+        if let Err(e @ SendError::Full { .. }) = self.send(event) {
+            warn!("{e}");
+        }
+    }
+
+    /// Like [`EventBus::publish`], for requests that are pointless without a handler or when
+    /// the handler can't take them.
+    pub(crate) fn send(&self, event: Event) -> Result<(), SendError> {
+        let topic = event.topic();
+        let event = Arc::new(event);
+        let mut delivered = 0;
+        let mut refused = None;
+
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain(|queue| match queue.upgrade() {
+                Some(queue) => {
+                    if queue.topics.contains(&topic) {
+                        match queue.push(event.clone()) {
+                            true => delivered += 1,
+                            false => refused = Some(queue.name),
+                        }
+                    }
+                    true
+                }
+                None => false,
+            });
+
+        match (refused, delivered) {
+            (Some(subscriber), _) => Err(SendError::Full { subscriber, topic }),
+            (None, 0) => Err(SendError::NoSubscriber(topic)),
+            (None, _) => Ok(()),
+        }
+    }
+
+    /// `name` shows up in lag warnings.
+    pub(crate) fn subscribe(&self, name: &'static str, topics: &[Topic]) -> Subscription {
+        let queue = Arc::new(Queue {
+            name,
+            topics: topics.to_vec(),
+            capacity: CAPACITY,
+            state: Mutex::new(QueueState::default()),
+            ready: Condvar::new(),
+            notify: Notify::new(),
+        });
+        self.subscribers
+            .lock()
+            .unwrap()
+            .push(Arc::downgrade(&queue));
+
+        Subscription { queue }
+    }
+
+    /// Sends `request` and waits for the first event on `reply` that `extract` accepts. The
+    /// subscription is in place before the request goes out, so a quick answer isn't missed.
+    pub(crate) fn request<T>(
+        &self,
+        request: Event,
+        reply: Topic,
+        timeout: Duration,
+        extract: impl Fn(&Event) -> Option<T>,
+    ) -> Result<Option<T>, SendError> {
+        let replies = self.subscribe("request", &[reply]);
+        self.send(request)?;
+
+        let deadline = Instant::now() + timeout;
+        while let Some(event) =
+            replies.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+        {
+            if let Some(value) = extract(&event) {
+                return Ok(Some(value));
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+#[derive(Default)]
+struct QueueState {
+    events: VecDeque<Arc<Event>>,
+    /// Events dropped since the subscriber last received one.
+    lagged: usize,
+}
+
+struct Queue {
+    name: &'static str,
+    topics: Vec<Topic>,
+    capacity: usize,
+    state: Mutex<QueueState>,
+    /// Wakes blocking receivers.
+    ready: Condvar,
+    /// Wakes async receivers.
+    notify: Notify,
+}
+
+impl Queue {
+    /// Queues `event`, making room by dropping the oldest notification. Commands are never
+    /// dropped, so when only commands are queued a new command is refused and this returns false.
+    fn push(&self, event: Arc<Event>) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.events.len() >= self.capacity {
+            let oldest = state
+                .events
+                .iter()
+                .position(|queued| !queued.topic().is_command());
+            match oldest {
+                Some(oldest) => {
+                    state.events.remove(oldest);
+                }
+                None if event.topic().is_command() => return false,
+                None => {
+                    state.lagged += 1;
+                    return true;
+                }
+            }
+            state.lagged += 1;
+        }
+        state.events.push_back(event);
+        drop(state);
+
+        self.ready.notify_one();
+        self.notify.notify_one();
+        true
+    }
+
+    fn pop(&self, state: &mut QueueState) -> Option<Arc<Event>> {
+        let event = state.events.pop_front()?;
+        if state.lagged > 0 {
+            warn!(
+                subscriber = self.name,
+                missed = state.lagged,
+                "Subscriber fell behind and missed events"
+            );
+            state.lagged = 0;
+        }
+
+        Some(event)
+    }
+}
+
+/// One subscriber's end of the [`EventBus`]. Unsubscribes when dropped.
+pub(crate) struct Subscription {
+    queue: Arc<Queue>,
+}
+
+impl Subscription {
+    pub(crate) fn try_recv(&self) -> Option<Arc<Event>> {
+        let mut state = self.queue.state.lock().unwrap();
+        self.queue.pop(&mut state)
+    }
+
+    pub(crate) fn recv_timeout(&self, timeout: Duration) -> Option<Arc<Event>> {
+        let state = self.queue.state.lock().unwrap();
+        let (mut state, _) = self
+            .queue
+            .ready
+            .wait_timeout_while(state, timeout, |state| state.events.is_empty())
+            .unwrap();
+
+        self.queue.pop(&mut state)
+    }
+
+    pub(crate) fn blocking_recv(&self) -> Arc<Event> {
+        let state = self.queue.state.lock().unwrap();
+        let mut state = self
+            .queue
+            .ready
+            .wait_while(state, |state| state.events.is_empty())
+            .unwrap();
+
+        self.queue.pop(&mut state).expect("Woken without an event")
+    }
+
+    pub(crate) async fn recv(&self) -> Arc<Event> {
+        loop {
+            if let Some(event) = self.try_recv() {
+                return event;
+            }
+
+            // A push between the check and here leaves a permit, so this doesn't miss it
+            self.queue.notify.notified().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::record::{Record, RecordData};
+
+    fn command(serial: u32) -> Event {
+        Event::RecordToDevice(Record::new(serial, RecordData::Ping))
+    }
+
+    fn serial(event: &Event) -> u32 {
+        match event {
+            Event::RecordToDevice(record) => record.serial,
+            _ => panic!("Not a command: {:?}", event.topic()),
+        }
+    }
+
+    #[test]
+    fn full_subscriber_refuses_commands() {
+        let bus = EventBus::default();
+        let device = bus.subscribe("device", &[Topic::DeviceCommand]);
+        for serial in 0..CAPACITY as u32 {
+            bus.send(command(serial)).unwrap();
+        }
+
+        assert!(matches!(
+            bus.send(command(CAPACITY as u32)),
+            Err(SendError::Full {
+                subscriber: "device",
+                topic: Topic::DeviceCommand
+            })
+        ));
+        assert_eq!(serial(&device.try_recv().unwrap()), 0);
+    }
+
+    #[test]
+    fn full_subscriber_drops_notifications_before_commands() {
+        let bus = EventBus::default();
+        let all = bus.subscribe("all", &Topic::ALL);
+        bus.send(command(0)).unwrap();
+        for _ in 1..CAPACITY {
+            bus.publish(Event::DeviceDisconnected);
+        }
+
+        bus.send(command(1)).unwrap();
+        assert_eq!(serial(&all.try_recv().unwrap()), 0);
+        for _ in 2..CAPACITY {
+            assert!(matches!(
+                *all.try_recv().unwrap(),
+                Event::DeviceDisconnected
+            ));
+        }
+        assert_eq!(serial(&all.try_recv().unwrap()), 1);
+        assert!(all.try_recv().is_none());
+    }
+}
@@ -0,0 +1,262 @@
+use crate::history::Kind;
+use crate::record::RecordData;
+use crate::steelseries::api::sonar::types::RedirectionId;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+pub(crate) const USAGE: &str = "\
+Usage: kbd-companion [options] [command]
+
+Without a command the app runs until closed.
+
+Commands:
+    devices                         List the keyboard's HID interfaces
+    ping                            Ping the keyboard and report the round trip
+    battery                         Read the battery level
+    led-meter <percent> [--warn <percent>] [--danger <percent>] [--linger <ms>]
+                                    Show a value on the LED meter
+    send <record-json>              Send a record, e.g. '{\"type\":\"ping\"}' or
+                                    '{\"type\":\"set-output-mute-state\",\"muted\":true}',
+                                    and print replies
+    schema                          Print the JSON Schema of records
+    history [--days <n>] [--kind battery|connection|mute] [--csv]
+                                    Print the recorded battery, connection and mute
+                                    history, as CSV with --csv
+    state [--watch]                 Print the running app's state, and with --watch
+                                    keep printing what changes
+    sonar devices                   List Sonar's audio devices
+    sonar redirections              List Sonar's classic redirections
+    sonar redirect <id> <device>    Point a redirection (game, chat, ...) at a device
+
+Options:
+    --json              Print command results as JSON
+    --headless          Run without the window, until interrupted
+    --simulate          Talk to a simulated keyboard instead of the real one
+    --log-file <path>   Write logs to daily files named after this path, e.g.
+                        app.2025-01-31.log for app.log. The app logs to
+                        kbd-companion.log in the data directory by default,
+                        commands only with this option
+
+Exit status:
+    0   Closed normally
+    1   Something failed, see the log
+    2   Invalid arguments
+    3   The keyboard wasn't found within device.connect_retries attempts";
+
+#[derive(Debug, Default)]
+pub(crate) enum Command {
+    /// Run the app, with or without the window.
+    #[default]
+    Run,
+    Devices,
+    Ping,
+    Battery,
+    LedMeter {
+        percent: u8,
+        warning_threshold: u8,
+        danger_threshold: u8,
+        linger_time: u16,
+    },
+    Send(RecordData),
+    Schema,
+    History {
+        days: Option<u32>,
+        kind: Option<Kind>,
+        csv: bool,
+    },
+    /// Ask the running app for its state over IPC.
+    State {
+        watch: bool,
+    },
+    Sonar(SonarCommand),
+}
+
+#[derive(Debug)]
+pub(crate) enum SonarCommand {
+    Devices,
+    Redirections,
+    Redirect {
+        redirection: RedirectionId,
+        device: String,
+    },
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Options {
+    pub(crate) command: Command,
+    pub(crate) json: bool,
+    pub(crate) headless: bool,
+    pub(crate) simulate: bool,
+    pub(crate) log_file: Option<PathBuf>,
+}
+
+impl Options {
+    pub(crate) fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let mut options = Self::default();
+        let mut rest = Vec::new();
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--json" => options.json = true,
+                "--headless" => options.headless = true,
+                "--simulate" => options.simulate = true,
+                "--log-file" => {
+                    let path = args.next().ok_or("--log-file needs a path")?;
+                    options.log_file = Some(PathBuf::from(path));
+                }
+                _ => rest.push(arg),
+            }
+        }
+
+        options.command = Command::parse(rest.into_iter())?;
+        Ok(options)
+    }
+
+    /// Where log files go, `None` meaning nowhere.
+    pub(crate) fn log_path(&self) -> Option<PathBuf> {
+        match &self.log_file {
+            Some(path) => Some(path.clone()),
+            None if matches!(self.command, Command::Run) => dirs::data_local_dir()
+                .map(|dir| dir.join("kbd-companion").join("kbd-companion.log")),
+            None => None,
+        }
+    }
+
+    /// Whether logs also go to stderr. Headless runs only log to the file, unless there is none.
+    pub(crate) fn log_to_stderr(&self) -> bool {
+        !self.headless || self.log_path().is_none()
+    }
+}
+
+impl Command {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let Some(name) = args.next() else {
+            return Ok(Command::Run);
+        };
+
+        let command = match name.as_str() {
+            "devices" => Command::Devices,
+            "ping" => Command::Ping,
+            "battery" => Command::Battery,
+            "led-meter" => {
+                let percent = parse_percent("percent", args.next())?;
+                let mut warning_threshold = 0;
+                let mut danger_threshold = 0;
+                let mut linger_time = 1000;
+
+                while let Some(flag) = args.next() {
+                    match flag.as_str() {
+                        "--warn" => warning_threshold = parse_percent(&flag, args.next())?,
+                        "--danger" => danger_threshold = parse_percent(&flag, args.next())?,
+                        "--linger" => linger_time = parse_value(&flag, args.next())?,
+                        _ => return Err(format!("Unknown led-meter option '{flag}'")),
+                    }
+                }
+
+                Command::LedMeter {
+                    percent,
+                    warning_threshold,
+                    danger_threshold,
+                    linger_time,
+                }
+            }
+            "send" => {
+                let json = args.next().ok_or("send needs a record")?;
+                let record =
+                    serde_json::from_str(&json).map_err(|e| format!("Invalid record: {e}"))?;
+                Command::Send(record)
+            }
+            "schema" => Command::Schema,
+            "history" => {
+                let mut days = None;
+                let mut kind = None;
+                let mut csv = false;
+
+                while let Some(flag) = args.next() {
+                    match flag.as_str() {
+                        "--days" => days = Some(parse_value(&flag, args.next())?),
+                        "--kind" => kind = Some(parse_value(&flag, args.next())?),
+                        "--csv" => csv = true,
+                        _ => return Err(format!("Unknown history option '{flag}'")),
+                    }
+                }
+
+                Command::History { days, kind, csv }
+            }
+            "state" => match args.next().as_deref() {
+                Some("--watch") => Command::State { watch: true },
+                Some(other) => return Err(format!("Unknown state option '{other}'")),
+                None => Command::State { watch: false },
+            },
+            "sonar" => match args.next().as_deref() {
+                Some("devices") => Command::Sonar(SonarCommand::Devices),
+                Some("redirections") => Command::Sonar(SonarCommand::Redirections),
+                Some("redirect") => {
+                    let id = args.next().ok_or("sonar redirect needs a redirection")?;
+                    let redirection = RedirectionId::from_str(&id)
+                        .map_err(|_| format!("Unknown redirection '{id}'"))?;
+                    let device = args.next().ok_or("sonar redirect needs a device")?;
+                    Command::Sonar(SonarCommand::Redirect {
+                        redirection,
+                        device,
+                    })
+                }
+                Some(other) => return Err(format!("Unknown sonar command '{other}'")),
+                None => return Err("sonar needs a command".to_string()),
+            },
+            _ => return Err(format!("Unknown command '{name}'")),
+        };
+
+        match args.next() {
+            Some(extra) => Err(format!("Unexpected argument '{extra}'")),
+            None => Ok(command),
+        }
+    }
+}
+
+fn parse_value<T: FromStr>(name: &str, value: Option<String>) -> Result<T, String> {
+    let value = value.ok_or_else(|| format!("{name} needs a value"))?;
+    value
+        .parse()
+        .map_err(|_| format!("Invalid value '{value}' for {name}"))
+}
+
+fn parse_percent(name: &str, value: Option<String>) -> Result<u8, String> {
+    match parse_value(name, value)? {
+        percent @ 0..=100 => Ok(percent),
+        percent => Err(format!("{name} is a percentage, got {percent}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Options {
+        Options::parse(args.iter().map(|arg| arg.to_string())).unwrap()
+    }
+
+    #[test]
+    fn app_logs_to_data_dir_by_default() {
+        let has_data_dir = dirs::data_local_dir().is_some();
+
+        let gui = parse(&[]);
+        assert_eq!(gui.log_path().is_some(), has_data_dir);
+        assert!(gui.log_to_stderr());
+
+        let headless = parse(&["--headless"]);
+        assert_eq!(headless.log_path(), gui.log_path());
+        assert_eq!(headless.log_to_stderr(), !has_data_dir);
+    }
+
+    #[test]
+    fn commands_log_to_file_only_when_asked() {
+        let ping = parse(&["ping"]);
+        assert_eq!(ping.log_path(), None);
+        assert!(ping.log_to_stderr());
+
+        let ping = parse(&["--log-file", "ping.log", "ping"]);
+        assert_eq!(ping.log_path(), Some(PathBuf::from("ping.log")));
+        assert!(ping.log_to_stderr());
+    }
+}
@@ -0,0 +1,368 @@
+use crate::cli::{Command, Options, SonarCommand};
+use crate::config::{Config, DeviceConfig, IpcConfig, SonarConfig};
+use crate::hid_device_channel::{HidDeviceChannel, ReadError, WriteError};
+use crate::history::{self, History, HistoryQuery, Kind};
+use crate::ipc::IpcClient;
+use crate::record::{Record, RecordData};
+use crate::steelseries::api::sonar::Client;
+use crate::steelseries::SteelSeriesEngineClient;
+use chrono::Utc;
+use hidapi::HidError;
+use serde::Serialize;
+use serde_json::Value;
+use std::fmt::{Display, Formatter};
+use std::process::ExitCode;
+use std::time::{Duration, Instant};
+
+/// How long to wait for the keyboard to answer a one-shot command.
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Debug)]
+enum CommandError {
+    Connect(HidError),
+    Write(WriteError),
+    Read(ReadError),
+    NoResponse,
+    Sonar(String),
+    Ipc(String),
+    History(String),
+    Json(serde_json::Error),
+}
+
+impl Display for CommandError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CommandError::Connect(e) => write!(f, "Failed to open the keyboard: {e}"),
+            CommandError::Write(e) => write!(f, "Failed to send to the keyboard: {e:?}"),
+            CommandError::Read(e) => write!(f, "Failed to read from the keyboard: {e:?}"),
+            CommandError::NoResponse => write!(f, "The keyboard didn't answer"),
+            CommandError::Sonar(e) => write!(f, "Sonar request failed: {e}"),
+            CommandError::Ipc(e) => write!(f, "Failed to reach the app: {e}"),
+            CommandError::History(e) => write!(f, "Failed to read the history: {e}"),
+            CommandError::Json(e) => write!(f, "Failed to encode output: {e}"),
+        }
+    }
+}
+
+type CommandResult = Result<(), CommandError>;
+
+/// Runs a one-shot command and prints its result, as JSON with `--json`.
+pub(crate) fn run(options: &Options, config: &Config) -> ExitCode {
+    let result = match &options.command {
+        Command::Run => return ExitCode::SUCCESS,
+        Command::Devices => devices(&config.device, options.json),
+        Command::Schema => schema(),
+        Command::History { days, kind, csv } => history(*days, *kind, *csv, options.json),
+        Command::State { watch } => state(&config.ipc, *watch, options.json),
+        Command::Sonar(command) => sonar(command, &config.sonar, options.json),
+        command => open(options, &config.device)
+            .and_then(|channel| device_command(command, &channel, options.json)),
+    };
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn open(options: &Options, device: &DeviceConfig) -> Result<HidDeviceChannel, CommandError> {
+    if options.simulate {
+        return Ok(HidDeviceChannel::simulated());
+    }
+
+    HidDeviceChannel::connect(
+        device.vendor_id,
+        device.product_id,
+        device.usage_page,
+        device.usage,
+    )
+    .map_err(CommandError::Connect)
+}
+
+fn print<T: Serialize>(json: bool, value: &T, human: impl FnOnce(&T)) -> CommandResult {
+    if json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(value).map_err(CommandError::Json)?
+        );
+    } else {
+        human(value);
+    }
+
+    Ok(())
+}
+
+/// Sends `data` and collects what comes back until `done` is satisfied or the timeout passes.
+fn exchange(
+    channel: &HidDeviceChannel,
+    data: RecordData,
+    done: impl Fn(&RecordData) -> bool,
+) -> Result<Vec<Record>, CommandError> {
+    channel
+        .write_record(Record::new(0, data))
+        .map_err(CommandError::Write)?;
+
+    let started = Instant::now();
+    let mut received = Vec::new();
+    while started.elapsed() < RESPONSE_TIMEOUT {
+        let timeout = RESPONSE_TIMEOUT.saturating_sub(started.elapsed());
+        if let Some(record) = channel
+            .read_record(Some(timeout.as_millis() as i32))
+            .map_err(CommandError::Read)?
+        {
+            received.push(record);
+            if done(&record.data) {
+                break;
+            }
+        }
+    }
+
+    Ok(received)
+}
+
+fn devices(device: &DeviceConfig, json: bool) -> CommandResult {
+    let interfaces = HidDeviceChannel::list(
+        device.vendor_id,
+        device.product_id,
+        device.usage_page,
+        device.usage,
+    )
+    .map_err(CommandError::Connect)?;
+
+    print(json, &interfaces, |interfaces| {
+        interfaces.iter().for_each(|interface| {
+            println!(
+                "{}{:04x}:{:04x} usage {:04x}:{:02x} interface {} {} ({})",
+                if interface.selected { "* " } else { "  " },
+                interface.vendor_id,
+                interface.product_id,
+                interface.usage_page,
+                interface.usage,
+                interface.interface_number,
+                interface.product.as_deref().unwrap_or("unknown"),
+                interface.path
+            );
+        });
+    })
+}
+
+/// Always JSON, there is no other useful form of a schema.
+fn schema() -> CommandResult {
+    let schema = schemars::schema_for!(Record);
+    print(true, &schema, |_| {})
+}
+
+fn device_command(command: &Command, channel: &HidDeviceChannel, json: bool) -> CommandResult {
+    match *command {
+        Command::Ping => {
+            #[derive(Serialize)]
+            struct Ping {
+                round_trip_ms: u128,
+            }
+
+            let started = Instant::now();
+            exchange(channel, RecordData::Ping, |data| *data == RecordData::Pong)?
+                .iter()
+                .find(|record| record.data == RecordData::Pong)
+                .ok_or(CommandError::NoResponse)?;
+
+            let ping = Ping {
+                round_trip_ms: started.elapsed().as_millis(),
+            };
+            print(json, &ping, |ping| {
+                println!("Pong in {} ms", ping.round_trip_ms)
+            })
+        }
+        Command::Battery => {
+            #[derive(Serialize)]
+            struct Battery {
+                percent: u8,
+                voltage: u16,
+            }
+
+            let is_battery = |data: &RecordData| matches!(data, RecordData::BatteryResponse { .. });
+            let battery = exchange(channel, RecordData::BatteryRequest, is_battery)?
+                .into_iter()
+                .find_map(|record| match record.data {
+                    RecordData::BatteryResponse { percent, voltage } => {
+                        Some(Battery { percent, voltage })
+                    }
+                    _ => None,
+                })
+                .ok_or(CommandError::NoResponse)?;
+
+            print(json, &battery, |battery| {
+                println!("{}% ({} mV)", battery.percent, battery.voltage)
+            })
+        }
+        Command::LedMeter {
+            percent,
+            warning_threshold,
+            danger_threshold,
+            linger_time,
+        } => {
+            let record = Record::new(
+                0,
+                RecordData::SetLedMeter {
+                    percent,
+                    warning_threshold,
+                    danger_threshold,
+                    invert: false,
+                    linger_time,
+                },
+            );
+            channel.write_record(record).map_err(CommandError::Write)?;
+
+            print(json, &record, |record| println!("Sent {:?}", record.data))
+        }
+        Command::Send(data) => {
+            let replies = exchange(channel, data, |_| false)?;
+
+            print(json, &replies, |replies| {
+                replies
+                    .iter()
+                    .for_each(|record| println!("{} {:?}", record.serial, record.data));
+            })
+        }
+        Command::Run
+        | Command::Devices
+        | Command::Schema
+        | Command::History { .. }
+        | Command::State { .. }
+        | Command::Sonar(_) => Ok(()),
+    }
+}
+
+fn history(days: Option<u32>, kind: Option<Kind>, csv: bool, json: bool) -> CommandResult {
+    let query = HistoryQuery {
+        since: days.map(|days| Utc::now() - chrono::Duration::days(days as i64)),
+        kind,
+        ..Default::default()
+    };
+    let entries = History::open()
+        .and_then(|history| history.query(&query).map_err(|e| e.to_string()))
+        .map_err(CommandError::History)?;
+
+    if csv {
+        return history::write_csv(&entries, std::io::stdout())
+            .map_err(|e| CommandError::History(e.to_string()));
+    }
+    print(json, &entries, |entries| {
+        entries.iter().for_each(|entry| println!("{entry}"));
+    })
+}
+
+/// Prints the running app's state, then with `watch` each change as it happens.
+fn state(config: &IpcConfig, watch: bool, json: bool) -> CommandResult {
+    let mut client = IpcClient::connect(config).map_err(CommandError::Ipc)?;
+    let state = client
+        .call("get_state", Value::Null)
+        .map_err(CommandError::Ipc)?;
+    print(json, &state, |state| {
+        state
+            .as_object()
+            .into_iter()
+            .flatten()
+            .for_each(|(field, value)| {
+                println!("{field}: {value}");
+            });
+    })?;
+
+    if !watch {
+        return Ok(());
+    }
+
+    client
+        .call("subscribe", Value::Null)
+        .map_err(CommandError::Ipc)?;
+    loop {
+        let (method, params) = client.notification().map_err(CommandError::Ipc)?;
+        if method != "state" {
+            continue;
+        }
+
+        let changes = params["changes"].as_array().cloned().unwrap_or_default();
+        changes.iter().for_each(|change| match json {
+            true => println!("{change}"),
+            false => println!(
+                "{}: {}",
+                change["field"].as_str().unwrap_or("?"),
+                change["value"]
+            ),
+        });
+    }
+}
+
+fn sonar(command: &SonarCommand, config: &SonarConfig, json: bool) -> CommandResult {
+    let runtime = tokio::runtime::Runtime::new().map_err(|e| CommandError::Sonar(e.to_string()))?;
+
+    runtime.block_on(async {
+        let url = SteelSeriesEngineClient::new_autodetect(&config.core_props)
+            .map_err(|e| CommandError::Sonar(e.to_string()))?
+            .get_subapp_url("sonar")
+            .await
+            .map_err(|e| CommandError::Sonar(e.to_string()))?
+            .ok_or_else(|| CommandError::Sonar("Sonar isn't running".to_string()))?;
+        let client = Client::new(url.as_str());
+
+        match command {
+            SonarCommand::Devices => {
+                let devices = client
+                    .list_audio_devices(None, None, None)
+                    .await
+                    .map_err(|e| CommandError::Sonar(format!("{e:?}")))?
+                    .to_owned();
+
+                print(json, &devices, |devices| {
+                    devices.iter().for_each(|device| {
+                        println!(
+                            "{} {}",
+                            device.id.as_deref().unwrap_or("?"),
+                            device.friendly_name.as_deref().unwrap_or("unknown")
+                        );
+                    });
+                })
+            }
+            SonarCommand::Redirections => {
+                let redirections = client
+                    .list_classic_redirections()
+                    .await
+                    .map_err(|e| CommandError::Sonar(format!("{e:?}")))?
+                    .to_owned();
+
+                print(json, &redirections, |redirections| {
+                    redirections.iter().for_each(|redirection| {
+                        println!(
+                            "{} -> {}",
+                            redirection
+                                .id
+                                .map(|id| id.to_string())
+                                .unwrap_or("?".to_string()),
+                            redirection.device_id.as_deref().unwrap_or("none")
+                        );
+                    });
+                })
+            }
+            SonarCommand::Redirect {
+                redirection,
+                device,
+            } => {
+                let redirection = client
+                    .set_classic_redirection_device(*redirection, device)
+                    .await
+                    .map_err(|e| CommandError::Sonar(format!("{e:?}")))?
+                    .to_owned();
+
+                print(json, &redirection, |redirection| {
+                    println!(
+                        "Redirected to {}",
+                        redirection.device_id.as_deref().unwrap_or("none")
+                    );
+                })
+            }
+        }
+    })
+}
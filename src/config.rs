@@ -0,0 +1,550 @@
+use crate::logging;
+use crate::record::RecordData;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+use tracing::{info, warn};
+
+/// Tunables read from `config.toml` in the config directory. Every key is optional, this is the
+/// full list with the defaults:
+///
+/// ```toml
+/// [device]
+/// vendor_id = 0x3434
+/// product_id = 0x0661
+/// usage_page = 0xFF60
+/// usage = 0x61
+/// read_timeout_ms = 10
+/// connect_retries = 100    # 0 keeps retrying forever
+/// retry_interval_ms = 100
+/// heartbeat_interval_ms = 2000
+/// heartbeat_timeout_ms = 1000
+/// heartbeat_misses = 3     # unanswered pings in a row before reconnecting
+/// decode_error_limit = 10  # malformed reports within the window before reconnecting
+/// decode_error_window_s = 60
+/// max_reports_per_s = 100
+/// acks = false             # true if the firmware acknowledges records, mute states are
+///                          # then retransmitted until it does
+/// ack_timeout_ms = 150     # doubles with every retransmission
+/// ack_retries = 4
+/// reconcile_interval_s = 10
+///
+/// [led]
+/// volume_linger_ms = 1000
+/// cycle_linger_ms = 1500   # how long the meter shows the output device's place in the cycle
+///
+/// [led.battery]
+/// warning_threshold = 6
+/// danger_threshold = 2
+/// linger_ms = 1000
+///
+/// [led.limit]              # shown when the volume limiter pulls the volume down
+/// warning_threshold = 100
+/// danger_threshold = 100
+/// linger_ms = 1500
+///
+/// [led.battery_preview]    # the GUI's "Illuminate battery %" button
+/// warning_threshold = 7
+/// danger_threshold = 2
+/// linger_ms = 2000
+///
+/// [battery]
+/// poll_interval_s = 300
+/// low_poll_interval_s = 60
+/// low_percent = 20
+/// critical_percent = 5     # flashes the LED meter at led.battery.danger_threshold
+/// hysteresis = 3
+///
+/// [history]
+/// enabled = true
+/// retention_days = 90
+/// max_entries = 100000
+///
+/// [sonar]
+/// core_props = 'C:\ProgramData\SteelSeries\GG\coreProps.json'
+///
+/// [ipc]
+/// enabled = true
+/// name = "kbd-companion"
+///
+/// [log]
+/// # level = "debug"        # RUST_LOG syntax, applied without a restart. Unset by default.
+/// ```
+#[derive(Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Config {
+    pub(crate) device: DeviceConfig,
+    pub(crate) led: LedConfig,
+    pub(crate) battery: BatteryConfig,
+    pub(crate) history: HistoryConfig,
+    pub(crate) sonar: SonarConfig,
+    pub(crate) ipc: IpcConfig,
+    pub(crate) log: LogConfig,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct DeviceConfig {
+    pub(crate) vendor_id: u16,
+    pub(crate) product_id: u16,
+    pub(crate) usage_page: u16,
+    pub(crate) usage: u16,
+    pub(crate) read_timeout_ms: u16,
+    pub(crate) connect_retries: u32,
+    pub(crate) retry_interval_ms: u64,
+    pub(crate) heartbeat_interval_ms: u64,
+    /// How long a ping may go unanswered before it counts as missed.
+    pub(crate) heartbeat_timeout_ms: u64,
+    pub(crate) heartbeat_misses: u32,
+    pub(crate) decode_error_limit: u32,
+    pub(crate) decode_error_window_s: u64,
+    /// Cap on records written to the keyboard, pings aside.
+    pub(crate) max_reports_per_s: u32,
+    /// Whether the firmware sends `Ack` records. Older firmware doesn't.
+    pub(crate) acks: bool,
+    pub(crate) ack_timeout_ms: u64,
+    pub(crate) ack_retries: u32,
+    /// How often the mute states and the volume meter are re-sent while a record may have been
+    /// lost.
+    pub(crate) reconcile_interval_s: u64,
+}
+
+impl Default for DeviceConfig {
+    fn default() -> Self {
+        Self {
+            vendor_id: 0x3434,
+            product_id: 0x0661,
+            usage_page: 0xFF60,
+            usage: 0x61,
+            read_timeout_ms: 10,
+            connect_retries: 100,
+            retry_interval_ms: 100,
+            heartbeat_interval_ms: 2000,
+            heartbeat_timeout_ms: 1000,
+            heartbeat_misses: 3,
+            decode_error_limit: 10,
+            decode_error_window_s: 60,
+            max_reports_per_s: 100,
+            acks: false,
+            ack_timeout_ms: 150,
+            ack_retries: 4,
+            reconcile_interval_s: 10,
+        }
+    }
+}
+
+/// How a `SetLedMeter` record is drawn.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct LedMeterStyle {
+    pub(crate) warning_threshold: u8,
+    pub(crate) danger_threshold: u8,
+    pub(crate) linger_ms: u16,
+}
+
+impl LedMeterStyle {
+    pub(crate) fn meter(&self, percent: u8) -> RecordData {
+        RecordData::SetLedMeter {
+            percent,
+            warning_threshold: self.warning_threshold,
+            danger_threshold: self.danger_threshold,
+            invert: false,
+            linger_time: self.linger_ms,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct LedConfig {
+    pub(crate) volume_linger_ms: u16,
+    pub(crate) cycle_linger_ms: u16,
+    pub(crate) battery: LedMeterStyle,
+    /// Used when the volume limiter clamps the volume.
+    pub(crate) limit: LedMeterStyle,
+    /// Used by the GUI's "Illuminate battery %" button.
+    pub(crate) battery_preview: LedMeterStyle,
+}
+
+impl Default for LedConfig {
+    fn default() -> Self {
+        Self {
+            volume_linger_ms: 1000,
+            cycle_linger_ms: 1500,
+            battery: LedMeterStyle {
+                warning_threshold: 6,
+                danger_threshold: 2,
+                linger_ms: 1000,
+            },
+            // The whole bar in the danger colour
+            limit: LedMeterStyle {
+                warning_threshold: 100,
+                danger_threshold: 100,
+                linger_ms: 1500,
+            },
+            battery_preview: LedMeterStyle {
+                warning_threshold: 7,
+                danger_threshold: 2,
+                linger_ms: 2000,
+            },
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct BatteryConfig {
+    pub(crate) poll_interval_s: u64,
+    /// Used instead of `poll_interval_s` while the battery is low or critical.
+    pub(crate) low_poll_interval_s: u64,
+    pub(crate) low_percent: u8,
+    pub(crate) critical_percent: u8,
+    /// Points above a threshold the charge has to climb before its alert clears.
+    pub(crate) hysteresis: u8,
+}
+
+impl Default for BatteryConfig {
+    fn default() -> Self {
+        Self {
+            poll_interval_s: 300,
+            low_poll_interval_s: 60,
+            low_percent: 20,
+            critical_percent: 5,
+            hysteresis: 3,
+        }
+    }
+}
+
+/// Battery, connection and mute history in the data directory. Entries older than
+/// `retention_days` or beyond the newest `max_entries` are dropped every hour.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct HistoryConfig {
+    pub(crate) enabled: bool,
+    pub(crate) retention_days: u32,
+    pub(crate) max_entries: u32,
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            retention_days: 90,
+            max_entries: 100_000,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct SonarConfig {
+    /// SteelSeries GG writes its local address here. Changing it reconnects to Sonar.
+    pub(crate) core_props: PathBuf,
+}
+
+impl Default for SonarConfig {
+    fn default() -> Self {
+        Self {
+            core_props: PathBuf::from("C:\\ProgramData\\SteelSeries\\GG\\coreProps.json"),
+        }
+    }
+}
+
+/// Local JSON-RPC server for other programs. Only read at startup.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct IpcConfig {
+    pub(crate) enabled: bool,
+    /// Named pipe name on Windows, socket file name in the runtime directory elsewhere.
+    pub(crate) name: String,
+}
+
+impl Default for IpcConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            name: "kbd-companion".to_string(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct LogConfig {
+    /// Replaces `RUST_LOG` and the default levels while set.
+    pub(crate) level: Option<String>,
+}
+
+#[derive(Debug)]
+pub(crate) enum ConfigError {
+    Read(PathBuf, std::io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Invalid(PathBuf, String),
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Read(path, e) => write!(f, "Failed to read {}: {e}", path.display()),
+            ConfigError::Parse(path, e) => write!(f, "Failed to parse {}: {e}", path.display()),
+            ConfigError::Invalid(path, e) => write!(f, "Invalid config in {}: {e}", path.display()),
+        }
+    }
+}
+
+/// Our directory inside the platform's config directory.
+pub(crate) fn config_dir() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("kbd-companion"))
+}
+
+/// Reads and validates a TOML file. Missing files give the defaults.
+pub(crate) fn read_toml<T: DeserializeOwned + Default>(
+    path: &Path,
+    validate: impl FnOnce(&T) -> Result<(), String>,
+) -> Result<T, ConfigError> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(T::default()),
+        Err(e) => return Err(ConfigError::Read(path.to_path_buf(), e)),
+    };
+
+    let value =
+        toml::from_str::<T>(&contents).map_err(|e| ConfigError::Parse(path.to_path_buf(), e))?;
+    validate(&value).map_err(|e| ConfigError::Invalid(path.to_path_buf(), e))?;
+
+    Ok(value)
+}
+
+/// Notices edits to a file in the config directory, looking at most once a second.
+pub(crate) struct FileWatcher {
+    path: Option<PathBuf>,
+    modified: Option<SystemTime>,
+    last_check: Instant,
+}
+
+impl FileWatcher {
+    const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+    pub(crate) fn new(file_name: &str) -> Self {
+        let path = config_dir().map(|dir| dir.join(file_name));
+        Self {
+            modified: path.as_deref().and_then(Self::modified),
+            path,
+            last_check: Instant::now(),
+        }
+    }
+
+    /// `None` when the system has no config directory.
+    pub(crate) fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    fn modified(path: &Path) -> Option<SystemTime> {
+        std::fs::metadata(path).and_then(|m| m.modified()).ok()
+    }
+
+    /// The path, if the file changed since the last call.
+    pub(crate) fn changed(&mut self) -> Option<&Path> {
+        if self.last_check.elapsed() < Self::CHECK_INTERVAL {
+            return None;
+        }
+        self.last_check = Instant::now();
+
+        let path = self.path.as_deref()?;
+        let modified = Self::modified(path);
+        if modified == self.modified {
+            return None;
+        }
+        self.modified = modified;
+
+        Some(path)
+    }
+}
+
+/// Watches nothing, for state that isn't backed by a file.
+impl Default for FileWatcher {
+    fn default() -> Self {
+        Self {
+            path: None,
+            modified: None,
+            last_check: Instant::now(),
+        }
+    }
+}
+
+impl Config {
+    pub(crate) const FILE_NAME: &str = "config.toml";
+
+    fn read(path: &Path) -> Result<Self, ConfigError> {
+        read_toml(path, Config::validate)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.device.read_timeout_ms == 0 {
+            return Err("device.read_timeout_ms must be at least 1".to_string());
+        }
+        if self.device.heartbeat_timeout_ms == 0
+            || self.device.heartbeat_timeout_ms > self.device.heartbeat_interval_ms
+        {
+            return Err(
+                "device.heartbeat_timeout_ms must be between 1 and heartbeat_interval_ms"
+                    .to_string(),
+            );
+        }
+        if self.device.heartbeat_misses == 0 {
+            return Err("device.heartbeat_misses must be at least 1".to_string());
+        }
+        if self.device.decode_error_limit == 0 {
+            return Err("device.decode_error_limit must be at least 1".to_string());
+        }
+        if self.device.max_reports_per_s == 0 {
+            return Err("device.max_reports_per_s must be at least 1".to_string());
+        }
+        if self.device.ack_timeout_ms == 0 {
+            return Err("device.ack_timeout_ms must be at least 1".to_string());
+        }
+        if self.battery.poll_interval_s == 0 || self.battery.low_poll_interval_s == 0 {
+            return Err("battery poll intervals must be at least 1".to_string());
+        }
+        if self.battery.low_percent > 100
+            || self.battery.critical_percent > self.battery.low_percent
+        {
+            return Err("battery needs critical_percent <= low_percent <= 100".to_string());
+        }
+        if self.history.retention_days == 0 || self.history.max_entries == 0 {
+            return Err("history keeps at least a day and an entry".to_string());
+        }
+        if let Some(level) = &self.log.level {
+            logging::parse_filter(level)?;
+        }
+
+        [
+            ("led.battery", &self.led.battery),
+            ("led.limit", &self.led.limit),
+            ("led.battery_preview", &self.led.battery_preview),
+        ]
+        .into_iter()
+        .try_for_each(|(name, style)| {
+            [
+                ("warning_threshold", style.warning_threshold),
+                ("danger_threshold", style.danger_threshold),
+            ]
+            .into_iter()
+            .try_for_each(|(key, value)| match value {
+                0..=100 => Ok(()),
+                _ => Err(format!("{name}.{key} is a percentage, got {value}")),
+            })
+        })
+    }
+}
+
+/// Keeps the config in sync with the file on disk. A file that fails to load keeps the previous
+/// config in place.
+pub(crate) struct ConfigWatcher {
+    file: FileWatcher,
+    config: Config,
+}
+
+impl ConfigWatcher {
+    pub(crate) fn load() -> Self {
+        let file = FileWatcher::new(Config::FILE_NAME);
+        let config = match file.path().map(Config::read) {
+            Some(Ok(config)) => config,
+            Some(Err(e)) => {
+                warn!("{e}, using defaults");
+                Config::default()
+            }
+            None => {
+                warn!("No config directory on this system, using defaults");
+                Config::default()
+            }
+        };
+
+        Self { file, config }
+    }
+
+    /// A watcher over `config` that never touches the disk.
+    #[cfg(test)]
+    pub(crate) fn fixed(config: Config) -> Self {
+        Self {
+            file: FileWatcher::default(),
+            config,
+        }
+    }
+
+    pub(crate) fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Reloads the file when it changed since the last call. Returns the new config if the
+    /// settings are different.
+    pub(crate) fn poll(&mut self) -> Option<&Config> {
+        let path = self.file.changed()?;
+
+        match Config::read(path) {
+            Ok(config) if config != self.config => {
+                info!("Reloaded {}", path.display());
+                self.config = config;
+                Some(&self.config)
+            }
+            Ok(_) => None,
+            Err(e) => {
+                warn!("{e}, keeping the previous config");
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(toml: &str) -> Result<Config, String> {
+        let config = toml::from_str::<Config>(toml).map_err(|e| e.to_string())?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// The example in [`Config`]'s documentation, without the comment markers.
+    fn documented_example() -> String {
+        let source = include_str!("config.rs");
+        let start = source.find("/// ```toml\n").unwrap() + "/// ```toml\n".len();
+        let end = start + source[start..].find("/// ```\n").unwrap();
+        source[start..end]
+            .lines()
+            .map(|line| line.trim_start_matches("///").trim_start())
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    #[test]
+    fn documented_example_lists_the_defaults() {
+        let example = documented_example();
+        assert!(example.contains("[log]"), "{example}");
+        assert_eq!(parse(&example), Ok(Config::default()));
+    }
+
+    #[test]
+    fn heartbeat_timeout_must_fit_the_interval() {
+        let error = parse("[device]\nheartbeat_interval_ms = 500\nheartbeat_timeout_ms = 1000")
+            .unwrap_err();
+        assert!(error.contains("heartbeat_timeout_ms"), "{error}");
+        assert!(parse("[device]\nheartbeat_timeout_ms = 0").is_err());
+    }
+
+    #[test]
+    fn critical_battery_must_not_exceed_low() {
+        let error = parse("[battery]\nlow_percent = 10\ncritical_percent = 15").unwrap_err();
+        assert!(error.contains("critical_percent"), "{error}");
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected() {
+        let error = parse("[device]\nheartbeat_interval = 500").unwrap_err();
+        assert!(error.contains("heartbeat_interval"), "{error}");
+        assert!(parse("[leds]").is_err());
+    }
+}
@@ -1,18 +1,36 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")] // hide console window on Windows in release
 
+use crate::bus::{EventBus, Subscription, Topic};
+use crate::config::Config;
+use crate::gui::bindings::BindingsView;
+use crate::gui::devices::DevicesView;
 use crate::gui::keyboard::KeyboardView;
+use crate::gui::sessions::SessionsView;
 use crate::gui::steelseries::SonarView;
+use crate::shutdown::Shutdown;
+use crate::state::StateStore;
 use crate::Event;
 use eframe::egui;
 use eframe::egui::Button;
 use std::cmp::PartialEq;
 use std::default::Default;
-use std::sync::mpsc::{Receiver, Sender};
-use tokio::sync::mpsc::UnboundedSender;
+use std::time::Duration;
+use tracing::warn;
 
+mod bindings;
+mod devices;
 mod keyboard;
+mod sessions;
 mod steelseries;
 
+/// Sends a request on behalf of a view. One that doesn't go through only leaves the view stale,
+/// so it's logged rather than fatal.
+fn send(bus: &EventBus, event: Event) {
+    if let Err(e) = bus.send(event) {
+        warn!("{e}");
+    }
+}
+
 trait View {
     fn init(&mut self);
     fn render(&mut self, ui: &mut egui::Ui);
@@ -22,16 +40,32 @@ trait View {
 #[derive(PartialEq)]
 enum Tab {
     Device,
+    AudioDevices,
+    Sessions,
+    Bindings,
     Sonar,
 }
 
+/// What the window shows.
+pub(crate) const TOPICS: [Topic; 8] = [
+    Topic::Device,
+    Topic::DeviceSent,
+    Topic::AudioResponse,
+    Topic::SonarResponse,
+    Topic::BindingsResponse,
+    Topic::HistoryResponse,
+    Topic::Config,
+    Topic::State,
+];
+
+/// `events` should be subscribed to [`TOPICS`].
 pub fn init_gui(
-    rx: Receiver<Event>,
-    tx: Sender<Event>,
-    ss_tx: UnboundedSender<Event>,
+    events: Subscription,
+    bus: EventBus,
+    state: &StateStore,
+    config: Config,
+    shutdown: Shutdown,
 ) -> eframe::Result {
-    env_logger::init(); // Log to stderr (if you run with `RUST_LOG=debug`).
-
     let options = eframe::NativeOptions {
         viewport: egui::ViewportBuilder::default()
             .with_inner_size([640.0, 480.0])
@@ -42,29 +76,59 @@ pub fn init_gui(
     // TODO: refactor into state struct
     let mut tab = Tab::Device;
 
-    let mut sonar_view = SonarView::new(ss_tx.clone());
-    let mut keyboard_view = KeyboardView::new(tx);
+    let snapshot = state.snapshot();
+    let mut sonar_view = SonarView::new(bus.clone(), snapshot.sonar_redirections.clone());
+    let mut keyboard_view = KeyboardView::new(
+        bus.clone(),
+        snapshot,
+        config.led.battery_preview,
+        config.history.enabled,
+    );
+    let mut sessions_view = SessionsView::new(bus.clone());
+    let mut devices_view = DevicesView::new(bus.clone());
+    let mut bindings_view = BindingsView::new(bus);
 
     sonar_view.init();
     keyboard_view.init();
+    sessions_view.init();
+    devices_view.init();
+    bindings_view.init();
 
     eframe::run_simple_native("Controller", options, move |ctx, _frame| {
-        match rx.try_recv() {
-            Ok(event) => {
-                sonar_view.process_event(&event);
-                keyboard_view.process_event(&event);
-            }
-            _ => {}
+        if shutdown.is_requested() {
+            ctx.send_viewport_cmd(egui::ViewportCommand::Close);
+        }
+        // Keep polling while idle so a shutdown from elsewhere closes the window
+        ctx.request_repaint_after(Duration::from_millis(250));
+
+        while let Some(event) = events.try_recv() {
+            sonar_view.process_event(&event);
+            keyboard_view.process_event(&event);
+            sessions_view.process_event(&event);
+            devices_view.process_event(&event);
+            bindings_view.process_event(&event);
         }
 
         egui::CentralPanel::default().show(ctx, |mut ui| {
             ui.horizontal(|ui| {
                 ui.scope(|ui| {
                     let device_btn = ui.add(Button::new("Keyboard").selected(tab == Tab::Device));
+                    let devices_btn =
+                        ui.add(Button::new("Audio Devices").selected(tab == Tab::AudioDevices));
+                    let sessions_btn =
+                        ui.add(Button::new("Sessions").selected(tab == Tab::Sessions));
+                    let bindings_btn =
+                        ui.add(Button::new("Bindings").selected(tab == Tab::Bindings));
                     let sonar_btn = ui.add(Button::new("Sonar").selected(tab == Tab::Sonar));
 
                     if device_btn.clicked() {
                         tab = Tab::Device
+                    } else if devices_btn.clicked() {
+                        tab = Tab::AudioDevices;
+                    } else if sessions_btn.clicked() {
+                        tab = Tab::Sessions;
+                    } else if bindings_btn.clicked() {
+                        tab = Tab::Bindings;
                     } else if sonar_btn.clicked() {
                         tab = Tab::Sonar;
                     }
@@ -79,6 +143,15 @@ pub fn init_gui(
                 Tab::Device => {
                     keyboard_view.render(&mut ui);
                 }
+                Tab::AudioDevices => {
+                    devices_view.render(&mut ui);
+                }
+                Tab::Sessions => {
+                    sessions_view.render(&mut ui);
+                }
+                Tab::Bindings => {
+                    bindings_view.render(&mut ui);
+                }
             }
         });
     })
@@ -0,0 +1,127 @@
+use crate::bindings::ActiveBinding;
+use crate::bus::EventBus;
+use crate::gui::View;
+use crate::{BindingsRequest, BindingsResponse, Event};
+use eframe::egui::{ComboBox, Grid, ScrollArea, Ui};
+use std::path::PathBuf;
+
+pub(super) struct BindingsView {
+    path: Option<PathBuf>,
+    error: Option<String>,
+    profile: Option<String>,
+    profiles: Vec<String>,
+    layer: u8,
+    bindings: Vec<ActiveBinding>,
+    bus: EventBus,
+}
+
+impl BindingsView {
+    pub(super) fn new(bus: EventBus) -> Self {
+        Self {
+            path: None,
+            error: None,
+            profile: None,
+            profiles: Vec::new(),
+            layer: 0,
+            bindings: Vec::new(),
+            bus,
+        }
+    }
+
+    fn bindings_request(&self, request: BindingsRequest) {
+        super::send(&self.bus, Event::BindingsRequest(request))
+    }
+
+    fn render_config(&mut self, ui: &mut Ui) {
+        ui.group(|ui| {
+            ui.horizontal(|ui| {
+                ui.heading("Bindings");
+                if ui.button("Reload").clicked() {
+                    self.bindings_request(BindingsRequest::Reload);
+                }
+            });
+
+            match &self.path {
+                Some(path) => ui.label(format!("Config file: {}", path.display())),
+                None => ui.label("No config file"),
+            };
+            if let Some(error) = &self.error {
+                ui.colored_label(ui.visuals().error_fg_color, error);
+            }
+
+            ui.horizontal(|ui| {
+                let mut profile = self.profile.clone();
+                ComboBox::from_label("Profile")
+                    .selected_text(profile.as_deref().unwrap_or("None"))
+                    .show_ui(ui, |ui| {
+                        ui.selectable_value(&mut profile, None, "None");
+                        self.profiles.iter().for_each(|name| {
+                            ui.selectable_value(&mut profile, Some(name.clone()), name);
+                        });
+                    });
+                if profile != self.profile {
+                    self.bindings_request(BindingsRequest::SetProfile(profile));
+                }
+
+                ui.label(format!("Layer {}", self.layer));
+            });
+        });
+    }
+
+    fn render_bindings(&self, ui: &mut Ui) {
+        ui.group(|ui| {
+            ui.heading("Active bindings");
+            Grid::new("active_bindings")
+                .num_columns(3)
+                .striped(true)
+                .show(ui, |ui| {
+                    ui.strong("Trigger");
+                    ui.strong("Action");
+                    ui.strong("From");
+                    ui.end_row();
+
+                    self.bindings.iter().for_each(|binding| {
+                        ui.label(binding.trigger.to_string());
+                        ui.label(binding.action.to_string());
+                        ui.label(binding.scope.to_string());
+                        ui.end_row();
+                    });
+                });
+        });
+    }
+}
+
+impl View for BindingsView {
+    fn init(&mut self) {
+        self.bindings_request(BindingsRequest::FetchBindings);
+    }
+
+    fn render(&mut self, ui: &mut Ui) {
+        ScrollArea::vertical().show(ui, |ui| {
+            self.render_config(ui);
+            ui.add_space(10f32);
+            self.render_bindings(ui);
+        });
+    }
+
+    fn process_event(&mut self, event: &Event) {
+        match event {
+            Event::BindingsResponse(BindingsResponse::FetchBindings {
+                path,
+                error,
+                profile,
+                profiles,
+                layer,
+                bindings,
+            }) => {
+                self.path = path.clone();
+                self.error = error.clone();
+                self.profile = profile.clone();
+                self.profiles = profiles.clone();
+                self.layer = *layer;
+                self.bindings = bindings.clone();
+            }
+            _ => {}
+        }
+    }
+}
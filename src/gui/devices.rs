@@ -0,0 +1,423 @@
+use crate::audio::{
+    AudioDeviceInfo, DeviceState, DeviceStateFilter, EndpointRole, EndpointSelector,
+};
+use crate::bus::EventBus;
+use crate::gui::View;
+use crate::{AudioRequest, AudioResponse, DeviceMatcher, Event, VolumeLimit};
+use eframe::egui::{Checkbox, ComboBox, Grid, Rgba, ScrollArea, Slider, TextEdit, Ui};
+
+pub(super) struct DevicesView {
+    devices: Vec<AudioDeviceInfo>,
+    state_filter: DeviceStateFilter,
+    outputs: Vec<AudioDeviceInfo>,
+    default_output: Option<String>,
+    preferred_outputs: Vec<String>,
+    output_endpoint: EndpointSelector,
+    input_endpoint: EndpointSelector,
+    active_outputs: Vec<AudioDeviceInfo>,
+    active_inputs: Vec<AudioDeviceInfo>,
+    volume_limits: Vec<VolumeLimit>,
+    applied_volume_limits: Vec<VolumeLimit>,
+    bus: EventBus,
+}
+
+impl DevicesView {
+    pub(super) fn new(bus: EventBus) -> Self {
+        Self {
+            devices: Vec::new(),
+            state_filter: DeviceStateFilter::Any,
+            outputs: Vec::new(),
+            default_output: None,
+            preferred_outputs: Vec::new(),
+            output_endpoint: EndpointSelector::Default(EndpointRole::Multimedia),
+            input_endpoint: EndpointSelector::Default(EndpointRole::Communications),
+            active_outputs: Vec::new(),
+            active_inputs: Vec::new(),
+            volume_limits: Vec::new(),
+            applied_volume_limits: Vec::new(),
+            bus,
+        }
+    }
+
+    fn audio_request(&self, request: AudioRequest) {
+        super::send(&self.bus, Event::AudioRequest(request))
+    }
+
+    fn device_name<'a>(&'a self, device_id: &'a str) -> &'a str {
+        self.outputs
+            .iter()
+            .find(|device| device.id == device_id)
+            .map_or(device_id, |device| device.name.as_str())
+    }
+
+    fn render_endpoints(&mut self, ui: &mut Ui) {
+        ui.group(|ui| {
+            ui.heading("Tracked endpoints");
+            ui.label("Devices whose volume and mute state are mirrored on the keyboard.");
+
+            let mut changed = false;
+            Grid::new("tracked_endpoints")
+                .num_columns(2)
+                .show(ui, |ui| {
+                    ui.label("Output");
+                    changed |= endpoint_selector_ui(
+                        ui,
+                        "output",
+                        &mut self.output_endpoint,
+                        &self.active_outputs,
+                    );
+                    ui.end_row();
+
+                    ui.label("Mic");
+                    changed |= endpoint_selector_ui(
+                        ui,
+                        "input",
+                        &mut self.input_endpoint,
+                        &self.active_inputs,
+                    );
+                    ui.end_row();
+                });
+
+            if changed {
+                self.audio_request(AudioRequest::SetEndpoints {
+                    output: self.output_endpoint.clone(),
+                    input: self.input_endpoint.clone(),
+                });
+            }
+        });
+    }
+
+    fn render_volume_limits(&mut self, ui: &mut Ui) {
+        ui.group(|ui| {
+            ui.heading("Volume limits");
+            ui.label("Outputs matching a rule are pulled back down whenever they go over its cap.");
+
+            let outputs = &self.active_outputs;
+            let mut removed = None;
+            self.volume_limits
+                .iter_mut()
+                .enumerate()
+                .for_each(|(index, limit)| {
+                    ui.horizontal(|ui| {
+                        let is_id = matches!(limit.matcher, DeviceMatcher::Id(_));
+                        ComboBox::from_id_salt(("volume_limit_kind", index))
+                            .selected_text(if is_id { "Device" } else { "Name pattern" })
+                            .show_ui(ui, |ui| {
+                                if ui.selectable_label(is_id, "Device").clicked() && !is_id {
+                                    limit.matcher = DeviceMatcher::Id(
+                                        outputs.first().map(|d| d.id.clone()).unwrap_or_default(),
+                                    );
+                                }
+                                if ui.selectable_label(!is_id, "Name pattern").clicked() && is_id {
+                                    limit.matcher = DeviceMatcher::NamePattern(String::new());
+                                }
+                            });
+
+                        match &mut limit.matcher {
+                            DeviceMatcher::Id(device_id) => {
+                                ComboBox::from_id_salt(("volume_limit_device", index))
+                                    .selected_text(
+                                        outputs
+                                            .iter()
+                                            .find(|device| device.id == *device_id)
+                                            .map_or("Unavailable device", |device| {
+                                                device.name.as_str()
+                                            }),
+                                    )
+                                    .show_ui(ui, |ui| {
+                                        outputs.iter().for_each(|device| {
+                                            ui.selectable_value(
+                                                device_id,
+                                                device.id.clone(),
+                                                &device.name,
+                                            );
+                                        });
+                                    });
+                            }
+                            DeviceMatcher::NamePattern(pattern) => {
+                                ui.add(
+                                    TextEdit::singleline(pattern)
+                                        .hint_text("e.g. headphones")
+                                        .desired_width(160f32),
+                                );
+                            }
+                        }
+
+                        ui.add(Slider::new(&mut limit.max_volume, 0..=100).suffix("%"));
+                        if ui.button("Remove").clicked() {
+                            removed = Some(index);
+                        }
+                    });
+                });
+
+            if let Some(index) = removed {
+                self.volume_limits.remove(index);
+            }
+
+            ui.horizontal(|ui| {
+                if ui.button("Add limit").clicked() {
+                    self.volume_limits.push(VolumeLimit {
+                        matcher: DeviceMatcher::NamePattern(String::new()),
+                        max_volume: 40,
+                    });
+                }
+
+                let modified = self.volume_limits != self.applied_volume_limits;
+                if ui
+                    .add_enabled(modified, |ui: &mut Ui| ui.button("Apply"))
+                    .clicked()
+                {
+                    self.audio_request(AudioRequest::SetVolumeLimits(self.volume_limits.clone()));
+                }
+            });
+        });
+    }
+
+    fn render_devices(&mut self, ui: &mut Ui) {
+        ui.group(|ui| {
+            ui.horizontal(|ui| {
+                ui.heading("Endpoints");
+
+                let previous_filter = self.state_filter;
+                ComboBox::from_id_salt("device_state_filter")
+                    .selected_text(self.state_filter.to_string())
+                    .show_ui(ui, |ui| {
+                        DeviceStateFilter::ALL.iter().for_each(|filter| {
+                            ui.selectable_value(
+                                &mut self.state_filter,
+                                *filter,
+                                filter.to_string(),
+                            );
+                        });
+                    });
+
+                if ui.button("Refresh").clicked() || previous_filter != self.state_filter {
+                    self.audio_request(AudioRequest::FetchDevices(self.state_filter));
+                }
+            });
+
+            Grid::new("audio_devices")
+                .num_columns(4)
+                .striped(true)
+                .show(ui, |ui| {
+                    ui.strong("Name");
+                    ui.strong("Direction");
+                    ui.strong("Form factor");
+                    ui.strong("State");
+                    ui.end_row();
+
+                    self.devices.iter().for_each(|device| {
+                        ui.label(&device.name).on_hover_text(&device.id);
+                        ui.label(
+                            device
+                                .data_flow
+                                .map_or("Unknown".to_string(), |flow| flow.to_string()),
+                        );
+                        ui.label(device.form_factor.to_string());
+                        match device.state {
+                            Some(DeviceState::Active) => ui.colored_label(
+                                Rgba::from_rgb(0f32, 255f32, 0f32),
+                                DeviceState::Active.to_string(),
+                            ),
+                            Some(state) => ui.label(state.to_string()),
+                            None => ui.label("Unknown"),
+                        };
+                        ui.end_row();
+                    });
+                });
+        });
+    }
+
+    fn render_outputs(&mut self, ui: &mut Ui) {
+        ui.group(|ui| {
+            ui.horizontal(|ui| {
+                ui.heading("Outputs");
+                if ui.button("Refresh").clicked() {
+                    self.audio_request(AudioRequest::FetchOutputDevices);
+                }
+            });
+
+            let mut default_output = None;
+            let mut preferred_outputs = self.preferred_outputs.clone();
+            self.outputs.iter().for_each(|device| {
+                ui.horizontal(|ui| {
+                    let is_default = self.default_output.as_ref() == Some(&device.id);
+                    let mut in_cycle = preferred_outputs.contains(&device.id);
+
+                    if ui.add(Checkbox::new(&mut in_cycle, "")).changed() {
+                        if in_cycle {
+                            preferred_outputs.push(device.id.clone());
+                        } else {
+                            preferred_outputs.retain(|device_id| *device_id != device.id);
+                        }
+                    }
+                    ui.label(&device.name);
+                    if is_default {
+                        ui.label("(default)");
+                    } else if ui.button("Set default").clicked() {
+                        default_output = Some(device.id.clone());
+                    }
+                });
+            });
+
+            if let Some(device_id) = default_output {
+                self.audio_request(AudioRequest::SetDefaultOutput(device_id));
+            }
+            if preferred_outputs != self.preferred_outputs {
+                self.audio_request(AudioRequest::SetPreferredOutputs(preferred_outputs));
+            }
+        });
+    }
+
+    fn render_cycle(&mut self, ui: &mut Ui) {
+        ui.group(|ui| {
+            ui.heading("Output cycle");
+            ui.label("Order the keyboard's output switch key steps through.");
+
+            let mut preferred_outputs = self.preferred_outputs.clone();
+            let count = preferred_outputs.len();
+            self.preferred_outputs
+                .iter()
+                .enumerate()
+                .for_each(|(index, device_id)| {
+                    ui.horizontal(|ui| {
+                        ui.label(format!("{}. {}", index + 1, self.device_name(device_id)));
+                        if ui
+                            .add_enabled(index > 0, |ui: &mut Ui| ui.button("Up"))
+                            .clicked()
+                        {
+                            preferred_outputs.swap(index, index - 1);
+                        }
+                        if ui
+                            .add_enabled(index + 1 < count, |ui: &mut Ui| ui.button("Down"))
+                            .clicked()
+                        {
+                            preferred_outputs.swap(index, index + 1);
+                        }
+                    });
+                });
+
+            if preferred_outputs != self.preferred_outputs {
+                self.audio_request(AudioRequest::SetPreferredOutputs(preferred_outputs));
+            }
+        });
+    }
+}
+
+impl View for DevicesView {
+    fn init(&mut self) {
+        self.audio_request(AudioRequest::FetchOutputDevices);
+        self.audio_request(AudioRequest::FetchDevices(self.state_filter));
+        self.audio_request(AudioRequest::FetchEndpoints);
+        self.audio_request(AudioRequest::FetchVolumeLimits);
+    }
+
+    fn render(&mut self, ui: &mut Ui) {
+        ScrollArea::vertical().show(ui, |ui| {
+            self.render_endpoints(ui);
+            ui.add_space(10f32);
+            self.render_outputs(ui);
+            ui.add_space(10f32);
+            self.render_cycle(ui);
+            ui.add_space(10f32);
+            self.render_volume_limits(ui);
+            ui.add_space(10f32);
+            self.render_devices(ui);
+        });
+    }
+
+    fn process_event(&mut self, event: &Event) {
+        match event {
+            Event::AudioResponse(AudioResponse::FetchEndpoints {
+                output,
+                input,
+                outputs,
+                inputs,
+            }) => {
+                self.output_endpoint = output.clone();
+                self.input_endpoint = input.clone();
+                self.active_outputs = outputs.clone();
+                self.active_inputs = inputs.clone();
+            }
+            Event::AudioResponse(AudioResponse::FetchVolumeLimits(limits)) => {
+                self.volume_limits = limits.clone();
+                self.applied_volume_limits = limits.clone();
+            }
+            Event::AudioResponse(AudioResponse::FetchDevices(devices)) => {
+                self.devices = devices.clone();
+            }
+            Event::AudioResponse(AudioResponse::FetchOutputDevices {
+                devices,
+                default,
+                preferred,
+            }) => {
+                self.outputs = devices.clone();
+                self.default_output = default.clone();
+                self.preferred_outputs = preferred.clone();
+            }
+            _ => {}
+        }
+    }
+}
+
+fn endpoint_selector_text(selector: &EndpointSelector, devices: &[AudioDeviceInfo]) -> String {
+    match selector {
+        EndpointSelector::Default(role) => format!("Default ({role})"),
+        EndpointSelector::Pinned { device_id, .. } => devices
+            .iter()
+            .find(|device| device.id == *device_id)
+            .map_or("Unavailable device".to_string(), |device| {
+                device.name.clone()
+            }),
+    }
+}
+
+/// Combo box picking either a default role or a pinned device, plus the fallback role for pinned
+/// devices. Returns whether the selection changed.
+fn endpoint_selector_ui(
+    ui: &mut Ui,
+    id: &str,
+    selector: &mut EndpointSelector,
+    devices: &[AudioDeviceInfo],
+) -> bool {
+    let mut changed = false;
+    let fallback = match selector {
+        EndpointSelector::Default(role) => *role,
+        EndpointSelector::Pinned { fallback, .. } => *fallback,
+    };
+
+    ui.horizontal(|ui| {
+        ComboBox::from_id_salt(("endpoint_selector", id))
+            .selected_text(endpoint_selector_text(selector, devices))
+            .show_ui(ui, |ui| {
+                EndpointRole::ALL.iter().for_each(|role| {
+                    let value = EndpointSelector::Default(*role);
+                    let text = endpoint_selector_text(&value, devices);
+                    changed |= ui.selectable_value(selector, value, text).changed();
+                });
+                ui.separator();
+                devices.iter().for_each(|device| {
+                    let value = EndpointSelector::Pinned {
+                        device_id: device.id.clone(),
+                        fallback,
+                    };
+                    changed |= ui.selectable_value(selector, value, &device.name).changed();
+                });
+            });
+
+        if let EndpointSelector::Pinned { fallback, .. } = selector {
+            ui.label("falls back to");
+            ComboBox::from_id_salt(("endpoint_fallback", id))
+                .selected_text(fallback.to_string())
+                .show_ui(ui, |ui| {
+                    EndpointRole::ALL.iter().for_each(|role| {
+                        changed |= ui
+                            .selectable_value(fallback, *role, role.to_string())
+                            .changed();
+                    });
+                });
+        }
+    });
+
+    changed
+}
@@ -1,30 +1,110 @@
+use crate::battery::BatteryLevel;
+use crate::bus::EventBus;
+use crate::config::LedMeterStyle;
 use crate::gui::View;
-use crate::record::{Record, RecordData};
-use crate::Event;
-use eframe::egui::{ProgressBar, Rgba, Slider, Ui};
-use std::sync::mpsc::Sender;
+use crate::history::{Entry, HistoryQuery, Kind, Sample};
+use crate::record::Record;
+use crate::state::{AppState, StateChange};
+use crate::{AudioRequest, AudioResponse, Event, InputMode, LedMeterMode};
+use chrono::Utc;
+use eframe::egui::{ComboBox, ProgressBar, Rgba, Slider, Ui};
+use egui_plot::{Line, Plot, PlotPoints};
+
+/// How far back the battery chart goes.
+const HISTORY_DAYS: i64 = 7;
 
 pub(super) struct KeyboardView {
-    bat_pc: u8,
+    state: AppState,
+    /// Battery percent by Unix time in seconds, oldest first.
+    battery_history: Vec<[f64; 2]>,
     set_bat_pc: u8,
-    led_meter_pc: u8,
-    muted: bool,
-    tx: Sender<Event>,
+    led_meter_mode: LedMeterMode,
+    level_rate_hz: u8,
+    privacy_mode: bool,
+    input_mode: InputMode,
+    release_delay_ms: u16,
+    battery_preview: LedMeterStyle,
+    /// Whether new battery readings are recorded, and so belong on the chart.
+    record_history: bool,
+    bus: EventBus,
 }
 impl KeyboardView {
-    pub(super) fn new(tx: Sender<Event>) -> Self {
+    pub(super) fn new(
+        bus: EventBus,
+        state: AppState,
+        battery_preview: LedMeterStyle,
+        record_history: bool,
+    ) -> Self {
         Self {
-            bat_pc: 0,
+            state,
+            battery_history: Vec::new(),
             set_bat_pc: 0,
-            led_meter_pc: 0,
-            muted: false,
-            tx,
+            led_meter_mode: LedMeterMode::Volume,
+            level_rate_hz: 20,
+            privacy_mode: false,
+            input_mode: InputMode::Toggle,
+            release_delay_ms: 200,
+            battery_preview,
+            record_history,
+            bus,
         }
     }
 }
 
+impl KeyboardView {
+    fn request_history(&self) {
+        let query = HistoryQuery {
+            since: Some(Utc::now() - chrono::Duration::days(HISTORY_DAYS)),
+            kind: Some(Kind::Battery),
+            ..Default::default()
+        };
+        super::send(&self.bus, Event::HistoryRequest(query));
+    }
+
+    fn load_history(&mut self, entries: &[Entry]) {
+        self.battery_history = entries
+            .iter()
+            .filter_map(|entry| match entry.sample {
+                Sample::Battery { percent, .. } => {
+                    Some([entry.time.timestamp() as f64, percent as f64])
+                }
+                _ => None,
+            })
+            .collect();
+    }
+
+    fn render_history(&self, ui: &mut Ui) {
+        // Hours relative to now keep the axis readable without a date formatter
+        let now = Utc::now().timestamp() as f64;
+        let points: PlotPoints = self
+            .battery_history
+            .iter()
+            .map(|[time, percent]| [(time - now) / 3600.0, *percent])
+            .collect();
+
+        Plot::new("battery_history")
+            .height(120.0)
+            .include_y(0.0)
+            .include_y(100.0)
+            .x_axis_label("Hours ago")
+            .allow_scroll(false)
+            .show(ui, |plot| plot.line(Line::new(points).name("Battery %")));
+    }
+}
+
 impl View for KeyboardView {
-    fn init(&mut self) {}
+    fn init(&mut self) {
+        self.request_history();
+        super::send(
+            &self.bus,
+            Event::AudioRequest(AudioRequest::FetchLedMeterMode),
+        );
+        super::send(
+            &self.bus,
+            Event::AudioRequest(AudioRequest::FetchPrivacyMode),
+        );
+        super::send(&self.bus, Event::AudioRequest(AudioRequest::FetchInputMode));
+    }
 
     fn render(&mut self, ui: &mut Ui) {
         ui.vertical(|ui| {
@@ -33,30 +113,125 @@ impl View for KeyboardView {
                 ui.horizontal(|ui| {
                     let name_label = ui.label("Battery Level:");
                     ui.colored_label(
-                        Rgba::from_rgb(0f32, 255f32, 0f32),
-                        format!("{}%", self.bat_pc),
+                        match self.state.battery_level {
+                            BatteryLevel::Normal => Rgba::from_rgb(0f32, 255f32, 0f32),
+                            BatteryLevel::Low => Rgba::from_rgb(255f32, 255f32, 0f32),
+                            BatteryLevel::Critical => Rgba::from_rgb(255f32, 0f32, 0f32),
+                        },
+                        match self.state.battery {
+                            Some(battery) => format!("{}%", battery.percent),
+                            None => "?".to_string(),
+                        },
                     )
                     .labelled_by(name_label.id);
                 });
                 ui.horizontal(|ui| {
                     let led_label = ui.label("Led meter:");
-                    ui.add(ProgressBar::new(self.led_meter_pc as f32 / 100f32))
-                        .labelled_by(led_label.id)
+                    ui.add(ProgressBar::new(
+                        self.state.led_meter.unwrap_or(0) as f32 / 100f32,
+                    ))
+                    .labelled_by(led_label.id)
+                });
+                ui.horizontal(|ui| {
+                    let rtt_label = ui.label("Round trip:");
+                    ui.label(match self.state.heartbeat {
+                        Some(stats) if stats.answered > 0 => format!(
+                            "{:.1} ms (min {:.1}, avg {:.1}, max {:.1}), {} missed",
+                            stats.last_rtt_ms,
+                            stats.min_rtt_ms,
+                            stats.average_rtt_ms,
+                            stats.max_rtt_ms,
+                            stats.missed
+                        ),
+                        Some(stats) => format!("no answer yet, {} missed", stats.missed),
+                        None => "not connected".to_string(),
+                    })
+                    .labelled_by(rtt_label.id);
+                });
+                ui.horizontal(|ui| {
+                    let queue_label = ui.label("Write queue:");
+                    ui.label(match self.state.write_queue {
+                        Some(stats) => format!(
+                            "{} waiting (peak {}), {} sent, {} coalesced, {} dropped",
+                            stats.depth,
+                            stats.peak_depth,
+                            stats.sent,
+                            stats.coalesced,
+                            stats.dropped
+                        ),
+                        None => "not connected".to_string(),
+                    })
+                    .labelled_by(queue_label.id);
                 });
+                ui.label(format!("Battery over the last {HISTORY_DAYS} days:"));
+                self.render_history(ui);
             });
             ui.add_space(10f32);
             ui.group(|ui| {
                 ui.heading("System State");
                 ui.horizontal(|ui| {
                     let name_label = ui.label("Mute:");
-                    let col = if self.muted {
+                    let muted = self.state.output_muted.unwrap_or(false);
+                    let col = if muted {
                         Rgba::from_rgb(255f32, 0f32, 0f32)
                     } else {
                         Rgba::from_rgb(0f32, 255f32, 0f32)
                     };
-                    ui.colored_label(col, format!("{}", self.muted))
+                    ui.colored_label(col, format!("{}", muted))
                         .labelled_by(name_label.id);
                 });
+                ui.horizontal(|ui| {
+                    let mode_label = ui.label("Mic key:");
+                    let mut changed = false;
+                    ComboBox::from_id_salt("input_mode")
+                        .selected_text(self.input_mode.to_string())
+                        .show_ui(ui, |ui| {
+                            [
+                                InputMode::Toggle,
+                                InputMode::PushToTalk,
+                                InputMode::PushToMute,
+                            ]
+                            .into_iter()
+                            .for_each(|mode| {
+                                changed |= ui
+                                    .selectable_value(&mut self.input_mode, mode, mode.to_string())
+                                    .changed();
+                            });
+                        })
+                        .response
+                        .labelled_by(mode_label.id);
+
+                    let delay = ui
+                        .add_enabled(
+                            self.input_mode != InputMode::Toggle,
+                            Slider::new(&mut self.release_delay_ms, 0..=2000).suffix(" ms"),
+                        )
+                        .on_hover_text("How long the mic stays in the held state after release");
+                    changed |= delay.drag_stopped() || (delay.changed() && !delay.dragged());
+
+                    if changed {
+                        super::send(
+                            &self.bus,
+                            Event::AudioRequest(AudioRequest::SetInputMode {
+                                mode: self.input_mode,
+                                release_delay_ms: self.release_delay_ms,
+                            }),
+                        );
+                    }
+                });
+                ui.horizontal(|ui| {
+                    let privacy = ui
+                        .checkbox(&mut self.privacy_mode, "Privacy mode")
+                        .on_hover_text(
+                            "Keep every microphone muted, including ones plugged in later",
+                        );
+                    if privacy.changed() {
+                        super::send(
+                            &self.bus,
+                            Event::AudioRequest(AudioRequest::SetPrivacyMode(self.privacy_mode)),
+                        );
+                    }
+                });
             });
             ui.add_space(10f32);
             ui.group(|ui| {
@@ -65,35 +240,93 @@ impl View for KeyboardView {
                     ui.add(Slider::new(&mut self.set_bat_pc, 0..=100));
                     let btn = ui.button("Illuminate battery %");
                     if btn.clicked() {
-                        self.tx
-                            .send(Event::RecordToDevice(Record::new(
+                        super::send(
+                            &self.bus,
+                            Event::RecordToDevice(Record::new(
                                 456,
-                                RecordData::SetLedMeter {
-                                    percent: self.set_bat_pc,
-                                    invert: false,
-                                    linger_time: 2000,
-                                    danger_threshold: 2,
-                                    warning_threshold: 7,
-                                },
-                            )))
-                            .expect("Failed to send Illuminate");
+                                self.battery_preview.meter(self.set_bat_pc),
+                            )),
+                        );
                     }
-                })
+                });
+                ui.horizontal(|ui| {
+                    let mode_label = ui.label("Led meter shows:");
+                    let mut changed = false;
+                    ComboBox::from_id_salt("led_meter_mode")
+                        .selected_text(self.led_meter_mode.to_string())
+                        .show_ui(ui, |ui| {
+                            [
+                                LedMeterMode::Volume,
+                                LedMeterMode::OutputLevel,
+                                LedMeterMode::InputLevel,
+                            ]
+                            .into_iter()
+                            .for_each(|mode| {
+                                changed |= ui
+                                    .selectable_value(
+                                        &mut self.led_meter_mode,
+                                        mode,
+                                        mode.to_string(),
+                                    )
+                                    .changed();
+                            });
+                        })
+                        .response
+                        .labelled_by(mode_label.id);
+
+                    let rate = ui.add_enabled(
+                        self.led_meter_mode != LedMeterMode::Volume,
+                        Slider::new(&mut self.level_rate_hz, 1..=30).suffix(" Hz"),
+                    );
+                    changed |= rate.drag_stopped() || (rate.changed() && !rate.dragged());
+
+                    if changed {
+                        super::send(
+                            &self.bus,
+                            Event::AudioRequest(AudioRequest::SetLedMeterMode {
+                                mode: self.led_meter_mode,
+                                rate_hz: self.level_rate_hz,
+                            }),
+                        );
+                    }
+                });
             });
         });
     }
 
     fn process_event(&mut self, event: &Event) {
         match event {
-            Event::RecordFromDevice(rec) => match rec.data {
-                RecordData::BatteryResponse { percent, .. } => self.bat_pc = percent,
-                _ => {}
-            },
-            Event::RecordToDevice(rec) => match rec.data {
-                RecordData::SetOutputMuteState(state) => self.muted = state,
-                RecordData::SetLedMeter { percent, .. } => self.led_meter_pc = percent,
-                _ => {}
-            },
+            Event::StateChanged(changes) => {
+                changes.iter().for_each(|change| {
+                    match change {
+                        // The chart shows what's recorded, which is nothing while history is off
+                        StateChange::Battery(battery) if self.record_history => self
+                            .battery_history
+                            .push([Utc::now().timestamp() as f64, battery.percent as f64]),
+                        _ => {}
+                    }
+                    self.state.apply(change);
+                });
+            }
+            Event::AudioResponse(AudioResponse::FetchInputMode {
+                mode,
+                release_delay_ms,
+            }) => {
+                self.input_mode = *mode;
+                self.release_delay_ms = *release_delay_ms;
+            }
+            Event::AudioResponse(AudioResponse::FetchPrivacyMode(enabled)) => {
+                self.privacy_mode = *enabled;
+            }
+            Event::AudioResponse(AudioResponse::FetchLedMeterMode { mode, rate_hz }) => {
+                self.led_meter_mode = *mode;
+                self.level_rate_hz = *rate_hz;
+            }
+            Event::HistoryResponse(entries) => self.load_history(entries),
+            Event::ConfigChanged(config) => {
+                self.battery_preview = config.led.battery_preview;
+                self.record_history = config.history.enabled;
+            }
             _ => {}
         }
     }
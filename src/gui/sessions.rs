@@ -0,0 +1,155 @@
+use crate::audio::AudioSessionInfo;
+use crate::bus::EventBus;
+use crate::gui::View;
+use crate::{AudioRequest, AudioResponse, Event, SessionTarget};
+use eframe::egui::{Checkbox, ComboBox, Slider, Ui};
+
+pub(super) struct SessionsView {
+    sessions: Vec<AudioSessionInfo>,
+    targets: Vec<SessionTarget>,
+    bus: EventBus,
+}
+
+impl SessionsView {
+    pub(super) fn new(bus: EventBus) -> Self {
+        Self {
+            sessions: Vec::new(),
+            targets: Vec::new(),
+            bus,
+        }
+    }
+
+    fn audio_request(&self, request: AudioRequest) {
+        super::send(&self.bus, Event::AudioRequest(request))
+    }
+
+    fn render_sessions(&mut self, ui: &mut Ui) {
+        ui.group(|ui| {
+            ui.horizontal(|ui| {
+                ui.heading("Sessions");
+                if ui.button("Refresh").clicked() {
+                    self.audio_request(AudioRequest::FetchSessions);
+                }
+            });
+
+            let mut requests = Vec::new();
+            self.sessions.iter_mut().for_each(|session| {
+                ui.horizontal(|ui| {
+                    ui.label(&session.display_name).on_hover_text(format!(
+                        "{} (PID {})",
+                        session.executable.as_deref().unwrap_or("unknown"),
+                        session.process_id
+                    ));
+
+                    let volume = ui.add(Slider::new(&mut session.volume, 0..=100).suffix("%"));
+                    if volume.drag_stopped() || (volume.changed() && !volume.dragged()) {
+                        requests.push(AudioRequest::SetSessionVolume {
+                            process_id: session.process_id,
+                            volume: session.volume,
+                        });
+                    }
+
+                    if ui.add(Checkbox::new(&mut session.muted, "Muted")).changed() {
+                        requests.push(AudioRequest::SetSessionMute {
+                            process_id: session.process_id,
+                            muted: session.muted,
+                        });
+                    }
+                });
+            });
+
+            requests.into_iter().for_each(|request| {
+                self.audio_request(request);
+            });
+        });
+    }
+
+    fn render_targets(&mut self, ui: &mut Ui) {
+        ui.group(|ui| {
+            ui.heading("Key targets");
+            ui.label("Slot numbers used by the keyboard's session mute and volume keys.");
+
+            let mut executables: Vec<&str> = self
+                .sessions
+                .iter()
+                .filter_map(|session| session.executable.as_deref())
+                .collect();
+            executables.sort_unstable();
+            executables.dedup();
+
+            let mut changed = false;
+            let mut removed = None;
+            self.targets
+                .iter_mut()
+                .enumerate()
+                .for_each(|(slot, target)| {
+                    ui.horizontal(|ui| {
+                        ui.label(format!("Slot {slot}"));
+                        ComboBox::from_id_salt(("session_target", slot))
+                            .selected_text(target.to_string())
+                            .show_ui(ui, |ui| {
+                                changed |= ui
+                                    .selectable_value(
+                                        target,
+                                        SessionTarget::ForegroundApp,
+                                        SessionTarget::ForegroundApp.to_string(),
+                                    )
+                                    .changed();
+                                executables.iter().for_each(|executable| {
+                                    changed |= ui
+                                        .selectable_value(
+                                            target,
+                                            SessionTarget::Executable(executable.to_string()),
+                                            *executable,
+                                        )
+                                        .changed();
+                                });
+                            });
+                        if ui.button("Remove").clicked() {
+                            removed = Some(slot);
+                        }
+                    });
+                });
+
+            if let Some(slot) = removed {
+                self.targets.remove(slot);
+                changed = true;
+            }
+            if ui.button("Add slot").clicked() {
+                self.targets.push(SessionTarget::ForegroundApp);
+                changed = true;
+            }
+
+            if changed {
+                self.audio_request(AudioRequest::SetSessionTargets(self.targets.clone()));
+            }
+        });
+    }
+}
+
+impl View for SessionsView {
+    fn init(&mut self) {
+        self.audio_request(AudioRequest::FetchSessions);
+        self.audio_request(AudioRequest::FetchSessionTargets);
+    }
+
+    fn render(&mut self, ui: &mut Ui) {
+        ui.vertical(|ui| {
+            self.render_sessions(ui);
+            ui.add_space(10f32);
+            self.render_targets(ui);
+        });
+    }
+
+    fn process_event(&mut self, event: &Event) {
+        match event {
+            Event::AudioResponse(AudioResponse::FetchSessions(sessions)) => {
+                self.sessions = sessions.clone();
+            }
+            Event::AudioResponse(AudioResponse::FetchSessionTargets(targets)) => {
+                self.targets = targets.clone();
+            }
+            _ => {}
+        }
+    }
+}
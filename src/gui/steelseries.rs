@@ -1,6 +1,6 @@
 use crate::{
-    gui::View, steelseries::api::sonar, steelseries::api::sonar::types::DeviceRole,
-    steelseries::api::sonar::types::RedirectionId,
+    bus::EventBus, gui::View, state::StateChange, steelseries::api::sonar,
+    steelseries::api::sonar::types::DeviceRole, steelseries::api::sonar::types::RedirectionId,
     steelseries::api::sonar::types::RedirectionVolumes, Event, SonarRequest, SonarResponse,
 };
 use eframe::egui;
@@ -8,8 +8,7 @@ use eframe::egui::ComboBox;
 use std::collections::hash_map::Values;
 use std::collections::HashMap;
 use std::iter::Filter;
-use tokio::sync::mpsc::error::SendError;
-use tokio::sync::mpsc::UnboundedSender;
+use tracing::debug;
 
 pub type AudioDevice = sonar::types::AudioDevice;
 pub type ClassicRedirection = sonar::types::ClassicRedirection;
@@ -68,17 +67,17 @@ pub(super) struct SonarView {
     audio_devices: AudioDevices,
     virtual_audio_devices: Vec<AudioDevice>,
     redirections: Vec<ClassicRedirection>,
-    channel_tx: UnboundedSender<Event>,
+    bus: EventBus,
     vad_volume: HashMap<DeviceRole, RedirectionVolumes>,
     sonar_url: String,
 }
 
 impl SonarView {
-    pub(crate) fn new(channel_tx: UnboundedSender<Event>) -> Self {
+    pub(crate) fn new(bus: EventBus, redirections: Vec<ClassicRedirection>) -> Self {
         Self {
             audio_devices: AudioDevices::new(),
-            redirections: Vec::new(),
-            channel_tx,
+            redirections,
+            bus,
             vad_volume: HashMap::new(),
             sonar_url: String::new(),
             virtual_audio_devices: Vec::new(),
@@ -91,29 +90,27 @@ impl SonarView {
         self.sonar_request(SonarRequest::RedirectDevice {
             redirection: redirection.clone(),
             device: device.clone(),
-        })
-        .expect("failed to redirect device");
+        });
     }
 
-    fn fetch_volume(&mut self) -> Result<(), SendError<Event>> {
+    fn fetch_volume(&mut self) {
         self.sonar_request(SonarRequest::FetchDeviceVolume)
     }
 
-    fn sonar_request(&mut self, request: SonarRequest) -> Result<(), SendError<Event>> {
+    fn sonar_request(&mut self, request: SonarRequest) {
         self.send_event(Event::SonarRequest(request))
     }
 
-    fn send_event(&mut self, event: Event) -> Result<(), SendError<Event>> {
-        self.channel_tx.send(event)
+    fn send_event(&mut self, event: Event) {
+        super::send(&self.bus, event)
     }
 }
 impl View for SonarView {
     fn init(&mut self) {
         self.sonar_request(SonarRequest::FetchDevices);
         self.sonar_request(SonarRequest::FetchClassicRedirections);
-        self.fetch_volume().expect("Failed to fetch volume");
-        self.sonar_request(SonarRequest::GetSonarUrl)
-            .expect("Failed to get SonarUrl");
+        self.fetch_volume();
+        self.sonar_request(SonarRequest::GetSonarUrl);
     }
 
     fn render(&mut self, ui: &mut egui::Ui) {
@@ -199,7 +196,7 @@ impl View for SonarView {
         redirections.into_iter().for_each(|(id, device)| {
             if let Some(redirection_id) = id {
                 if let Some(device_id) = device {
-                    println!("{:?}", self.redirect_device(&redirection_id, &device_id));
+                    debug!("{:?}", self.redirect_device(&redirection_id, &device_id));
                 }
             }
         });
@@ -209,14 +206,18 @@ impl View for SonarView {
         match event {
             Event::SonarResponse(SonarResponse::FetchDevices(devices)) => {
                 self.audio_devices = devices.into();
-                println!("Got sonar device list: {:?}", self.audio_devices);
+                debug!("Got sonar device list: {:?}", self.audio_devices);
             }
-            Event::SonarResponse(SonarResponse::FetchClassicRedirections(redirections)) => {
-                self.redirections = redirections.clone();
-                println!("Got classic redirections: {:?}", self.redirections);
+            Event::StateChanged(changes) => {
+                changes.iter().for_each(|change| {
+                    if let StateChange::SonarRedirections(redirections) = change {
+                        self.redirections = redirections.clone();
+                        debug!("Got classic redirections: {:?}", self.redirections);
+                    }
+                });
             }
             Event::SonarResponse(SonarResponse::FetchDeviceVolume(response)) => {
-                println!("Got sonar device volume: {:?}", response);
+                debug!("Got sonar device volume: {:?}", response);
                 let devices = response.clone().devices.unwrap();
                 self.vad_volume = devices
                     .into_iter()
@@ -0,0 +1,99 @@
+use crate::config::DeviceConfig;
+use serde::Serialize;
+use std::time::{Duration, Instant};
+
+/// Round trips of the pings answered so far on this connection.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Default)]
+pub(crate) struct HeartbeatStats {
+    pub(crate) last_rtt_ms: f32,
+    pub(crate) min_rtt_ms: f32,
+    pub(crate) max_rtt_ms: f32,
+    pub(crate) average_rtt_ms: f32,
+    pub(crate) answered: u32,
+    /// Pings that timed out, over the whole connection.
+    pub(crate) missed: u32,
+}
+
+/// What [`Heartbeat::poll`] wants done.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Beat {
+    Idle,
+    SendPing,
+    /// `device.heartbeat_misses` pings in a row went unanswered.
+    Lost {
+        missed: u32,
+    },
+}
+
+/// Pings the keyboard every `device.heartbeat_interval_ms` and notices when it stops answering,
+/// which an open USB endpoint alone doesn't tell.
+pub(crate) struct Heartbeat {
+    last_ping: Instant,
+    /// When the unanswered ping went out.
+    outstanding: Option<Instant>,
+    missed_in_row: u32,
+    greeted: bool,
+    stats: HeartbeatStats,
+}
+
+impl Heartbeat {
+    pub(crate) fn new() -> Self {
+        Self {
+            last_ping: Instant::now(),
+            outstanding: None,
+            missed_in_row: 0,
+            greeted: false,
+            stats: HeartbeatStats::default(),
+        }
+    }
+
+    pub(crate) fn sent(&mut self) {
+        self.last_ping = Instant::now();
+        self.outstanding = Some(self.last_ping);
+    }
+
+    pub(crate) fn poll(&mut self, config: &DeviceConfig) -> Beat {
+        if let Some(sent) = self.outstanding {
+            if sent.elapsed() < Duration::from_millis(config.heartbeat_timeout_ms) {
+                return Beat::Idle;
+            }
+
+            self.outstanding = None;
+            self.missed_in_row += 1;
+            self.stats.missed += 1;
+            if self.missed_in_row >= config.heartbeat_misses {
+                return Beat::Lost {
+                    missed: self.missed_in_row,
+                };
+            }
+        }
+
+        match self.last_ping.elapsed() >= Duration::from_millis(config.heartbeat_interval_ms) {
+            true => Beat::SendPing,
+            false => Beat::Idle,
+        }
+    }
+
+    /// Takes in a `Pong` and returns the updated stats, or `None` for a late one. The first
+    /// answer on a connection also reports `true`, to bring the keyboard up to date.
+    pub(crate) fn pong(&mut self) -> (bool, Option<HeartbeatStats>) {
+        let first = !std::mem::replace(&mut self.greeted, true);
+        let Some(sent) = self.outstanding.take() else {
+            return (first, None);
+        };
+
+        let rtt_ms = sent.elapsed().as_secs_f32() * 1000.0;
+        let stats = &mut self.stats;
+        stats.answered += 1;
+        stats.last_rtt_ms = rtt_ms;
+        stats.max_rtt_ms = stats.max_rtt_ms.max(rtt_ms);
+        stats.min_rtt_ms = match stats.answered {
+            1 => rtt_ms,
+            _ => stats.min_rtt_ms.min(rtt_ms),
+        };
+        stats.average_rtt_ms += (rtt_ms - stats.average_rtt_ms) / stats.answered as f32;
+        self.missed_in_row = 0;
+
+        (first, Some(self.stats))
+    }
+}
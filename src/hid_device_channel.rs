@@ -1,12 +1,91 @@
-use crate::record::Record;
+use crate::config::DeviceConfig;
+use crate::record::{Record, RecordData};
+use crate::simulated_device::SimulatedDevice;
 use bincode::config::legacy;
 use bincode::error::{DecodeError, EncodeError};
 use bincode::{decode_from_slice, encode_to_vec};
 use hidapi::{HidApi, HidDevice, HidError};
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+use tracing::trace;
 
 pub struct HidDeviceChannel {
-    api: HidApi,
-    device: HidDevice,
+    api: Option<HidApi>,
+    device: Box<dyn HidTransport>,
+}
+
+/// Raw report I/O underneath a [`HidDeviceChannel`].
+pub(crate) trait HidTransport {
+    fn read_timeout(&self, buf: &mut [u8], timeout: i32) -> Result<usize, HidError>;
+    fn write(&self, data: &[u8]) -> Result<usize, HidError>;
+}
+
+impl HidTransport for HidDevice {
+    fn read_timeout(&self, buf: &mut [u8], timeout: i32) -> Result<usize, HidError> {
+        HidDevice::read_timeout(self, buf, timeout)
+    }
+
+    fn write(&self, data: &[u8]) -> Result<usize, HidError> {
+        HidDevice::write(self, data)
+    }
+}
+
+/// Serves queued reports and keeps everything written, for tests. Clones share both queues.
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub(crate) struct FakeTransport {
+    pub(crate) reads: std::rc::Rc<std::cell::RefCell<VecDeque<Result<Vec<u8>, HidError>>>>,
+    pub(crate) writes: std::rc::Rc<std::cell::RefCell<Vec<Vec<u8>>>>,
+}
+
+#[cfg(test)]
+impl FakeTransport {
+    /// The records written so far, without the report ID.
+    pub(crate) fn written_records(&self) -> Vec<Record> {
+        self.writes
+            .borrow()
+            .iter()
+            .map(|report| decode_from_slice(&report[1..], legacy()).unwrap().0)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+impl HidTransport for FakeTransport {
+    /// Nothing queued reads like a timeout.
+    fn read_timeout(&self, buf: &mut [u8], _timeout: i32) -> Result<usize, HidError> {
+        match self.reads.borrow_mut().pop_front() {
+            Some(Ok(report)) => {
+                let size = report.len().min(buf.len());
+                buf[..size].copy_from_slice(&report[..size]);
+                Ok(size)
+            }
+            Some(Err(e)) => Err(e),
+            None => Ok(0),
+        }
+    }
+
+    fn write(&self, data: &[u8]) -> Result<usize, HidError> {
+        self.writes.borrow_mut().push(data.to_vec());
+        Ok(data.len())
+    }
+}
+
+/// One HID interface of the keyboard, as reported by the OS.
+#[derive(Debug, Serialize)]
+pub(crate) struct HidInterface {
+    pub(crate) path: String,
+    pub(crate) vendor_id: u16,
+    pub(crate) product_id: u16,
+    pub(crate) usage_page: u16,
+    pub(crate) usage: u16,
+    pub(crate) interface_number: i32,
+    pub(crate) manufacturer: Option<String>,
+    pub(crate) product: Option<String>,
+    pub(crate) serial_number: Option<String>,
+    /// Whether this is the interface records are exchanged over.
+    pub(crate) selected: bool,
 }
 
 #[derive(Debug)]
@@ -20,7 +99,11 @@ pub(crate) type WriteResult = Result<usize, WriteError>;
 #[derive(Debug)]
 pub enum ReadError {
     Hid(HidError),
-    Decode(DecodeError),
+    /// A report that doesn't decode, with its raw bytes.
+    Decode {
+        error: DecodeError,
+        bytes: Vec<u8>,
+    },
 }
 
 type ReadResult = Result<Option<Record>, ReadError>;
@@ -48,7 +131,47 @@ impl HidDeviceChannel {
             .ok_or_else(|| HidError::InitializationError)?
             .open_device(&api)?;
 
-        Ok(Self { api, device })
+        Ok(Self {
+            api: Some(api),
+            device: Box::new(device),
+        })
+    }
+
+    /// Every interface with the given vendor and product ID.
+    pub(crate) fn list(
+        vendor_id: u16,
+        product_id: u16,
+        usage_page: u16,
+        usage: u16,
+    ) -> Result<Vec<HidInterface>, HidError> {
+        let api = HidApi::new()?;
+        let interfaces = api
+            .device_list()
+            .filter(|x| x.vendor_id() == vendor_id && x.product_id() == product_id)
+            .map(|x| HidInterface {
+                path: x.path().to_string_lossy().into_owned(),
+                vendor_id: x.vendor_id(),
+                product_id: x.product_id(),
+                usage_page: x.usage_page(),
+                usage: x.usage(),
+                interface_number: x.interface_number(),
+                manufacturer: x.manufacturer_string().map(str::to_string),
+                product: x.product_string().map(str::to_string),
+                serial_number: x.serial_number().map(str::to_string),
+                selected: x.usage_page() == usage_page && x.usage() == usage,
+            })
+            .collect();
+
+        Ok(interfaces)
+    }
+
+    /// A channel to [`SimulatedDevice`] instead of real hardware.
+    pub(crate) fn simulated() -> Self {
+        Self::from_transport(Box::new(SimulatedDevice::new()))
+    }
+
+    pub(crate) fn from_transport(device: Box<dyn HidTransport>) -> Self {
+        Self { api: None, device }
     }
 }
 
@@ -67,16 +190,35 @@ impl HidDeviceChannel {
             return Ok(None);
         }
 
-        println!("Received Raw bytes: {:?}", data);
+        trace!("Received Raw bytes: {:?}", data);
 
-        decode_from_slice(&data, legacy())
-            .map(|result| Some(result.0))
-            .map_err(|e| ReadError::Decode(e))
+        // Tags past the known variants are kept whole instead of failing to decode
+        let serial = u32::from_le_bytes(data[..4].try_into().unwrap());
+        let tag = u32::from_le_bytes(data[4..8].try_into().unwrap());
+        if tag >= RecordData::first_unknown_tag() {
+            let bytes = data[8..].try_into().unwrap();
+            return Ok(Some(Record::new(
+                serial,
+                RecordData::Unknown { tag, bytes },
+            )));
+        }
+
+        match decode_from_slice(&data, legacy()) {
+            Ok((record, _)) => Ok(Some(record)),
+            Err(error) => Err(ReadError::Decode { error, bytes: data }),
+        }
     }
     pub(crate) fn write_record(&self, record: Record) -> WriteResult {
-        println!("Record to write: {:?}", record);
+        trace!("Record to write: {:?}", record);
 
-        match encode_to_vec(record, legacy()) {
+        let encoded = match record.data {
+            RecordData::Unknown { tag, bytes } => {
+                Ok([&record.serial.to_le_bytes()[..], &tag.to_le_bytes(), &bytes].concat())
+            }
+            _ => encode_to_vec(record, legacy()),
+        };
+
+        match encoded {
             Ok(mut encoded_data) => {
                 encoded_data.resize(32, 0);
                 assert_eq!(
@@ -88,7 +230,7 @@ impl HidDeviceChannel {
                     encoded_data
                 );
 
-                println!("Data to write: {:?}", encoded_data);
+                trace!("Data to write: {:?}", encoded_data);
 
                 // Prepend the record ID; required
                 encoded_data.insert(0, 0);
@@ -101,3 +243,89 @@ impl HidDeviceChannel {
         }
     }
 }
+
+/// Counts reports that failed to decode. A few are shrugged off, but `device.decode_error_limit`
+/// of them within `device.decode_error_window_s` means the link is garbled.
+pub(crate) struct DecodeErrors {
+    recent: VecDeque<Instant>,
+    total: u32,
+}
+
+impl DecodeErrors {
+    pub(crate) fn new() -> Self {
+        Self {
+            recent: VecDeque::new(),
+            total: 0,
+        }
+    }
+
+    /// Counts one error and returns whether the limit is reached.
+    pub(crate) fn exceeded(&mut self, config: &DeviceConfig) -> bool {
+        let window = Duration::from_secs(config.decode_error_window_s);
+        self.recent.retain(|at| at.elapsed() < window);
+        self.recent.push_back(Instant::now());
+        self.total += 1;
+
+        self.recent.len() >= config.decode_error_limit as usize
+    }
+
+    pub(crate) fn total(&self) -> u32 {
+        self.total
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    #[test]
+    fn random_reports_decode_or_round_trip() {
+        let mut rng = StdRng::seed_from_u64(0x3434_0661);
+        let transport = FakeTransport::default();
+        let channel = HidDeviceChannel::from_transport(Box::new(transport.clone()));
+        let first_unknown = RecordData::first_unknown_tag();
+
+        for _ in 0..10_000 {
+            let mut report = [0u8; 32];
+            rng.fill(&mut report[..]);
+            // Random tags are almost all unknown, so aim half of them at the known ones
+            if rng.gen_bool(0.5) {
+                let tag: u32 = rng.gen_range(0..first_unknown);
+                report[4..8].copy_from_slice(&tag.to_le_bytes());
+            }
+            let serial = u32::from_le_bytes(report[..4].try_into().unwrap());
+            let tag = u32::from_le_bytes(report[4..8].try_into().unwrap());
+            transport.reads.borrow_mut().push_back(Ok(report.to_vec()));
+
+            match channel.read_record(None) {
+                Ok(Some(record)) if tag >= first_unknown => {
+                    assert_eq!(
+                        record,
+                        Record::new(
+                            serial,
+                            RecordData::Unknown {
+                                tag,
+                                bytes: report[8..].try_into().unwrap(),
+                            }
+                        )
+                    );
+
+                    channel.write_record(record).unwrap();
+                    let written = transport.writes.borrow_mut().pop().unwrap();
+                    assert_eq!(written[1..], report, "{report:?}");
+                }
+                Ok(Some(record)) => {
+                    assert_eq!(record.serial, serial);
+                    assert!(!matches!(record.data, RecordData::Unknown { .. }));
+                }
+                Err(ReadError::Decode { bytes, .. }) => {
+                    assert!(tag < first_unknown, "{report:?}");
+                    assert_eq!(bytes, report);
+                }
+                other => panic!("{report:?} read as {other:?}"),
+            }
+        }
+    }
+}
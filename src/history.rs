@@ -0,0 +1,483 @@
+use crate::bus::{EventBus, Topic};
+use crate::config::HistoryConfig;
+use crate::state::StateChange;
+use crate::Event;
+use chrono::{DateTime, Utc};
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
+use std::io::Write;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use tracing::{debug, error, info_span, warn};
+
+/// What happened at one point in time.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub(crate) enum Sample {
+    Battery { percent: u8, voltage: u16 },
+    Connected,
+    Disconnected,
+    OutputMuted { muted: bool },
+    InputMuted { muted: bool },
+}
+
+impl Sample {
+    /// The change worth keeping, if any. Only changes are stored, so a flat line means the
+    /// value held.
+    fn from_change(change: &StateChange) -> Option<Self> {
+        match change {
+            StateChange::Connected(true) => Some(Sample::Connected),
+            StateChange::Connected(false) => Some(Sample::Disconnected),
+            StateChange::Battery(battery) => Some(Sample::Battery {
+                percent: battery.percent,
+                voltage: battery.voltage,
+            }),
+            StateChange::OutputMuted(muted) => Some(Sample::OutputMuted { muted: *muted }),
+            StateChange::InputMuted(muted) => Some(Sample::InputMuted { muted: *muted }),
+            _ => None,
+        }
+    }
+
+    /// Column values as `(name, value, voltage)`.
+    fn columns(&self) -> (&'static str, Option<i64>, Option<i64>) {
+        match self {
+            Sample::Battery { percent, voltage } => {
+                ("battery", Some(*percent as i64), Some(*voltage as i64))
+            }
+            Sample::Connected => ("connected", None, None),
+            Sample::Disconnected => ("disconnected", None, None),
+            Sample::OutputMuted { muted } => ("output_muted", Some(*muted as i64), None),
+            Sample::InputMuted { muted } => ("input_muted", Some(*muted as i64), None),
+        }
+    }
+
+    fn from_columns(name: &str, value: Option<i64>, voltage: Option<i64>) -> Option<Self> {
+        let sample = match name {
+            "battery" => Sample::Battery {
+                percent: value?.try_into().ok()?,
+                voltage: voltage?.try_into().ok()?,
+            },
+            "connected" => Sample::Connected,
+            "disconnected" => Sample::Disconnected,
+            "output_muted" => Sample::OutputMuted { muted: value? != 0 },
+            "input_muted" => Sample::InputMuted { muted: value? != 0 },
+            _ => return None,
+        };
+
+        Some(sample)
+    }
+}
+
+/// Groups of samples a query can be narrowed to.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Kind {
+    Battery,
+    Connection,
+    Mute,
+}
+
+impl FromStr for Kind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "battery" => Ok(Kind::Battery),
+            "connection" => Ok(Kind::Connection),
+            "mute" => Ok(Kind::Mute),
+            _ => Err(format!("Unknown history kind '{s}'")),
+        }
+    }
+}
+
+impl Kind {
+    fn names(&self) -> &'static [&'static str] {
+        match self {
+            Kind::Battery => &["battery"],
+            Kind::Connection => &["connected", "disconnected"],
+            Kind::Mute => &["output_muted", "input_muted"],
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub(crate) struct Entry {
+    pub(crate) time: DateTime<Utc>,
+    #[serde(flatten)]
+    pub(crate) sample: Sample,
+}
+
+impl Display for Entry {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let time = self.time.format("%Y-%m-%d %H:%M:%S");
+        match self.sample {
+            Sample::Battery { percent, voltage } => {
+                write!(f, "{time} battery {percent}% ({voltage} mV)")
+            }
+            Sample::Connected => write!(f, "{time} connected"),
+            Sample::Disconnected => write!(f, "{time} disconnected"),
+            Sample::OutputMuted { muted } => write!(f, "{time} output muted: {muted}"),
+            Sample::InputMuted { muted } => write!(f, "{time} input muted: {muted}"),
+        }
+    }
+}
+
+/// Writes `entries` as CSV with one column per value, left empty where it doesn't apply.
+pub(crate) fn write_csv(entries: &[Entry], writer: impl Write) -> csv::Result<()> {
+    let mut writer = csv::Writer::from_writer(writer);
+    writer.write_record(["time", "kind", "percent", "voltage", "muted"])?;
+    entries.iter().try_for_each(|entry| {
+        let (kind, value, voltage) = entry.sample.columns();
+        let (percent, muted) = match entry.sample {
+            Sample::Battery { .. } => (value, None),
+            _ => (None, value.map(|value| value != 0)),
+        };
+        let cell = |value: Option<String>| value.unwrap_or_default();
+
+        writer.write_record([
+            entry.time.to_rfc3339(),
+            kind.to_string(),
+            cell(percent.map(|percent| percent.to_string())),
+            cell(voltage.map(|voltage| voltage.to_string())),
+            cell(muted.map(|muted| muted.to_string())),
+        ])
+    })?;
+
+    writer.flush()?;
+    Ok(())
+}
+
+/// Which entries to read, oldest first.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub(crate) struct HistoryQuery {
+    pub(crate) since: Option<DateTime<Utc>>,
+    pub(crate) until: Option<DateTime<Utc>>,
+    pub(crate) kind: Option<Kind>,
+    /// Keeps the newest entries when there are more.
+    pub(crate) limit: Option<u32>,
+}
+
+/// Timestamped battery readings, connection changes and mute toggles, kept in an SQLite file in
+/// the data directory. Every user opens its own connection; SQLite sorts out the locking.
+pub(crate) struct History {
+    connection: Connection,
+}
+
+impl History {
+    const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+    pub(crate) fn path() -> Option<PathBuf> {
+        dirs::data_local_dir().map(|dir| dir.join("kbd-companion").join("history.sqlite3"))
+    }
+
+    pub(crate) fn open() -> Result<Self, String> {
+        let path = Self::path().ok_or("No data directory on this system")?;
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)
+                .map_err(|e| format!("Failed to create {}: {e}", dir.display()))?;
+        }
+
+        Connection::open(&path)
+            .and_then(Self::from_connection)
+            .map_err(|e| format!("Failed to open {}: {e}", path.display()))
+    }
+
+    /// Sets up the table on `connection` if it isn't there yet.
+    fn from_connection(connection: Connection) -> rusqlite::Result<Self> {
+        connection.busy_timeout(Duration::from_secs(2))?;
+        connection.pragma_update(None, "journal_mode", "WAL")?;
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS history (
+                time_ms INTEGER NOT NULL,
+                kind TEXT NOT NULL,
+                value INTEGER,
+                voltage INTEGER
+            );
+            CREATE INDEX IF NOT EXISTS history_time ON history (time_ms);",
+        )?;
+
+        Ok(Self { connection })
+    }
+
+    pub(crate) fn record(&self, time: DateTime<Utc>, sample: &Sample) -> rusqlite::Result<()> {
+        let (kind, value, voltage) = sample.columns();
+        self.connection.execute(
+            "INSERT INTO history (time_ms, kind, value, voltage) VALUES (?1, ?2, ?3, ?4)",
+            params![time.timestamp_millis(), kind, value, voltage],
+        )?;
+
+        Ok(())
+    }
+
+    pub(crate) fn query(&self, query: &HistoryQuery) -> rusqlite::Result<Vec<Entry>> {
+        let names = query.kind.map(|kind| kind.names().join(","));
+        let mut statement = self.connection.prepare(
+            "SELECT time_ms, kind, value, voltage FROM (
+                SELECT rowid, * FROM history
+                WHERE time_ms >= ?1 AND time_ms <= ?2
+                    AND (?3 IS NULL OR instr(',' || ?3 || ',', ',' || kind || ',') > 0)
+                ORDER BY time_ms DESC, rowid DESC
+                LIMIT ?4
+            ) ORDER BY time_ms, rowid",
+        )?;
+
+        let rows = statement.query_map(
+            params![
+                query
+                    .since
+                    .map_or(i64::MIN, |since| since.timestamp_millis()),
+                query
+                    .until
+                    .map_or(i64::MAX, |until| until.timestamp_millis()),
+                names,
+                query.limit.map_or(-1, i64::from),
+            ],
+            Self::entry,
+        )?;
+
+        // Rows written by a newer version are skipped rather than failing the whole query
+        rows.filter_map(Result::transpose).collect()
+    }
+
+    fn entry(row: &Row) -> rusqlite::Result<Option<Entry>> {
+        let time = DateTime::from_timestamp_millis(row.get(0)?);
+        let name: String = row.get(1)?;
+        let sample = Sample::from_columns(&name, row.get(2)?, row.get(3)?);
+
+        Ok(time
+            .zip(sample)
+            .map(|(time, sample)| Entry { time, sample }))
+    }
+
+    /// Applies the retention limits and returns how many entries went.
+    pub(crate) fn prune(&self, config: &HistoryConfig) -> rusqlite::Result<usize> {
+        let cutoff = Utc::now() - chrono::Duration::days(config.retention_days as i64);
+        let mut removed = self.connection.execute(
+            "DELETE FROM history WHERE time_ms < ?1",
+            params![cutoff.timestamp_millis()],
+        )?;
+
+        let oldest_kept: Option<i64> = self
+            .connection
+            .query_row(
+                "SELECT time_ms FROM history ORDER BY time_ms DESC LIMIT 1 OFFSET ?1",
+                params![config.max_entries.saturating_sub(1)],
+                |row| row.get(0),
+            )
+            .optional()?;
+        if let Some(oldest_kept) = oldest_kept {
+            removed += self.connection.execute(
+                "DELETE FROM history WHERE time_ms < ?1",
+                params![oldest_kept],
+            )?;
+        }
+
+        Ok(removed)
+    }
+
+    /// Writes state changes to the history on a thread of its own, pruning every hour. The
+    /// thread also answers [`Event::HistoryRequest`]s, so the window never waits on SQLite.
+    pub(crate) fn start_recording(bus: &EventBus, config: HistoryConfig) {
+        let events = bus.subscribe(
+            "history",
+            &[Topic::State, Topic::Config, Topic::HistoryRequest],
+        );
+        let bus = bus.clone();
+
+        std::thread::spawn(move || {
+            let _span = info_span!("history").entered();
+            let history = match History::open() {
+                Ok(history) => history,
+                Err(e) => {
+                    error!("History is off: {e}");
+                    return;
+                }
+            };
+
+            let mut config = config;
+            let mut last_prune: Option<Instant> = None;
+            loop {
+                if config.enabled
+                    && last_prune
+                        .is_none_or(|last_prune| last_prune.elapsed() >= Self::PRUNE_INTERVAL)
+                {
+                    match history.prune(&config) {
+                        Ok(removed) => debug!(removed, "Pruned history"),
+                        Err(e) => warn!("Failed to prune history: {e}"),
+                    }
+                    last_prune = Some(Instant::now());
+                }
+
+                let Some(event) = events.recv_timeout(Self::PRUNE_INTERVAL) else {
+                    continue;
+                };
+                match &*event {
+                    Event::ConfigChanged(new) => config = new.history.clone(),
+                    Event::HistoryRequest(query) => match history.query(query) {
+                        Ok(entries) => bus.publish(Event::HistoryResponse(entries)),
+                        Err(e) => warn!("Failed to query history: {e}"),
+                    },
+                    Event::StateChanged(changes) if config.enabled => {
+                        let now = Utc::now();
+                        changes
+                            .iter()
+                            .filter_map(Sample::from_change)
+                            .for_each(|sample| {
+                                if let Err(e) = history.record(now, &sample) {
+                                    warn!("Failed to record {sample:?}: {e}");
+                                }
+                            });
+                    }
+                    _ => {}
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn history() -> History {
+        History::from_connection(Connection::open_in_memory().unwrap()).unwrap()
+    }
+
+    fn hours_ago(hours: i64) -> DateTime<Utc> {
+        let now = DateTime::from_timestamp_millis(Utc::now().timestamp_millis()).unwrap();
+        now - chrono::Duration::hours(hours)
+    }
+
+    fn battery(percent: u8) -> Sample {
+        Sample::Battery {
+            percent,
+            voltage: 3300 + percent as u16 * 9,
+        }
+    }
+
+    /// One of each kind, an hour apart, oldest first.
+    fn recorded() -> (History, Vec<Entry>) {
+        let history = history();
+        let entries: Vec<Entry> = [
+            battery(80),
+            Sample::Connected,
+            Sample::OutputMuted { muted: true },
+            battery(79),
+            Sample::InputMuted { muted: false },
+        ]
+        .into_iter()
+        .enumerate()
+        .map(|(index, sample)| Entry {
+            time: hours_ago(5 - index as i64),
+            sample,
+        })
+        .collect();
+        entries
+            .iter()
+            .for_each(|entry| history.record(entry.time, &entry.sample).unwrap());
+
+        (history, entries)
+    }
+
+    #[test]
+    fn query_filters_by_kind_time_and_limit() {
+        let (history, entries) = recorded();
+        let query = |query: HistoryQuery| history.query(&query).unwrap();
+
+        assert_eq!(query(HistoryQuery::default()), entries);
+        assert_eq!(
+            query(HistoryQuery {
+                kind: Some(Kind::Mute),
+                ..Default::default()
+            }),
+            [entries[2].clone(), entries[4].clone()]
+        );
+        assert_eq!(
+            query(HistoryQuery {
+                kind: Some(Kind::Battery),
+                since: Some(entries[1].time),
+                ..Default::default()
+            }),
+            [entries[3].clone()]
+        );
+        assert_eq!(
+            query(HistoryQuery {
+                until: Some(entries[1].time),
+                ..Default::default()
+            }),
+            entries[..2]
+        );
+        assert_eq!(
+            query(HistoryQuery {
+                limit: Some(2),
+                ..Default::default()
+            }),
+            entries[3..]
+        );
+    }
+
+    #[test]
+    fn prune_keeps_the_newest_entries() {
+        let (history, entries) = recorded();
+        let config = HistoryConfig {
+            max_entries: 2,
+            ..Default::default()
+        };
+
+        assert_eq!(history.prune(&config).unwrap(), 3);
+        assert_eq!(
+            history.query(&HistoryQuery::default()).unwrap(),
+            entries[3..]
+        );
+    }
+
+    #[test]
+    fn prune_drops_entries_past_retention() {
+        let history = history();
+        let old = hours_ago(24 * 91);
+        let recent = hours_ago(1);
+        history.record(old, &battery(90)).unwrap();
+        history.record(recent, &battery(50)).unwrap();
+
+        assert_eq!(history.prune(&HistoryConfig::default()).unwrap(), 1);
+        assert_eq!(
+            history.query(&HistoryQuery::default()).unwrap(),
+            [Entry {
+                time: recent,
+                sample: battery(50)
+            }]
+        );
+    }
+
+    #[test]
+    fn csv_has_a_column_per_value() {
+        let time = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let entries = [
+            Entry {
+                time,
+                sample: battery(50),
+            },
+            Entry {
+                time,
+                sample: Sample::OutputMuted { muted: true },
+            },
+            Entry {
+                time,
+                sample: Sample::Connected,
+            },
+        ];
+
+        let mut csv = Vec::new();
+        write_csv(&entries, &mut csv).unwrap();
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "time,kind,percent,voltage,muted\n\
+             2023-11-14T22:13:20+00:00,battery,50,3750,\n\
+             2023-11-14T22:13:20+00:00,output_muted,,,true\n\
+             2023-11-14T22:13:20+00:00,connected,,,\n"
+        );
+    }
+}
@@ -0,0 +1,769 @@
+use crate::bus::{EventBus, Topic};
+use crate::config::{self, IpcConfig};
+use crate::history::{History, HistoryQuery};
+use crate::logging;
+use crate::record::{Record, RecordData};
+use crate::state::StateStore;
+use crate::steelseries::api::sonar::types::RedirectionId;
+use crate::{Event, SonarRequest, SonarResponse};
+use interprocess::local_socket::prelude::*;
+use interprocess::local_socket::traits::RecvHalf as _;
+use interprocess::local_socket::{ListenerOptions, Name, RecvHalf, SendHalf, Stream};
+use rand::Rng;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{debug, debug_span, info, warn};
+
+/// How long a call waits for the keyboard or Sonar to answer.
+const CALL_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Clients served at once, further ones are turned away.
+const MAX_CONNECTIONS: usize = 16;
+
+/// Longest request line. A client sending more without a newline is disconnected.
+const MAX_LINE_BYTES: u64 = 64 * 1024;
+
+/// Messages a connection's thread can have waiting. A client that lets more pile up, e.g. by not
+/// reading its notifications, is disconnected.
+const QUEUE_DEPTH: usize = 256;
+
+/// How long a client has to authenticate, so idle connections can't hold every slot.
+const AUTH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long a write to a client may block before the client is taken as gone.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What a connection's thread gets fed: lines from its client and events from the app.
+#[derive(Debug, Clone)]
+enum Message {
+    Line(String),
+    Closed,
+    Notification { method: &'static str, params: Value },
+}
+
+/// The hub's end of a connection.
+struct Client {
+    tx: SyncSender<Message>,
+    /// Set when the connection's queue was full, the connection then hangs up.
+    fell_behind: Arc<AtomicBool>,
+}
+
+struct Hub {
+    bus: EventBus,
+    token: String,
+    store: StateStore,
+    connections: Mutex<Vec<Client>>,
+    /// Clients currently being served.
+    clients: AtomicUsize,
+}
+
+impl Hub {
+    fn observe(&self, event: &Event) {
+        let (method, params) = match event {
+            Event::RecordFromDevice(record) => {
+                ("record", json!({ "from_device": true, "record": record }))
+            }
+            Event::RecordSentToDevice(record) => {
+                ("record", json!({ "from_device": false, "record": record }))
+            }
+            Event::StateChanged(changes) => ("state", json!({ "changes": changes })),
+            Event::BatteryAlert { level, percent } => (
+                "battery_alert",
+                json!({ "level": level, "percent": percent }),
+            ),
+            _ => return,
+        };
+        let message = Message::Notification { method, params };
+
+        self.connections.lock().unwrap().retain(|client| {
+            match client.tx.try_send(message.clone()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    client.fell_behind.store(true, Ordering::SeqCst);
+                    false
+                }
+                Err(TrySendError::Disconnected(_)) => false,
+            }
+        });
+    }
+}
+
+/// JSON-RPC server on a local socket (a named pipe on Windows) that other programs use to
+/// drive the keyboard through the companion instead of opening the HID device themselves.
+///
+/// Requests are newline-delimited JSON-RPC 2.0. Everything but `authenticate` needs the token
+/// from the `ipc-token` file in the config directory first:
+///
+/// ```json
+/// {"jsonrpc": "2.0", "id": 1, "method": "authenticate", "params": {"token": "..."}}
+/// {"jsonrpc": "2.0", "id": 2, "method": "set_led_meter", "params": {"percent": 40}}
+/// ```
+///
+/// After `subscribe`, records to and from the keyboard arrive as `record` notifications and
+/// changes to what `get_state` returns as `state` notifications. Battery alerts come as
+/// `battery_alert` notifications.
+///
+/// `get_history` takes optional `since`/`until` (RFC 3339), `kind` (`battery`, `connection` or
+/// `mute`) and `limit`.
+///
+/// At most [`MAX_CONNECTIONS`] clients are served at once, and a request line may be up to
+/// [`MAX_LINE_BYTES`] long. Clients that don't authenticate within [`AUTH_TIMEOUT`] or fall
+/// [`QUEUE_DEPTH`] messages behind are disconnected.
+pub(crate) struct IpcServer;
+
+impl IpcServer {
+    pub(crate) fn start(
+        config: &IpcConfig,
+        bus: EventBus,
+        store: StateStore,
+    ) -> std::io::Result<()> {
+        let token = write_token()?;
+        let listener = bind(&config.name)?;
+        #[cfg(windows)]
+        info!("IPC server listening on \\\\.\\pipe\\{}", config.name);
+        #[cfg(not(windows))]
+        info!(
+            "IPC server listening on {}",
+            socket_path(&config.name).display()
+        );
+
+        let events = bus.subscribe(
+            "ipc",
+            &[
+                Topic::Device,
+                Topic::DeviceSent,
+                Topic::Battery,
+                Topic::State,
+            ],
+        );
+        let hub = Arc::new(Hub {
+            bus,
+            token,
+            store,
+            connections: Mutex::new(Vec::new()),
+            clients: AtomicUsize::new(0),
+        });
+
+        let observer_hub = hub.clone();
+        std::thread::spawn(move || loop {
+            observer_hub.observe(&events.blocking_recv());
+        });
+
+        std::thread::spawn(move || {
+            listener.incoming().for_each(|stream| match stream {
+                Ok(_) if hub.clients.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS => {
+                    hub.clients.fetch_sub(1, Ordering::SeqCst);
+                    warn!("Refusing IPC connection, {MAX_CONNECTIONS} clients are connected");
+                }
+                Ok(stream) => {
+                    let hub = hub.clone();
+                    std::thread::spawn(move || {
+                        Connection::serve(hub.clone(), stream);
+                        hub.clients.fetch_sub(1, Ordering::SeqCst);
+                    });
+                }
+                Err(e) => warn!("Failed to accept IPC connection: {e}"),
+            });
+        });
+
+        Ok(())
+    }
+}
+
+fn token_path() -> Option<PathBuf> {
+    config::config_dir().map(|dir| dir.join("ipc-token"))
+}
+
+/// Generates a fresh token for this run and stores it where local clients can read it.
+fn write_token() -> std::io::Result<String> {
+    let token: String = rand::thread_rng()
+        .gen::<[u8; 16]>()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect();
+
+    let path = token_path().ok_or(std::io::ErrorKind::NotFound)?;
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(&path)?.write_all(token.as_bytes())?;
+
+    Ok(token)
+}
+
+#[cfg(windows)]
+fn socket_name(name: &str) -> std::io::Result<Name<'static>> {
+    use interprocess::local_socket::GenericNamespaced;
+
+    name.to_string().to_ns_name::<GenericNamespaced>()
+}
+
+#[cfg(not(windows))]
+fn socket_name(name: &str) -> std::io::Result<Name<'static>> {
+    use interprocess::local_socket::GenericFilePath;
+
+    socket_path(name).to_fs_name::<GenericFilePath>()
+}
+
+#[cfg(not(windows))]
+fn socket_path(name: &str) -> PathBuf {
+    dirs::runtime_dir()
+        .unwrap_or_else(std::env::temp_dir)
+        .join(format!("{name}.sock"))
+}
+
+/// Compares in time that only depends on the length, so timing doesn't leak how much of a guess
+/// was right.
+fn tokens_match(given: &str, token: &str) -> bool {
+    given.len() == token.len()
+        && given
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+fn bind(name: &str) -> std::io::Result<interprocess::local_socket::Listener> {
+    let listener = ListenerOptions::new()
+        .name(socket_name(name)?)
+        .create_sync();
+
+    // A socket file left behind by a crash blocks the name until it's removed, but one that
+    // still accepts connections belongs to another running instance.
+    #[cfg(not(windows))]
+    if let Err(e) = &listener {
+        if e.kind() == std::io::ErrorKind::AddrInUse && Stream::connect(socket_name(name)?).is_err()
+        {
+            std::fs::remove_file(socket_path(name))?;
+            return ListenerOptions::new()
+                .name(socket_name(name)?)
+                .create_sync();
+        }
+    }
+
+    listener
+}
+
+#[derive(Deserialize)]
+struct RpcRequest {
+    id: Option<Value>,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+#[derive(Debug)]
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    const PARSE_ERROR: i64 = -32700;
+    const METHOD_NOT_FOUND: i64 = -32601;
+    const INVALID_PARAMS: i64 = -32602;
+    const INTERNAL_ERROR: i64 = -32603;
+    const UNAUTHORIZED: i64 = -32001;
+    const TIMEOUT: i64 = -32002;
+
+    fn new(code: i64, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct AuthenticateParams {
+    token: String,
+}
+
+#[derive(Deserialize)]
+struct LedMeterParams {
+    percent: u8,
+    #[serde(default)]
+    warning_threshold: u8,
+    #[serde(default)]
+    danger_threshold: u8,
+    #[serde(default)]
+    invert: bool,
+    #[serde(default = "default_linger_time")]
+    linger_time: u16,
+}
+
+fn default_linger_time() -> u16 {
+    1000
+}
+
+#[derive(Deserialize)]
+struct SendRecordParams {
+    record: RecordData,
+}
+
+#[derive(Deserialize)]
+struct LogLevelParams {
+    /// `RUST_LOG` syntax, or null to go back to the startup levels.
+    filter: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct SonarRedirectParams {
+    redirection: RedirectionId,
+    device: String,
+}
+
+struct Connection {
+    hub: Arc<Hub>,
+    rx: Receiver<Message>,
+    recv: Arc<RecvHalf>,
+    send: SendHalf,
+    fell_behind: Arc<AtomicBool>,
+    authenticated: bool,
+    subscribed: bool,
+}
+
+impl Connection {
+    fn serve(hub: Arc<Hub>, stream: Stream) {
+        debug!("IPC client connected");
+        // The receive timeout is lifted once the client authenticates
+        let timeouts = stream
+            .set_recv_timeout(Some(AUTH_TIMEOUT))
+            .and_then(|()| stream.set_send_timeout(Some(WRITE_TIMEOUT)));
+        if let Err(e) = timeouts {
+            warn!("Failed to set IPC client timeouts: {e}");
+            return;
+        }
+
+        let (recv, send) = stream.split();
+        let recv = Arc::new(recv);
+        let (tx, rx) = mpsc::sync_channel(QUEUE_DEPTH);
+        let fell_behind = Arc::new(AtomicBool::new(false));
+        hub.connections.lock().unwrap().push(Client {
+            tx: tx.clone(),
+            fell_behind: fell_behind.clone(),
+        });
+        let reader = recv.clone();
+        std::thread::spawn(move || Self::read_lines(&*reader, tx));
+
+        let mut connection = Self {
+            hub,
+            rx,
+            recv,
+            send,
+            fell_behind,
+            authenticated: false,
+            subscribed: false,
+        };
+        connection.run();
+        debug!("IPC client disconnected");
+    }
+
+    fn read_lines(recv: impl Read, tx: SyncSender<Message>) {
+        let mut reader = BufReader::new(recv);
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            match (&mut reader)
+                .take(MAX_LINE_BYTES)
+                .read_until(b'\n', &mut line)
+            {
+                Ok(0) | Err(_) => break,
+                Ok(size) if size as u64 == MAX_LINE_BYTES && line.last() != Some(&b'\n') => {
+                    warn!("IPC request is longer than {MAX_LINE_BYTES} bytes, disconnecting");
+                    break;
+                }
+                Ok(_) => {}
+            }
+
+            let Ok(text) = std::str::from_utf8(&line) else {
+                break;
+            };
+            let text = text.trim_end_matches(['\n', '\r']).to_string();
+            if tx.send(Message::Line(text)).is_err() {
+                return;
+            }
+        }
+        let _ = tx.send(Message::Closed);
+    }
+
+    fn run(&mut self) {
+        let deadline = Instant::now() + AUTH_TIMEOUT;
+        loop {
+            let message = match self.authenticated {
+                true => self.rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+                false => self
+                    .rx
+                    .recv_timeout(deadline.saturating_duration_since(Instant::now())),
+            };
+            let message = match message {
+                Ok(message) => message,
+                Err(RecvTimeoutError::Timeout) => {
+                    debug!("IPC client didn't authenticate in time");
+                    return;
+                }
+                Err(RecvTimeoutError::Disconnected) => return,
+            };
+            if self.fell_behind.load(Ordering::SeqCst) {
+                warn!("IPC client fell {QUEUE_DEPTH} messages behind, disconnecting");
+                return;
+            }
+
+            let result = match message {
+                Message::Line(line) if line.trim().is_empty() => Ok(()),
+                Message::Line(line) => match self.handle_line(&line) {
+                    Some(response) => self.write(&response),
+                    None => Ok(()),
+                },
+                Message::Closed => return,
+                Message::Notification { method, params } => self.notify(method, params),
+            };
+
+            if let Err(e) = result {
+                debug!("IPC client went away: {e}");
+                return;
+            }
+        }
+    }
+
+    fn write(&mut self, value: &Value) -> std::io::Result<()> {
+        let mut line = value.to_string();
+        line.push('\n');
+        self.send.write_all(line.as_bytes())
+    }
+
+    /// Forwards an event to the client if it asked for them.
+    fn notify(&mut self, method: &str, params: Value) -> std::io::Result<()> {
+        if !self.subscribed {
+            return Ok(());
+        }
+
+        self.write(&json!({ "jsonrpc": "2.0", "method": method, "params": params }))
+    }
+
+    fn handle_line(&mut self, line: &str) -> Option<Value> {
+        let request = match serde_json::from_str::<RpcRequest>(line) {
+            Ok(request) => request,
+            Err(e) => {
+                let error = RpcError::new(RpcError::PARSE_ERROR, e.to_string());
+                return Some(Self::response(Value::Null, Err(error)));
+            }
+        };
+
+        let _span = debug_span!("ipc_call", method = %request.method).entered();
+        let result = self.call(&request.method, request.params);
+        if let Err(error) = &result {
+            debug!(code = error.code, "{}", error.message);
+        }
+        // Requests without an ID are notifications and get no answer
+        request.id.map(|id| Self::response(id, result))
+    }
+
+    fn response(id: Value, result: Result<Value, RpcError>) -> Value {
+        match result {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+            Err(error) => json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "error": { "code": error.code, "message": error.message },
+            }),
+        }
+    }
+
+    fn params<T: DeserializeOwned>(params: Value) -> Result<T, RpcError> {
+        serde_json::from_value(params)
+            .map_err(|e| RpcError::new(RpcError::INVALID_PARAMS, e.to_string()))
+    }
+
+    fn call(&mut self, method: &str, params: Value) -> Result<Value, RpcError> {
+        if method != "authenticate" && !self.authenticated {
+            return Err(RpcError::new(
+                RpcError::UNAUTHORIZED,
+                "Call authenticate with the token first",
+            ));
+        }
+
+        match method {
+            "authenticate" => {
+                let params: AuthenticateParams = Self::params(params)?;
+                self.authenticated = tokens_match(&params.token, &self.hub.token);
+                match self.authenticated {
+                    true => {
+                        if let Err(e) = self.recv.set_timeout(None) {
+                            warn!("Failed to lift the IPC receive timeout: {e}");
+                        }
+                        Ok(Value::Bool(true))
+                    }
+                    false => Err(RpcError::new(RpcError::UNAUTHORIZED, "Wrong token")),
+                }
+            }
+            "subscribe" => {
+                self.subscribed = true;
+                Ok(Value::Bool(true))
+            }
+            "unsubscribe" => {
+                self.subscribed = false;
+                Ok(Value::Bool(true))
+            }
+            "get_state" => Ok(json!(self.hub.store.snapshot())),
+            "get_history" => {
+                let query: HistoryQuery = match params {
+                    Value::Null => HistoryQuery::default(),
+                    params => Self::params(params)?,
+                };
+                let entries = History::open()
+                    .and_then(|history| history.query(&query).map_err(|e| e.to_string()))
+                    .map_err(|e| RpcError::new(RpcError::INTERNAL_ERROR, e))?;
+                Ok(json!(entries))
+            }
+            "get_battery" => self.call_app(
+                Event::RecordToDevice(Record::new(0, RecordData::BatteryRequest)),
+                Topic::Device,
+                |event| match event {
+                    Event::RecordFromDevice(Record {
+                        data: RecordData::BatteryResponse { percent, voltage },
+                        ..
+                    }) => Some(json!({ "percent": percent, "voltage": voltage })),
+                    _ => None,
+                },
+            ),
+            "set_led_meter" => {
+                let params: LedMeterParams = Self::params(params)?;
+                self.send_record(RecordData::SetLedMeter {
+                    percent: params.percent.min(100),
+                    warning_threshold: params.warning_threshold,
+                    danger_threshold: params.danger_threshold,
+                    invert: params.invert,
+                    linger_time: params.linger_time,
+                })?;
+                Ok(Value::Null)
+            }
+            "send_record" => {
+                let params: SendRecordParams = Self::params(params)?;
+                self.send_record(params.record)?;
+                Ok(Value::Null)
+            }
+            "set_log_level" => {
+                let params: LogLevelParams = Self::params(params)?;
+                logging::set_filter(params.filter.as_deref())
+                    .map_err(|e| RpcError::new(RpcError::INVALID_PARAMS, e))?;
+                Ok(Value::Null)
+            }
+            "sonar_devices" => self.sonar_call(SonarRequest::FetchDevices),
+            "sonar_redirections" => self.sonar_call(SonarRequest::FetchClassicRedirections),
+            "sonar_redirect" => {
+                let params: SonarRedirectParams = Self::params(params)?;
+                self.sonar_call(SonarRequest::RedirectDevice {
+                    redirection: params.redirection,
+                    device: params.device,
+                })
+            }
+            _ => Err(RpcError::new(
+                RpcError::METHOD_NOT_FOUND,
+                format!("Unknown method '{method}'"),
+            )),
+        }
+    }
+
+    fn send_record(&self, data: RecordData) -> Result<(), RpcError> {
+        self.hub
+            .bus
+            .send(Event::RecordToDevice(Record::new(0, data)))
+            .map_err(|_| RpcError::new(RpcError::TIMEOUT, "The companion is shutting down"))
+    }
+
+    fn sonar_call(&self, request: SonarRequest) -> Result<Value, RpcError> {
+        self.call_app(
+            Event::SonarRequest(request),
+            Topic::SonarResponse,
+            |event| match event {
+                Event::SonarResponse(SonarResponse::FetchDevices(devices)) => {
+                    serde_json::to_value(devices).ok()
+                }
+                Event::SonarResponse(SonarResponse::FetchClassicRedirections(redirections)) => {
+                    serde_json::to_value(redirections).ok()
+                }
+                Event::SonarResponse(SonarResponse::RedirectDevice(redirection)) => {
+                    serde_json::to_value(redirection).ok()
+                }
+                _ => None,
+            },
+        )
+    }
+
+    /// Sends `request` through the app and waits for the answer `extract` picks out. Records
+    /// for the client queue up meanwhile and go out after the response.
+    fn call_app(
+        &self,
+        request: Event,
+        reply: Topic,
+        extract: impl Fn(&Event) -> Option<Value>,
+    ) -> Result<Value, RpcError> {
+        self.hub
+            .bus
+            .request(request, reply, CALL_TIMEOUT, extract)
+            .map_err(|e| RpcError::new(RpcError::TIMEOUT, e.to_string()))?
+            .ok_or_else(|| RpcError::new(RpcError::TIMEOUT, "No answer in time"))
+    }
+}
+
+/// Client end of [`IpcServer`], for commands that ask the running app instead of the keyboard.
+pub(crate) struct IpcClient {
+    recv: BufReader<RecvHalf>,
+    send: SendHalf,
+    next_id: u64,
+}
+
+impl IpcClient {
+    /// Connects to the running app and authenticates with the token it left behind.
+    pub(crate) fn connect(config: &IpcConfig) -> Result<Self, String> {
+        let stream = socket_name(&config.name)
+            .and_then(Stream::connect)
+            .map_err(|e| format!("The app isn't running or has IPC disabled: {e}"))?;
+        let token = token_path()
+            .ok_or("No config directory for the IPC token".to_string())
+            .and_then(|path| {
+                std::fs::read_to_string(&path)
+                    .map_err(|e| format!("Failed to read {}: {e}", path.display()))
+            })?;
+
+        let (recv, send) = stream.split();
+        let mut client = Self {
+            recv: BufReader::new(recv),
+            send,
+            next_id: 0,
+        };
+        client.call("authenticate", json!({ "token": token.trim() }))?;
+
+        Ok(client)
+    }
+
+    /// Calls `method` and waits for its result. Notifications arriving meanwhile are dropped.
+    pub(crate) fn call(&mut self, method: &str, params: Value) -> Result<Value, String> {
+        self.next_id += 1;
+        let request = json!({
+            "jsonrpc": "2.0",
+            "id": self.next_id,
+            "method": method,
+            "params": params,
+        });
+        let mut line = request.to_string();
+        line.push('\n');
+        self.send
+            .write_all(line.as_bytes())
+            .map_err(|e| format!("Failed to send to the app: {e}"))?;
+
+        loop {
+            let mut response = self.read()?;
+            if response["id"] != self.next_id {
+                continue;
+            }
+            if let Some(error) = response.get("error") {
+                return Err(error["message"]
+                    .as_str()
+                    .unwrap_or("Unknown error")
+                    .to_string());
+            }
+            return Ok(response["result"].take());
+        }
+    }
+
+    /// Waits for the next notification and returns its method and params.
+    pub(crate) fn notification(&mut self) -> Result<(String, Value), String> {
+        loop {
+            let mut message = self.read()?;
+            if let Some(method) = message["method"].as_str() {
+                return Ok((method.to_string(), message["params"].take()));
+            }
+        }
+    }
+
+    fn read(&mut self) -> Result<Value, String> {
+        let mut line = String::new();
+        match self.recv.read_line(&mut line) {
+            Ok(0) => Err("The app closed the connection".to_string()),
+            Ok(_) => serde_json::from_str(&line).map_err(|e| format!("Invalid message: {e}")),
+            Err(e) => Err(format!("Failed to read from the app: {e}")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(input: &[u8]) -> Vec<String> {
+        let (tx, rx) = mpsc::sync_channel(QUEUE_DEPTH);
+        Connection::read_lines(input, tx);
+        rx.iter()
+            .map_while(|message| match message {
+                Message::Line(line) => Some(line),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn tokens_match_only_exactly() {
+        assert!(tokens_match("0123abcd", "0123abcd"));
+        assert!(!tokens_match("0123abce", "0123abcd"));
+        assert!(!tokens_match("0123abc", "0123abcd"));
+        assert!(!tokens_match("", "0123abcd"));
+    }
+
+    #[test]
+    fn read_lines_splits_requests() {
+        assert_eq!(
+            lines(b"{}\r\n\n[1]\n{\"a\": 2}"),
+            ["{}", "", "[1]", "{\"a\": 2}"]
+        );
+    }
+
+    #[test]
+    fn hub_drops_clients_that_fall_behind() {
+        let bus = EventBus::default();
+        let hub = Hub {
+            bus: bus.clone(),
+            token: String::new(),
+            store: StateStore::start(bus),
+            connections: Mutex::new(Vec::new()),
+            clients: AtomicUsize::new(0),
+        };
+        let (tx, rx) = mpsc::sync_channel(QUEUE_DEPTH);
+        let fell_behind = Arc::new(AtomicBool::new(false));
+        hub.connections.lock().unwrap().push(Client {
+            tx,
+            fell_behind: fell_behind.clone(),
+        });
+
+        let event = Event::StateChanged(Vec::new());
+        (0..QUEUE_DEPTH).for_each(|_| hub.observe(&event));
+        assert!(!fell_behind.load(Ordering::SeqCst));
+
+        hub.observe(&event);
+        assert!(fell_behind.load(Ordering::SeqCst));
+        assert!(hub.connections.lock().unwrap().is_empty());
+        assert_eq!(rx.try_iter().count(), QUEUE_DEPTH);
+    }
+
+    #[test]
+    fn read_lines_disconnects_on_overlong_line() {
+        let mut input = b"{}\n".to_vec();
+        input.extend(std::iter::repeat_n(b' ', MAX_LINE_BYTES as usize));
+        input.extend(b"\n{}\n");
+
+        assert_eq!(lines(&input), ["{}"]);
+    }
+}
@@ -0,0 +1,86 @@
+use std::path::Path;
+use std::sync::OnceLock;
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, reload, EnvFilter, Registry};
+
+const DEFAULT_FILTER: &str = "warn,kbd_companion=info";
+
+/// Daily log files kept next to the current one.
+const MAX_LOG_FILES: usize = 7;
+
+static FILTER: OnceLock<reload::Handle<EnvFilter, Registry>> = OnceLock::new();
+
+/// Flushes file logs when dropped, so keep it alive until the process exits.
+pub(crate) struct LogGuard {
+    _writer: Option<WorkerGuard>,
+}
+
+/// Sends logs to daily rotated files named after `path`, and to stderr if `stderr` is set.
+/// `RUST_LOG` overrides the default levels until [`set_filter`] changes them.
+pub(crate) fn init(path: Option<&Path>, stderr: bool) -> Result<LogGuard, String> {
+    let (filter, handle) = reload::Layer::new(startup_filter());
+    FILTER
+        .set(handle)
+        .map_err(|_| "Logging is already initialised".to_string())?;
+
+    let (file, guard) = match path {
+        Some(path) => {
+            let (writer, guard) = tracing_appender::non_blocking(appender(path)?);
+            let layer = fmt::layer().with_writer(writer).with_ansi(false);
+            (Some(layer), Some(guard))
+        }
+        None => (None, None),
+    };
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(stderr.then(|| fmt::layer().with_writer(std::io::stderr)))
+        .with(file)
+        .try_init()
+        .map_err(|e| e.to_string())?;
+
+    Ok(LogGuard { _writer: guard })
+}
+
+fn appender(path: &Path) -> Result<RollingFileAppender, String> {
+    let dir = path.parent().unwrap_or(Path::new("."));
+    std::fs::create_dir_all(dir).map_err(|e| format!("Failed to create {}: {e}", dir.display()))?;
+    let mut appender = RollingFileAppender::builder()
+        .rotation(Rotation::DAILY)
+        .max_log_files(MAX_LOG_FILES);
+    if let Some(prefix) = path.file_stem().and_then(|stem| stem.to_str()) {
+        appender = appender.filename_prefix(prefix);
+    }
+    if let Some(suffix) = path.extension().and_then(|ext| ext.to_str()) {
+        appender = appender.filename_suffix(suffix);
+    }
+    appender
+        .build(dir)
+        .map_err(|e| format!("Failed to open log file in {}: {e}", dir.display()))
+}
+
+/// Checks a filter in `RUST_LOG` syntax, e.g. `debug` or `warn,kbd_companion::ipc=trace`.
+pub(crate) fn parse_filter(filter: &str) -> Result<EnvFilter, String> {
+    EnvFilter::try_new(filter).map_err(|e| format!("Invalid log filter '{filter}': {e}"))
+}
+
+/// Swaps the active filter at runtime. `None` goes back to the one logging started with.
+pub(crate) fn set_filter(filter: Option<&str>) -> Result<(), String> {
+    let filter = match filter {
+        Some(filter) => parse_filter(filter)?,
+        None => startup_filter(),
+    };
+
+    FILTER
+        .get()
+        .ok_or("Logging isn't initialised")?
+        .reload(filter)
+        .map_err(|e| e.to_string())
+}
+
+fn startup_filter() -> EnvFilter {
+    EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(DEFAULT_FILTER))
+}
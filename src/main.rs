@@ -1,109 +1,386 @@
+mod acks;
 mod audio;
+mod battery;
+mod bindings;
+mod bus;
+mod cli;
+mod commands;
+mod config;
 mod gui;
+mod heartbeat;
 mod hid_device_channel;
+mod history;
+mod ipc;
+mod logging;
 mod record;
+mod shutdown;
+mod simulated_device;
+mod state;
 mod steelseries;
+mod write_queue;
 
-use crate::audio::AudioManager;
+use crate::acks::Acks;
+use crate::audio::{
+    AudioBackend, AudioDeviceInfo, AudioError, AudioSessionInfo, DataFlow, DeviceStateFilter,
+    EndpointRole, EndpointSelector,
+};
+use crate::battery::{BatteryLevel, BatteryMonitor};
+use crate::bindings::{Action, ActiveBinding, Bindings};
+use crate::bus::{EventBus, Subscription, Topic};
+use crate::cli::{Command, Options, USAGE};
+use crate::config::{Config, ConfigWatcher, DeviceConfig, SonarConfig};
 use crate::gui::init_gui;
-use crate::hid_device_channel::{HidDeviceChannel, WriteError};
+use crate::heartbeat::{Beat, Heartbeat, HeartbeatStats};
+use crate::hid_device_channel::{DecodeErrors, HidDeviceChannel, ReadError, WriteError};
+use crate::history::{Entry, History, HistoryQuery};
+use crate::ipc::IpcServer;
+use crate::shutdown::Shutdown;
+use crate::state::{DeviceInfo, StateChange, StateStore};
 use crate::steelseries::api::sonar::types::{ClassicRedirection, RedirectionId, VolumeInfo};
 use crate::steelseries::SteelSeriesEngineClient;
-use hid_device_channel::WriteResult;
+use crate::write_queue::{WriteQueue, WriteQueueStats};
 use hidapi::HidError;
 use record::*;
-use std::fmt::Debug;
+use std::collections::HashMap;
+use std::fmt::{Debug, Display};
+use std::path::PathBuf;
 use std::process::ExitCode;
-use std::sync::mpsc;
-use std::sync::mpsc::{Receiver, SendError, Sender, TryRecvError};
-use std::thread::sleep;
-use std::time::Duration;
-use tokio::sync::mpsc::UnboundedReceiver;
-use windows::Win32::Media::Audio::{
-    eCapture, eCommunications, eMultimedia, eRender, EDataFlow, ERole,
-};
+use std::thread::{sleep, JoinHandle};
+use std::time::{Duration, Instant};
+use tracing::{debug, debug_span, error, info, info_span, warn, Instrument};
 
 struct VolumeManager {
     previous_vol: Option<u8>,
     current_vol: Option<u8>,
-    audio_manager: AudioManager,
+    audio: Box<dyn AudioBackend>,
     curr_mute: Option<bool>,
     prev_mute: Option<bool>,
     prev_mic_mute: Option<bool>,
     curr_mic_mute: Option<bool>,
+    session_targets: Vec<SessionTarget>,
+    preferred_outputs: Vec<String>,
+    level_sampler: LevelSampler,
+    privacy: Option<PrivacyMode>,
+    output_endpoint: EndpointSelector,
+    input_endpoint: EndpointSelector,
+    input_mode: InputMode,
+    release_delay: Duration,
+    input_hold: Option<InputHold>,
+    volume_limits: Vec<VolumeLimit>,
+    clamped_vol: Option<u8>,
+}
+
+/// Which endpoints a [`VolumeLimit`] applies to.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum DeviceMatcher {
+    Id(String),
+    /// Case-insensitive match on the friendly name. `*` matches any run of characters; a
+    /// pattern without one matches anywhere in the name.
+    NamePattern(String),
+}
+
+impl DeviceMatcher {
+    fn matches(&self, device: &AudioDeviceInfo) -> bool {
+        match self {
+            DeviceMatcher::Id(id) => device.id == *id,
+            DeviceMatcher::NamePattern(pattern) => {
+                let pattern = pattern.to_lowercase();
+                let pattern = if pattern.contains('*') {
+                    pattern
+                } else {
+                    format!("*{pattern}*")
+                };
+
+                glob_matches(&pattern, &device.name.to_lowercase())
+            }
+        }
+    }
+}
+
+fn glob_matches(pattern: &str, text: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = text.strip_prefix(first) else {
+        return false;
+    };
+
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        // No wildcard at all, the whole text has to match
+        return rest.is_empty();
+    };
+
+    for part in middle {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+
+    rest.ends_with(last)
+}
+
+/// Caps the volume of matching output endpoints.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct VolumeLimit {
+    pub(crate) matcher: DeviceMatcher,
+    pub(crate) max_volume: u8,
+}
+
+/// How the keyboard's mic key drives the input mute state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum InputMode {
+    /// Each press flips the mute state
+    Toggle,
+    /// The mic is live only while the key is held
+    PushToTalk,
+    /// The mic is muted only while the key is held
+    PushToMute,
+}
+
+impl Display for InputMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InputMode::Toggle => write!(f, "Toggle"),
+            InputMode::PushToTalk => write!(f, "Push to talk"),
+            InputMode::PushToMute => write!(f, "Push to mute"),
+        }
+    }
+}
+
+/// A push-to-talk or push-to-mute key that is currently held, or released and waiting out the
+/// release delay.
+struct InputHold {
+    /// Mute state to go back to once the hold ends
+    restore: bool,
+    released_at: Option<Instant>,
+}
+
+/// State of the hard privacy mode, present only while it's on.
+struct PrivacyMode {
+    /// Mute state of every capture endpoint before the mode muted it, keyed by device ID
+    previous: HashMap<String, bool>,
+    last_scan: Option<Instant>,
+    all_muted: bool,
+}
+
+impl PrivacyMode {
+    const SCAN_INTERVAL: Duration = Duration::from_secs(1);
+
+    fn new() -> Self {
+        Self {
+            previous: HashMap::new(),
+            last_scan: None,
+            all_muted: false,
+        }
+    }
+}
+
+/// What the keyboard's LED meter mirrors while nothing else (battery, output switching) is
+/// being shown on it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum LedMeterMode {
+    Volume,
+    OutputLevel,
+    InputLevel,
+}
+
+impl Display for LedMeterMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LedMeterMode::Volume => write!(f, "Volume"),
+            LedMeterMode::OutputLevel => write!(f, "Output level"),
+            LedMeterMode::InputLevel => write!(f, "Mic level"),
+        }
+    }
+}
+
+/// Samples the default endpoint's peak level for the level meter modes. Updates are capped at
+/// `rate_hz`, and never exceed [`LevelSampler::MAX_RATE_HZ`] so the HID link isn't flooded.
+struct LevelSampler {
+    mode: LedMeterMode,
+    rate_hz: u8,
+    last_sample: Option<Instant>,
+    last_level: Option<u8>,
+}
+
+impl LevelSampler {
+    const MAX_RATE_HZ: u8 = 30;
+
+    fn new() -> Self {
+        Self {
+            mode: LedMeterMode::Volume,
+            rate_hz: 20,
+            last_sample: None,
+            last_level: None,
+        }
+    }
+
+    fn set_mode(&mut self, mode: LedMeterMode, rate_hz: u8) {
+        self.rate_hz = rate_hz.clamp(1, Self::MAX_RATE_HZ);
+        self.mode = mode;
+    }
+
+    fn interval(&self) -> Duration {
+        Duration::from_secs(1) / self.rate_hz as u32
+    }
+
+    /// Returns a new level when a sample is due and it differs from the previous one. Leaving
+    /// the level modes yields a single `0` so the meter isn't stuck on the last level.
+    fn sample(
+        &mut self,
+        audio: &dyn AudioBackend,
+        output: &EndpointSelector,
+        input: &EndpointSelector,
+    ) -> Option<u8> {
+        let (data_flow, selector) = match self.mode {
+            LedMeterMode::Volume => return self.last_level.take().map(|_| 0),
+            LedMeterMode::OutputLevel => (DataFlow::Render, output),
+            LedMeterMode::InputLevel => (DataFlow::Capture, input),
+        };
+
+        if self
+            .last_sample
+            .is_some_and(|last_sample| last_sample.elapsed() < self.interval())
+        {
+            return None;
+        }
+        self.last_sample = Some(Instant::now());
+
+        let device = audio.endpoint(data_flow, selector).ok()?;
+        let level = audio.peak(&device.id).ok()?;
+        if self.last_level == Some(level) {
+            return None;
+        }
+
+        self.last_level = Some(level);
+        Some(level)
+    }
+}
+
+/// What a `ToggleSessionMute`/`SetSessionVolume` record acts on, indexed by the record's `target`.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum SessionTarget {
+    ForegroundApp,
+    Executable(String),
+}
+
+impl Display for SessionTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SessionTarget::ForegroundApp => write!(f, "Foreground app"),
+            SessionTarget::Executable(name) => write!(f, "{name}"),
+        }
+    }
 }
 
 impl Default for VolumeManager {
     fn default() -> Self {
-        let mut manager = unsafe {
-            Self {
-                previous_vol: None,
-                current_vol: None,
-                audio_manager: AudioManager::new().unwrap(),
-                curr_mute: None,
-                prev_mute: None,
-                prev_mic_mute: None,
-                curr_mic_mute: None,
-            }
+        Self::new(audio::system_backend())
+    }
+}
+
+impl VolumeManager {
+    fn new(audio: Box<dyn AudioBackend>) -> Self {
+        let mut manager = Self {
+            previous_vol: None,
+            current_vol: None,
+            audio,
+            curr_mute: None,
+            prev_mute: None,
+            prev_mic_mute: None,
+            curr_mic_mute: None,
+            session_targets: vec![SessionTarget::ForegroundApp],
+            preferred_outputs: Vec::new(),
+            level_sampler: LevelSampler::new(),
+            privacy: None,
+            output_endpoint: EndpointSelector::Default(EndpointRole::Multimedia),
+            input_endpoint: EndpointSelector::Default(EndpointRole::Communications),
+            input_mode: InputMode::Toggle,
+            release_delay: Duration::from_millis(200),
+            input_hold: None,
+            volume_limits: Vec::new(),
+            clamped_vol: None,
         };
 
         manager.refresh();
         manager
     }
-}
 
-impl VolumeManager {
-    fn get_system_volume(
-        &self,
-        device_type: Option<EDataFlow>,
-        device_role: Option<ERole>,
-    ) -> Option<u8> {
-        let device_type = device_type.unwrap_or(eRender);
-        let device_role = device_role.unwrap_or(eMultimedia);
-        let device = self
-            .audio_manager
-            .get_default_device(device_type, device_role);
+    fn get_output_device(&self) -> Result<AudioDeviceInfo, AudioError> {
+        self.audio.endpoint(DataFlow::Render, &self.output_endpoint)
+    }
 
-        match device.unwrap().activate() {
-            Ok(device) => device.get_volume().map_err(|_| None::<u8>).ok(),
-            Err(_) => None::<u8>,
-        }
+    fn get_input_device(&self) -> Result<AudioDeviceInfo, AudioError> {
+        self.audio.endpoint(DataFlow::Capture, &self.input_endpoint)
+    }
+
+    fn get_output_volume(&self) -> Result<u8, AudioError> {
+        self.audio.volume(&self.get_output_device()?.id)
     }
 
-    fn get_mute(&self, device_type: Option<EDataFlow>, device_role: Option<ERole>) -> Option<bool> {
-        let device_type = device_type.unwrap_or(eRender);
-        let device_role = device_role.unwrap_or(eMultimedia);
+    fn set_output_volume(&self, percent: u8) -> Result<(), AudioError> {
+        self.audio
+            .set_volume(&self.get_output_device()?.id, percent)
+    }
 
-        let device = self
-            .audio_manager
-            .get_default_device(device_type, device_role);
+    fn get_output_mute(&self) -> Result<bool, AudioError> {
+        self.audio.muted(&self.get_output_device()?.id)
+    }
 
-        match device
-            .expect("Failed to get default audio device")
-            .activate()
-        {
-            Ok(device) => Some(
-                device
-                    .get_muted()
-                    .expect("Failed to get mute state of device"),
-            ),
-            Err(e) => panic!("{e:?}"),
+    /// Mute state shown on the keyboard's mic indicator. In privacy mode this covers every
+    /// capture endpoint, not just the tracked one.
+    fn get_input_mute(&self) -> Result<bool, AudioError> {
+        match &self.privacy {
+            Some(privacy) => Ok(privacy.all_muted),
+            None => self.audio.muted(&self.get_input_device()?.id),
         }
     }
 
     fn refresh(&mut self) -> &Self {
         self.previous_vol = self.current_vol;
-        self.current_vol = self.get_system_volume(Some(eRender), Some(eMultimedia));
+        self.clamped_vol = self.enforce_volume_limit();
+        self.current_vol = self.clamped_vol.or_else(|| self.get_output_volume().ok());
 
         self.prev_mute = self.curr_mute;
-        self.curr_mute = self.get_mute(Some(eRender), Some(eMultimedia));
+        self.curr_mute = self.get_output_mute().ok();
 
+        self.enforce_privacy_mode();
+        self.finish_input_hold();
         self.prev_mic_mute = self.curr_mic_mute;
-        self.curr_mic_mute = self.get_mute(Some(eCapture), Some(eCommunications));
+        self.curr_mic_mute = self.get_input_mute().ok();
 
         self
     }
 
+    /// Pulls the tracked output back down to the lowest matching limit. Runs on every refresh,
+    /// so it also catches a newly selected default device before anything plays through it.
+    /// Returns the limit when the volume had to be clamped.
+    fn enforce_volume_limit(&self) -> Option<u8> {
+        if self.volume_limits.is_empty() {
+            return None;
+        }
+
+        let device = self.get_output_device().ok()?;
+        let limit = self
+            .volume_limits
+            .iter()
+            .filter(|limit| limit.matcher.matches(&device))
+            .map(|limit| limit.max_volume)
+            .min()?;
+        if self.audio.volume(&device.id).ok()? <= limit {
+            return None;
+        }
+
+        match self.audio.set_volume(&device.id, limit) {
+            Ok(()) => Some(limit),
+            Err(e) => {
+                error!("Failed to clamp volume of {}: {e:?}", device.name);
+                None
+            }
+        }
+    }
+
     fn get_vol_if_changed(&self) -> Option<u8> {
         match self.current_vol {
             Some(vol) if vol != self.previous_vol? => Some(vol),
@@ -125,29 +402,440 @@ impl VolumeManager {
         }
     }
 
-    fn toggle_mic_mute(&mut self) {
-        let curr_mute = self.curr_mic_mute.unwrap_or_else(|| {
-            self.refresh()
-                .curr_mic_mute
-                .expect("Failed to refresh Mic mute state")
-        });
+    /// Flips the tracked output's mute state and returns the new state.
+    fn toggle_output_mute(&mut self) -> Result<bool, AudioError> {
+        let muted = !match self.curr_mute {
+            Some(muted) => muted,
+            None => self.get_output_mute()?,
+        };
+
+        self.audio.set_muted(&self.get_output_device()?.id, muted)?;
+        Ok(muted)
+    }
+
+    fn toggle_mic_mute(&mut self) -> Result<(), AudioError> {
+        let curr_mute = match self.curr_mic_mute {
+            Some(muted) => muted,
+            None => self.get_input_mute()?,
+        };
 
         self.set_mic_mute(!curr_mute)
     }
 
-    fn set_mic_mute(&mut self, muted: bool) {
-        self.audio_manager
-            .get_mic()
-            .expect("Failed to get microphone device")
-            .activate()
-            .expect("Failed to activate microphone device")
-            .set_muted(muted)
+    fn set_mic_mute(&mut self, muted: bool) -> Result<(), AudioError> {
+        self.audio.set_muted(&self.get_input_device()?.id, muted)
+    }
+
+    fn press_input_key(&mut self) -> Result<(), AudioError> {
+        let held_muted = match self.input_mode {
+            InputMode::Toggle => return self.toggle_mic_mute(),
+            InputMode::PushToTalk => false,
+            InputMode::PushToMute => true,
+        };
+
+        match &mut self.input_hold {
+            // Pressed again within the release delay, keep holding
+            Some(hold) => hold.released_at = None,
+            None => {
+                self.input_hold = Some(InputHold {
+                    restore: self.get_input_mute()?,
+                    released_at: None,
+                })
+            }
+        }
+
+        self.set_mic_mute(held_muted)
+    }
+
+    fn release_input_key(&mut self) {
+        if let Some(hold) = &mut self.input_hold {
+            hold.released_at.get_or_insert_with(Instant::now);
+        }
+    }
+
+    /// Restores the pre-hold mute state once a released key has waited out the release delay.
+    fn finish_input_hold(&mut self) {
+        let Some(hold) = &self.input_hold else {
+            return;
+        };
+        if !hold
+            .released_at
+            .is_some_and(|released_at| released_at.elapsed() >= self.release_delay)
+        {
+            return;
+        }
+
+        let restore = hold.restore;
+        self.input_hold = None;
+        if let Err(e) = self.set_mic_mute(restore) {
+            error!("Failed to restore mic mute after release: {e:?}");
+        }
+    }
+
+    /// Drops an in-progress hold without waiting for the key to come back up, muting the mic
+    /// so it's never left live when the keyboard goes away mid-hold.
+    fn abort_input_hold(&mut self) {
+        if self.input_hold.take().is_none() {
+            return;
+        }
+
+        if let Err(e) = self.set_mic_mute(true) {
+            error!("Failed to mute mic after losing the keyboard: {e:?}");
+        }
+    }
+
+    fn is_privacy_mode(&self) -> bool {
+        self.privacy.is_some()
+    }
+
+    /// Turns the hard privacy mode on or off. Enabling mutes every active capture endpoint;
+    /// disabling puts each endpoint back to the mute state it had before the mode touched it.
+    fn set_privacy_mode(&mut self, enabled: bool) {
+        if enabled == self.is_privacy_mode() {
+            return;
+        }
+
+        if enabled {
+            // Privacy wins over a held push-to-talk key
+            self.input_hold = None;
+            self.privacy = Some(PrivacyMode::new());
+            self.enforce_privacy_mode();
+            return;
+        }
+
+        let Some(privacy) = self.privacy.take() else {
+            return;
+        };
+        let devices = match self
+            .audio
+            .devices(Some(DataFlow::Capture), DeviceStateFilter::Active)
+        {
+            Ok(devices) => devices,
+            Err(e) => {
+                error!("Failed to list capture devices: {e:?}");
+                return;
+            }
+        };
+
+        devices.iter().for_each(|device| {
+            let Some(muted) = privacy.previous.get(&device.id).copied() else {
+                return;
+            };
+            if let Err(e) = self.audio.set_muted(&device.id, muted) {
+                error!("Failed to restore capture device mute state: {e:?}");
+            }
+        });
+    }
+
+    /// Mutes capture endpoints that are live while privacy mode is on, remembering the state
+    /// each one had the first time it was seen. Covers devices plugged in after the mode was
+    /// enabled as well as anything unmuting itself.
+    fn enforce_privacy_mode(&mut self) {
+        let Some(privacy) = self.privacy.as_mut() else {
+            return;
+        };
+        if privacy
+            .last_scan
+            .is_some_and(|last_scan| last_scan.elapsed() < PrivacyMode::SCAN_INTERVAL)
+        {
+            return;
+        }
+        privacy.last_scan = Some(Instant::now());
+
+        let audio = &self.audio;
+        let devices = match audio.devices(Some(DataFlow::Capture), DeviceStateFilter::Active) {
+            Ok(devices) => devices,
+            Err(e) => {
+                error!("Failed to list capture devices: {e:?}");
+                privacy.all_muted = false;
+                return;
+            }
+        };
+
+        privacy.all_muted = devices.into_iter().fold(true, |all_muted, device| {
+            let Ok(muted) = audio.muted(&device.id) else {
+                return false;
+            };
+
+            privacy.previous.entry(device.id.clone()).or_insert(muted);
+            if muted {
+                return all_muted;
+            }
+
+            match audio.set_muted(&device.id, true) {
+                Ok(()) => all_muted,
+                Err(e) => {
+                    error!("Failed to mute capture device: {e:?}");
+                    false
+                }
+            }
+        });
+    }
+
+    fn get_output_devices(&self) -> Vec<AudioDeviceInfo> {
+        match self
+            .audio
+            .devices(Some(DataFlow::Render), DeviceStateFilter::Active)
+        {
+            Ok(devices) => devices,
+            Err(e) => {
+                error!("Failed to list output devices: {e:?}");
+                Vec::new()
+            }
+        }
+    }
+
+    fn get_device_infos(&self, states: DeviceStateFilter) -> Vec<AudioDeviceInfo> {
+        match self.audio.devices(None, states) {
+            Ok(devices) => devices,
+            Err(e) => {
+                error!("Failed to list audio devices: {e:?}");
+                Vec::new()
+            }
+        }
+    }
+
+    fn get_default_output_id(&self) -> Option<String> {
+        self.audio
+            .default_device(DataFlow::Render, EndpointRole::Multimedia)
+            .ok()
+            .map(|device| device.id)
+    }
+
+    /// Switches both the console and multimedia roles, matching what the Windows sound settings
+    /// do when picking an output device.
+    fn set_default_output(&self, device_id: &str) -> Result<(), AudioError> {
+        self.audio.make_default(device_id, EndpointRole::Console)?;
+        self.audio.make_default(device_id, EndpointRole::Multimedia)
+    }
+
+    /// Moves the default output to the next entry of `preferred_outputs`, skipping devices that
+    /// are currently unavailable. Returns the index of the selected entry.
+    fn cycle_output_device(&self) -> Option<usize> {
+        let count = self.preferred_outputs.len();
+        let current = self.get_default_output_id().and_then(|current| {
+            self.preferred_outputs
+                .iter()
+                .position(|device_id| *device_id == current)
+        });
+        let start = current.map_or(0, |index| index + 1);
+
+        (0..count)
+            .map(|offset| (start + offset) % count)
+            .find(|index| {
+                let device_id = &self.preferred_outputs[*index];
+                match self.set_default_output(device_id) {
+                    Ok(()) => true,
+                    Err(e) => {
+                        error!("Failed to switch output to {device_id}: {e:?}");
+                        false
+                    }
+                }
+            })
+    }
+
+    fn sample_level(&mut self) -> Option<u8> {
+        self.level_sampler.sample(
+            self.audio.as_ref(),
+            &self.output_endpoint,
+            &self.input_endpoint,
+        )
+    }
+
+    fn get_sessions(&self) -> Vec<AudioSessionInfo> {
+        self.audio.sessions().unwrap_or_else(|e| {
+            error!("Failed to list audio sessions: {e:?}");
+            Vec::new()
+        })
+    }
+
+    /// Sessions addressed by the record target at `index`. The foreground target also matches
+    /// sessions sharing the focused process' executable, since browsers and chat clients tend
+    /// to play audio from a helper process rather than the one owning the window.
+    fn get_target_sessions(&self, index: u8) -> Vec<AudioSessionInfo> {
+        let sessions = self.get_sessions();
+
+        match self.session_targets.get(index as usize) {
+            Some(SessionTarget::ForegroundApp) => {
+                let Some(process_id) = self.audio.foreground_process_id() else {
+                    return Vec::new();
+                };
+                let executable = self.audio.process_executable(process_id);
+
+                sessions
+                    .into_iter()
+                    .filter(|session| {
+                        session.process_id == process_id
+                            || executable
+                                .as_deref()
+                                .is_some_and(|executable| session.is_executable(executable))
+                    })
+                    .collect()
+            }
+            Some(SessionTarget::Executable(name)) => sessions
+                .into_iter()
+                .filter(|session| session.is_executable(name))
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    fn toggle_session_mute(&self, target: u8) {
+        let sessions = self.get_target_sessions(target);
+        let muted = sessions.iter().any(|session| !session.muted);
+
+        sessions.iter().for_each(|session| {
+            if let Err(e) = self.audio.set_session_muted(session.process_id, muted) {
+                error!("Failed to set session mute: {e:?}");
+            }
+        });
+    }
+
+    fn set_session_volume(&self, target: u8, percent: u8) {
+        self.get_target_sessions(target).iter().for_each(|session| {
+            if let Err(e) = self.audio.set_session_volume(session.process_id, percent) {
+                error!("Failed to set session volume: {e:?}");
+            }
+        });
+    }
+
+    fn process_request(&mut self, request: AudioRequest) -> Option<AudioResponse> {
+        match request {
+            AudioRequest::FetchSessions => Some(AudioResponse::FetchSessions(self.get_sessions())),
+            AudioRequest::SetSessionVolume { process_id, volume } => {
+                if let Err(e) = self.audio.set_session_volume(process_id, volume) {
+                    error!("Failed to set session volume: {e:?}");
+                }
+                Some(AudioResponse::FetchSessions(self.get_sessions()))
+            }
+            AudioRequest::SetSessionMute { process_id, muted } => {
+                if let Err(e) = self.audio.set_session_muted(process_id, muted) {
+                    error!("Failed to set session mute: {e:?}");
+                }
+                Some(AudioResponse::FetchSessions(self.get_sessions()))
+            }
+            AudioRequest::FetchSessionTargets => Some(AudioResponse::FetchSessionTargets(
+                self.session_targets.clone(),
+            )),
+            AudioRequest::SetSessionTargets(targets) => {
+                self.session_targets = targets;
+                Some(AudioResponse::FetchSessionTargets(
+                    self.session_targets.clone(),
+                ))
+            }
+            AudioRequest::FetchDevices(states) => {
+                Some(AudioResponse::FetchDevices(self.get_device_infos(states)))
+            }
+            AudioRequest::FetchOutputDevices => Some(self.output_devices_response()),
+            AudioRequest::FetchEndpoints => Some(self.endpoints_response()),
+            AudioRequest::SetEndpoints { output, input } => {
+                self.output_endpoint = output;
+                self.input_endpoint = input;
+                Some(self.endpoints_response())
+            }
+            AudioRequest::FetchVolumeLimits => {
+                Some(AudioResponse::FetchVolumeLimits(self.volume_limits.clone()))
+            }
+            AudioRequest::SetVolumeLimits(limits) => {
+                self.volume_limits = limits;
+                Some(AudioResponse::FetchVolumeLimits(self.volume_limits.clone()))
+            }
+            AudioRequest::FetchInputMode => Some(self.input_mode_response()),
+            AudioRequest::SetInputMode {
+                mode,
+                release_delay_ms,
+            } => {
+                if mode != self.input_mode && self.input_hold.is_some() {
+                    self.abort_input_hold();
+                }
+                self.input_mode = mode;
+                self.release_delay = Duration::from_millis(release_delay_ms as u64);
+                Some(self.input_mode_response())
+            }
+            AudioRequest::FetchPrivacyMode => {
+                Some(AudioResponse::FetchPrivacyMode(self.is_privacy_mode()))
+            }
+            AudioRequest::SetPrivacyMode(enabled) => {
+                self.set_privacy_mode(enabled);
+                Some(AudioResponse::FetchPrivacyMode(self.is_privacy_mode()))
+            }
+            AudioRequest::FetchLedMeterMode => Some(self.led_meter_mode_response()),
+            AudioRequest::SetLedMeterMode { mode, rate_hz } => {
+                self.level_sampler.set_mode(mode, rate_hz);
+                Some(self.led_meter_mode_response())
+            }
+            AudioRequest::SetDefaultOutput(device_id) => {
+                if let Err(e) = self.set_default_output(&device_id) {
+                    error!("Failed to set default output: {e:?}");
+                }
+                Some(self.output_devices_response())
+            }
+            AudioRequest::SetPreferredOutputs(device_ids) => {
+                self.preferred_outputs = device_ids;
+                Some(self.output_devices_response())
+            }
+        }
+    }
+
+    fn endpoints_response(&self) -> AudioResponse {
+        let active_devices = |data_flow| {
+            self.audio
+                .devices(Some(data_flow), DeviceStateFilter::Active)
+                .unwrap_or_default()
+        };
+
+        AudioResponse::FetchEndpoints {
+            output: self.output_endpoint.clone(),
+            input: self.input_endpoint.clone(),
+            outputs: active_devices(DataFlow::Render),
+            inputs: active_devices(DataFlow::Capture),
+        }
+    }
+
+    fn input_mode_response(&self) -> AudioResponse {
+        AudioResponse::FetchInputMode {
+            mode: self.input_mode,
+            release_delay_ms: self.release_delay.as_millis() as u16,
+        }
+    }
+
+    fn led_meter_mode_response(&self) -> AudioResponse {
+        AudioResponse::FetchLedMeterMode {
+            mode: self.level_sampler.mode,
+            rate_hz: self.level_sampler.rate_hz,
+        }
+    }
+
+    fn output_devices_response(&self) -> AudioResponse {
+        AudioResponse::FetchOutputDevices {
+            devices: self.get_output_devices(),
+            default: self.get_default_output_id(),
+            preferred: self.preferred_outputs.clone(),
+        }
     }
 }
 
+// Serials of the records the device thread sends on its own, so they stand out in logs
+const SERIAL_VOLUME_METER: u32 = 123;
+const SERIAL_LEVEL_METER: u32 = 124;
+const SERIAL_LIMIT_METER: u32 = 125;
+const SERIAL_BATTERY_FLASH: u32 = 126;
+// The keyboard answers under the serial after the request's, which tells our polls apart
+const SERIAL_BATTERY_POLL: u32 = 127;
+const SERIAL_OUTPUT_MUTE: u32 = 456;
+const SERIAL_INPUT_MUTE: u32 = 789;
+
 trait ApplicationState {}
 struct Connected {
     device: HidDeviceChannel,
+    battery: BatteryMonitor,
+    heartbeat: Heartbeat,
+    decode_errors: DecodeErrors,
+    writes: WriteQueue,
+    acks: Acks,
+    /// The last volume meter, re-sent when reconciling. Flashes and the level meter are left
+    /// out, they'd be stale by then.
+    volume_meter: Option<Record>,
 }
 struct Disconnected {
     error: Option<AppError>,
@@ -165,40 +853,74 @@ impl ApplicationState for Disconnected {}
 #[derive(Debug)]
 enum AppError {
     Write(WriteError),
+    /// Reading from the endpoint failed, e.g. because the keyboard was unplugged.
+    Read(ReadError),
     Connect(HidError),
+    /// The keyboard stopped answering pings while its endpoint stayed open.
+    HeartbeatLost {
+        missed: u32,
+    },
+    /// Too many reports in a short time failed to decode.
+    Garbled {
+        errors: u32,
+    },
 }
 
 impl Application<Disconnected> {
-    pub fn new(tx: Sender<Event>, rx: Receiver<Event>) -> Application<Disconnected> {
+    /// `events` should take [`Topic::DeviceCommand`], [`Topic::AudioRequest`] and
+    /// [`Topic::BindingsRequest`].
+    pub fn new(
+        bus: EventBus,
+        events: Subscription,
+        config: ConfigWatcher,
+        shutdown: Shutdown,
+    ) -> Application<Disconnected> {
         Application::<Disconnected> {
             volume_manager: Default::default(),
+            config,
+            bindings: Bindings::load(),
+            layer: 0,
             state: Default::default(),
-            tx,
-            rx,
+            events,
+            bus,
+            shutdown,
         }
     }
 
-    pub fn connect(
+    fn attach(
         self,
-        vendor_id: u16,
-        product_id: u16,
-        usage_page: u16,
-        usage: u16,
+        device: Result<HidDeviceChannel, HidError>,
     ) -> Result<Application<Connected>, Application<Disconnected>> {
-        match HidDeviceChannel::connect(vendor_id, product_id, usage_page, usage) {
+        match device {
             Ok(device) => Ok(Application::<Connected> {
                 volume_manager: self.volume_manager,
-                state: { Connected { device } },
-                tx: self.tx,
-                rx: self.rx,
+                config: self.config,
+                bindings: self.bindings,
+                layer: self.layer,
+                state: Connected {
+                    device,
+                    battery: BatteryMonitor::new(),
+                    heartbeat: Heartbeat::new(),
+                    decode_errors: DecodeErrors::new(),
+                    writes: WriteQueue::new(),
+                    acks: Acks::new(),
+                    volume_meter: None,
+                },
+                bus: self.bus,
+                events: self.events,
+                shutdown: self.shutdown,
             }),
             Err(error) => Err(Application::<Disconnected> {
                 volume_manager: self.volume_manager,
+                config: self.config,
+                bindings: self.bindings,
+                layer: self.layer,
                 state: Disconnected {
                     error: Some(AppError::Connect(error)),
                 },
-                tx: self.tx,
-                rx: self.rx,
+                bus: self.bus,
+                events: self.events,
+                shutdown: self.shutdown,
             }),
         }
     }
@@ -206,63 +928,311 @@ impl Application<Disconnected> {
 
 struct Application<S: ApplicationState = Disconnected> {
     volume_manager: VolumeManager,
+    config: ConfigWatcher,
+    bindings: Bindings,
+    /// Keyboard layer from the last `LayerChanged` record.
+    layer: u8,
     state: S,
-    tx: Sender<Event>,
-    rx: Receiver<Event>,
+    bus: EventBus,
+    /// Requests from the GUI, IPC clients and bindings.
+    events: Subscription,
+    /// Set from outside to make the device loops wind down.
+    shutdown: Shutdown,
+}
+
+impl<S: ApplicationState> Application<S> {
+    /// Answers every pending audio and bindings request, which don't need the keyboard, and
+    /// returns the records asked to be written to it.
+    fn handle_events(&mut self) -> Vec<Record> {
+        let mut records = Vec::new();
+        while let Some(event) = self.events.try_recv() {
+            match &*event {
+                Event::RecordToDevice(record) => records.push(*record),
+                Event::AudioRequest(request) => {
+                    let _span = debug_span!("audio_request", ?request).entered();
+                    if let Some(response) = self.volume_manager.process_request(request.clone()) {
+                        self.bus.publish(Event::AudioResponse(response));
+                    }
+                }
+                Event::BindingsRequest(request) => {
+                    let _span = debug_span!("bindings_request", ?request).entered();
+                    if let Some(response) =
+                        self.bindings.process_request(request.clone(), self.layer)
+                    {
+                        self.bus.publish(Event::BindingsResponse(response));
+                    }
+                }
+                _ => {} // Not subscribed to anything else
+            }
+        }
+
+        records
+    }
+
+    /// Picks up edits to the config and bindings files and passes them on to the GUI.
+    fn poll_config(&mut self) {
+        if let Some(config) = self.config.poll() {
+            if let Err(e) = logging::set_filter(config.log.level.as_deref()) {
+                warn!("Failed to change log level: {e}");
+            }
+            self.bus.publish(Event::ConfigChanged(config.clone()));
+        }
+
+        if self.bindings.poll() {
+            self.bus
+                .publish(Event::BindingsResponse(self.bindings.response(self.layer)));
+        }
+    }
 }
 
 impl Application<Connected> {
     fn process_record(&mut self, record: &Record) {
-        println!("DATA!: {:?}", record);
+        let _span = debug_span!("record", serial = record.serial).entered();
+        debug!(data = ?record.data, "Received record");
+        if let RecordData::Unknown { tag, .. } = record.data {
+            debug!(
+                tag,
+                "Record from newer firmware, leaving it to bindings and subscribers"
+            );
+        }
 
         match record.data {
             RecordData::Pong => {
-                self.send_record(Record::new(record.serial + 1, RecordData::BatteryRequest));
-                self.volume_manager
-                    .get_mute(Some(eRender), Some(eMultimedia))
-                    .inspect(|state| {
-                        let (hid, gui) = self.send_record(Record::new(
-                            record.serial + 2,
-                            RecordData::SetOutputMuteState(*state),
-                        ));
-                        hid.expect("Failed to send record to device");
-                        gui.expect("Failed to send record to gui");
-                    });
-                self.volume_manager
-                    .get_mute(Some(eCapture), Some(eCommunications))
-                    .and_then(|state| {
-                        Some(self.send_record(Record::new(
-                            record.serial + 3,
-                            RecordData::SetInputMuteState(state),
-                        )))
-                    });
+                let (first, stats) = self.state.heartbeat.pong();
+                if let Some(stats) = stats {
+                    self.bus.publish(Event::Heartbeat(stats));
+                }
+                if first {
+                    self.sync_device(record.serial);
+                }
+            }
+            RecordData::BatteryResponse { percent, .. } => {
+                let polled = record.serial == SERIAL_BATTERY_POLL.wrapping_add(1);
+                if let Some(level) = self
+                    .state
+                    .battery
+                    .update(percent, &self.config.config().battery)
+                {
+                    match level {
+                        BatteryLevel::Normal => info!(percent, "Battery recovered"),
+                        BatteryLevel::Low => warn!(percent, "Battery is low"),
+                        BatteryLevel::Critical => warn!(percent, "Battery is critical"),
+                    }
+                    self.bus.publish(Event::BatteryAlert { level, percent });
+                }
+
+                // Our own polls stay quiet, the meter is for answers someone asked to see
+                if !polled {
+                    self.send_record(Record::new(
+                        record.serial.wrapping_add(1),
+                        self.config.config().led.battery.meter(percent),
+                    ));
+                }
+            }
+            RecordData::Ack { serial } => self.state.acks.ack(serial),
+            RecordData::LayerChanged { layer } => {
+                self.layer = layer;
+                self.bus
+                    .publish(Event::BindingsResponse(self.bindings.response(layer)));
+                self.run_binding(record);
+            }
+            _ => self.run_binding(record),
+        }
+    }
+
+    /// Brings the keyboard up to date after it first answers on a connection.
+    fn sync_device(&mut self, serial: u32) {
+        self.send_record(Record::new(
+            serial.wrapping_add(1),
+            RecordData::BatteryRequest,
+        ));
+        self.send_mute_states(serial.wrapping_add(2));
+    }
+
+    /// Sends the system's mute states, which the keys' LEDs show.
+    fn send_mute_states(&mut self, serial: u32) {
+        if let Ok(state) = self.volume_manager.get_output_mute() {
+            self.send_record(Record::new(
+                serial,
+                RecordData::SetOutputMuteState { muted: state },
+            ));
+        }
+        if let Ok(state) = self.volume_manager.get_input_mute() {
+            self.send_record(Record::new(
+                serial.wrapping_add(1),
+                RecordData::SetInputMuteState { muted: state },
+            ));
+        }
+    }
+
+    fn run_binding(&mut self, record: &Record) {
+        let action = match self.bindings.resolve(&record.data, self.layer) {
+            Action::Default => match Action::built_in(&record.data) {
+                Some(action) => action,
+                None => return,
+            },
+            action => action,
+        };
+
+        match action {
+            Action::Default | Action::Ignore => {}
+            Action::ToggleOutputMute => {
+                let muted = match self.volume_manager.toggle_output_mute() {
+                    Ok(muted) => muted,
+                    Err(e) => {
+                        error!("Failed to toggle output mute: {e:?}");
+                        // Still answer with the real state so the key's LED doesn't lie
+                        match self.volume_manager.get_output_mute() {
+                            Ok(muted) => muted,
+                            Err(_) => return,
+                        }
+                    }
+                };
+
+                // Take in the new state now so the next refresh doesn't report it a second time
+                self.volume_manager.refresh();
+                self.send_record(Record::new(
+                    record.serial.wrapping_add(1),
+                    RecordData::SetOutputMuteState { muted },
+                ));
+            }
+            Action::ToggleInputMute | Action::InputKey { pressed: true }
+                if self.volume_manager.is_privacy_mode() =>
+            {
+                info!("Ignoring mic key, privacy mode is on");
+            }
+            Action::InputKey { pressed: true } => {
+                if let Err(e) = self.volume_manager.press_input_key() {
+                    error!("Failed to apply mic key press: {e:?}");
+                }
+            }
+            Action::InputKey { pressed: false } => {
+                self.volume_manager.release_input_key();
             }
-            RecordData::BatteryResponse { percent, .. } => {
-                let (hid, gui) = self.send_record(Record::new(
-                    record.serial + 1,
+            Action::ToggleInputMute => {
+                if let Err(e) = self.volume_manager.toggle_mic_mute() {
+                    error!("Failed to toggle mic mute: {e:?}");
+                }
+            }
+            Action::TogglePrivacyMode => {
+                let enabled = !self.volume_manager.is_privacy_mode();
+                self.volume_manager.set_privacy_mode(enabled);
+                self.bus
+                    .publish(Event::AudioResponse(AudioResponse::FetchPrivacyMode(
+                        enabled,
+                    )));
+            }
+            Action::SetVolume { percent } => {
+                if let Err(e) = self.volume_manager.set_output_volume(percent) {
+                    error!("Failed to set output volume: {e:?}");
+                }
+            }
+            Action::ToggleSessionMute { target } => {
+                self.volume_manager.toggle_session_mute(target);
+            }
+            Action::SetSessionVolume { target, percent } => {
+                self.volume_manager.set_session_volume(target, percent);
+            }
+            Action::CycleOutputDevice => {
+                let Some(index) = self.volume_manager.cycle_output_device() else {
+                    return;
+                };
+                let count = self.volume_manager.preferred_outputs.len();
+
+                // Pick up the new device's levels now, otherwise the next refresh reports its
+                // volume as a change and overwrites the index shown on the meter.
+                self.volume_manager.refresh();
+                self.send_record(Record::new(
+                    record.serial.wrapping_add(1),
                     RecordData::SetLedMeter {
-                        percent,
-                        danger_threshold: 2,
-                        warning_threshold: 6,
+                        percent: ((index + 1) * 100 / count) as u8,
+                        warning_threshold: 0,
+                        danger_threshold: 0,
                         invert: false,
-                        linger_time: 1000,
+                        linger_time: self.config.config().led.cycle_linger_ms,
                     },
                 ));
-                hid.expect("Failed to send record to device");
-                gui.expect("Failed to send record to gui");
+                if let Some(muted) = self.volume_manager.curr_mute {
+                    self.send_record(Record::new(
+                        record.serial.wrapping_add(2),
+                        RecordData::SetOutputMuteState { muted },
+                    ));
+                }
+            }
+            Action::SonarRedirect {
+                redirection,
+                device,
+            } => {
+                let request = Event::SonarRequest(SonarRequest::RedirectDevice {
+                    redirection,
+                    device,
+                });
+                if let Err(e) = self.bus.send(request) {
+                    error!("Failed to redirect Sonar: {e}");
+                }
+            }
+            Action::RunCommand { command, args } => {
+                if let Err(e) = std::process::Command::new(&command).args(&args).spawn() {
+                    error!("Failed to run {command}: {e:?}");
+                }
             }
-            RecordData::ToggleInputMute => {
-                self.volume_manager.toggle_mic_mute();
+            Action::SendRecord { record: data } => {
+                self.send_record(Record::new(record.serial.wrapping_add(1), data));
             }
-            _ => {}
         }
     }
 
-    fn send_record(&self, record: Record) -> (WriteResult, Result<(), SendError<Event>>) {
-        (
-            self.state.device.write_record(record),
-            self.tx.send(Event::RecordToDevice(record.clone())),
-        )
+    /// Queues `record` for the keyboard; [`Application::flush_writes`] sends it. Records that
+    /// [`RecordData::needs_ack`] are reliable.
+    fn send_record(&mut self, record: Record) {
+        self.queue_record(record, record.data.needs_ack());
+    }
+
+    /// Like [`Application::send_record`], but `reliable` picks whether the record is
+    /// retransmitted until the keyboard acks it.
+    fn queue_record(&mut self, record: Record, reliable: bool) {
+        self.state.writes.push(record, reliable);
+    }
+
+    /// Writes what the rate limit allows and tells subscribers about each record. Failures are
+    /// logged, and otherwise surface when the next ping fails.
+    fn flush_writes(&mut self) {
+        if self.state.acks.reconcile_due(&self.config.config().device) {
+            info!("Re-sending mute and LED states, the keyboard may have missed a record");
+            self.send_mute_states(0);
+            // In the level modes the sampler refreshes the meter many times a second anyway
+            let volume_mode = self.volume_manager.level_sampler.mode == LedMeterMode::Volume;
+            if let Some(volume_meter) = self.state.volume_meter.filter(|_| volume_mode) {
+                self.queue_record(volume_meter, true);
+            }
+        }
+
+        let device = &self.config.config().device;
+        if device.acks {
+            self.state
+                .acks
+                .overdue(device)
+                .into_iter()
+                .for_each(|record| {
+                    debug!(serial = record.serial, data = ?record.data, "Retransmitting");
+                    self.state.writes.push_retry(record);
+                });
+        }
+
+        while let Some((record, reliable)) = self.state.writes.pop(device.max_reports_per_s) {
+            let record = match device.acks && reliable {
+                true => self.state.acks.track(record, device),
+                false => record,
+            };
+            match self.state.device.write_record(record) {
+                Ok(_) => self.bus.publish(Event::RecordSentToDevice(record)),
+                Err(e) => error!("Failed to write {:?}: {e:?}", record.data),
+            }
+        }
+
+        if let Some(stats) = self.state.writes.report() {
+            self.bus.publish(Event::WriteQueue(stats));
+        }
     }
 
     pub fn before_read(&mut self) {
@@ -270,21 +1240,67 @@ impl Application<Connected> {
         let new_vol = manager.get_vol_if_changed();
         let new_mute = manager.get_mute_if_changed();
         let new_mic_mute = manager.get_mic_mute_if_changed();
+        let clamped_vol = manager.clamped_vol;
+
+        let battery = &self.config.config().battery;
+        if self.state.battery.poll_due(battery) {
+            self.send_record(Record::new(SERIAL_BATTERY_POLL, RecordData::BatteryRequest));
+        }
+        if self.state.battery.flash_due() {
+            let style = self.config.config().led.battery;
+            self.send_record(Record::new(
+                SERIAL_BATTERY_FLASH,
+                RecordData::SetLedMeter {
+                    percent: style.danger_threshold,
+                    warning_threshold: style.warning_threshold,
+                    danger_threshold: style.danger_threshold,
+                    invert: false,
+                    linger_time: BatteryMonitor::FLASH_INTERVAL.as_millis() as u16 / 2,
+                },
+            ));
+        }
+
+        if let Some(level) = self.volume_manager.sample_level() {
+            self.send_record(Record::new(
+                SERIAL_LEVEL_METER,
+                RecordData::SetLedMeter {
+                    percent: level,
+                    warning_threshold: 0,
+                    danger_threshold: 0,
+                    invert: false,
+                    linger_time: 0,
+                },
+            ));
+        }
 
         match new_vol {
+            // Flash the bar in its own colours when the limiter kicked in
+            _ if clamped_vol.is_some() => {
+                self.send_record(Record::new(
+                    SERIAL_LIMIT_METER,
+                    self.config
+                        .config()
+                        .led
+                        .limit
+                        .meter(clamped_vol.unwrap_or_default()),
+                ));
+            }
+            // The meter belongs to the level sampler in the level modes
+            _ if self.volume_manager.level_sampler.mode != LedMeterMode::Volume => {}
             None => {}
             Some(vol) => {
                 let led_meter_record = Record::new(
-                    123,
+                    SERIAL_VOLUME_METER,
                     RecordData::SetLedMeter {
                         percent: vol,
                         warning_threshold: 0,
                         danger_threshold: 0,
                         invert: false,
-                        linger_time: 1000,
+                        linger_time: self.config.config().led.volume_linger_ms,
                     },
                 );
 
+                self.state.volume_meter = Some(led_meter_record);
                 self.send_record(led_meter_record);
             }
         }
@@ -292,56 +1308,130 @@ impl Application<Connected> {
         match new_mute {
             None => {}
             Some(mute) => {
-                let (hid, gui) =
-                    self.send_record(Record::new(456, RecordData::SetOutputMuteState(mute)));
-                hid.expect("Failed to send new output mute value to device");
-                gui.expect("Failed to send new output mute value to gui");
+                self.send_record(Record::new(
+                    SERIAL_OUTPUT_MUTE,
+                    RecordData::SetOutputMuteState { muted: mute },
+                ));
             }
         }
 
         match new_mic_mute {
             None => {}
             Some(mute) => {
-                self.send_record(Record::new(789, RecordData::SetInputMuteState(mute)));
+                self.send_record(Record::new(
+                    SERIAL_INPUT_MUTE,
+                    RecordData::SetInputMuteState { muted: mute },
+                ));
             }
         }
     }
 
-    fn listen_for_data(&mut self) {
+    /// Handles traffic until shutdown or a read error, or fails once the keyboard stops
+    /// answering pings.
+    fn listen_for_data(&mut self) -> Result<(), AppError> {
         loop {
+            if self.shutdown.is_requested() {
+                return Ok(());
+            }
+
+            match self.state.heartbeat.poll(&self.config.config().device) {
+                Beat::Idle => {}
+                Beat::SendPing => {
+                    self.state
+                        .device
+                        .write_record(Record::new(0, RecordData::Ping))
+                        .map_err(AppError::Write)?;
+                    self.state.heartbeat.sent();
+                }
+                Beat::Lost { missed } => {
+                    warn!(missed, "The keyboard stopped answering pings");
+                    return Err(AppError::HeartbeatLost { missed });
+                }
+            }
+
+            self.poll_config();
             self.before_read();
+            self.flush_writes();
 
-            let response = match self.state.device.read_record(Some(10)) {
+            let read_timeout = self.config.config().device.read_timeout_ms as i32;
+            let response = match self.state.device.read_record(Some(read_timeout)) {
                 Ok(res) => res,
-                Err(err) => {
-                    eprintln!("Error!: {:?}", err);
-
-                    return;
+                Err(ReadError::Decode { error, bytes }) => {
+                    self.state.acks.doubt();
+                    let decode_errors = &mut self.state.decode_errors;
+                    let exceeded = decode_errors.exceeded(&self.config.config().device);
+                    warn!(
+                        ?bytes,
+                        total = decode_errors.total(),
+                        "Dropping a report that doesn't decode: {error}"
+                    );
+                    if exceeded {
+                        return Err(AppError::Garbled {
+                            errors: decode_errors.total(),
+                        });
+                    }
+                    continue;
                 }
+                Err(err) => return Err(AppError::Read(err)),
             };
 
             match response {
                 Some(res) => {
-                    self.tx
-                        .send(Event::RecordFromDevice(res.clone()))
-                        .expect("Failed to send record to gui");
+                    self.bus.publish(Event::RecordFromDevice(res));
                     self.process_record(&res);
                 }
                 None => {}
             }
 
-            match self.rx.try_recv() {
-                Ok(Event::RecordToDevice(record)) => {
-                    self.send_record(record);
-                }
-                Err(TryRecvError::Disconnected) => return,
-                _ => {} // No data or not interested
+            self.handle_events()
+                .into_iter()
+                .for_each(|record| self.send_record(record));
+        }
+    }
+
+    /// Leaves the keyboard dark, so no stale meter or mute state outlives the app.
+    fn clear_indicators(&self) {
+        [
+            RecordData::SetLedMeter {
+                percent: 0,
+                warning_threshold: 0,
+                danger_threshold: 0,
+                invert: false,
+                linger_time: 0,
+            },
+            RecordData::SetOutputMuteState { muted: false },
+            RecordData::SetInputMuteState { muted: false },
+        ]
+        .into_iter()
+        .for_each(|data| {
+            if let Err(e) = self.state.device.write_record(Record::new(0, data)) {
+                warn!("Failed to turn off LEDs: {e:?}");
             }
+        });
+    }
+
+    fn disconnect(mut self, error: Option<AppError>) -> Application<Disconnected> {
+        self.volume_manager.abort_input_hold();
+
+        Application::<Disconnected> {
+            volume_manager: self.volume_manager,
+            config: self.config,
+            bindings: self.bindings,
+            layer: self.layer,
+            state: Disconnected { error },
+            bus: self.bus,
+            events: self.events,
+            shutdown: self.shutdown,
         }
     }
 
     fn run(mut self) -> Application<Disconnected> {
         loop {
+            if self.shutdown.is_requested() {
+                self.clear_indicators();
+                return self.disconnect(None);
+            }
+
             let result = self
                 .state
                 .device
@@ -349,20 +1439,17 @@ impl Application<Connected> {
 
             match result {
                 Ok(size) => {
-                    println!("Wrote {size} bytes");
+                    debug!("Wrote {size} bytes");
+                    self.state.heartbeat.sent();
 
-                    self.listen_for_data();
+                    if let Err(e) = self.listen_for_data() {
+                        error!("Lost the keyboard: {e:?}");
+                        return self.disconnect(Some(e));
+                    }
                 }
                 Err(err) => {
-                    eprintln!("Error during write: {err:?}");
-                    return Application::<Disconnected> {
-                        volume_manager: self.volume_manager,
-                        state: Disconnected {
-                            error: Some(AppError::Write(err)),
-                        },
-                        tx: self.tx,
-                        rx: self.rx,
-                    };
+                    error!("Error during write: {err:?}");
+                    return self.disconnect(Some(AppError::Write(err)));
                 }
             }
         }
@@ -390,118 +1477,800 @@ pub(crate) enum SonarResponse {
     GetSonarUrl(String),
 }
 
+#[derive(Debug, Clone)]
+pub(crate) enum AudioRequest {
+    FetchSessions,
+    SetSessionVolume {
+        process_id: u32,
+        volume: u8,
+    },
+    SetSessionMute {
+        process_id: u32,
+        muted: bool,
+    },
+    FetchSessionTargets,
+    SetSessionTargets(Vec<SessionTarget>),
+    FetchDevices(DeviceStateFilter),
+    FetchOutputDevices,
+    SetDefaultOutput(String),
+    SetPreferredOutputs(Vec<String>),
+    FetchLedMeterMode,
+    SetLedMeterMode {
+        mode: LedMeterMode,
+        rate_hz: u8,
+    },
+    FetchPrivacyMode,
+    SetPrivacyMode(bool),
+    FetchVolumeLimits,
+    SetVolumeLimits(Vec<VolumeLimit>),
+    FetchInputMode,
+    SetInputMode {
+        mode: InputMode,
+        release_delay_ms: u16,
+    },
+    FetchEndpoints,
+    SetEndpoints {
+        output: EndpointSelector,
+        input: EndpointSelector,
+    },
+}
+
+#[derive(Debug)]
+pub(crate) enum AudioResponse {
+    FetchSessions(Vec<AudioSessionInfo>),
+    FetchSessionTargets(Vec<SessionTarget>),
+    FetchDevices(Vec<AudioDeviceInfo>),
+    FetchOutputDevices {
+        devices: Vec<AudioDeviceInfo>,
+        default: Option<String>,
+        preferred: Vec<String>,
+    },
+    FetchLedMeterMode {
+        mode: LedMeterMode,
+        rate_hz: u8,
+    },
+    FetchPrivacyMode(bool),
+    FetchEndpoints {
+        output: EndpointSelector,
+        input: EndpointSelector,
+        outputs: Vec<AudioDeviceInfo>,
+        inputs: Vec<AudioDeviceInfo>,
+    },
+    FetchInputMode {
+        mode: InputMode,
+        release_delay_ms: u16,
+    },
+    FetchVolumeLimits(Vec<VolumeLimit>),
+}
+
+#[derive(Debug, Clone)]
+pub(crate) enum BindingsRequest {
+    FetchBindings,
+    SetProfile(Option<String>),
+    Reload,
+}
+
+#[derive(Debug)]
+pub(crate) enum BindingsResponse {
+    FetchBindings {
+        path: Option<PathBuf>,
+        error: Option<String>,
+        profile: Option<String>,
+        profiles: Vec<String>,
+        layer: u8,
+        bindings: Vec<ActiveBinding>,
+    },
+}
+
 #[derive(Debug)]
 pub(crate) enum Event {
-    DeviceConnected,
+    DeviceConnected(DeviceInfo),
     DeviceDisconnected,
     RecordFromDevice(Record),
+    /// Round trips measured by the connection's heartbeat.
+    Heartbeat(HeartbeatStats),
+    /// Depth and throughput of the records waiting for the keyboard.
+    WriteQueue(WriteQueueStats),
+    /// The battery moved to another [`BatteryLevel`].
+    BatteryAlert {
+        level: BatteryLevel,
+        percent: u8,
+    },
+    /// Asks the device thread to write a record.
     RecordToDevice(Record),
+    /// A record the device thread has written, whoever asked for it.
+    RecordSentToDevice(Record),
     SonarRequest(SonarRequest),
     SonarResponse(SonarResponse),
+    AudioRequest(AudioRequest),
+    AudioResponse(AudioResponse),
+    BindingsRequest(BindingsRequest),
+    BindingsResponse(BindingsResponse),
+    HistoryRequest(HistoryQuery),
+    /// Entries matching a [`Event::HistoryRequest`], oldest first.
+    HistoryResponse(Vec<Entry>),
+    ConfigChanged(Config),
+    StateChanged(Vec<StateChange>),
 }
 
-async fn ss_comms(mut rx: UnboundedReceiver<Event>, gui_tx: Sender<Event>) {
-    let engine_client = SteelSeriesEngineClient::new_autodetect();
-    let new_client = crate::steelseries::api::sonar::Client::new(
-        engine_client
-            .get_subapp_url("sonar")
-            .await
-            .expect("Couldn't get sonar URL")
-            .as_str(),
-    );
+/// Finds Sonar through SteelSeries GG. `None` if GG or Sonar isn't running.
+async fn sonar_client(config: &SonarConfig) -> Option<crate::steelseries::api::sonar::Client> {
+    let url = match SteelSeriesEngineClient::new_autodetect(&config.core_props) {
+        Ok(engine_client) => engine_client.get_subapp_url("sonar").await,
+        Err(e) => Err(e),
+    };
+
+    match url {
+        Ok(Some(url)) => Some(crate::steelseries::api::sonar::Client::new(&url)),
+        Ok(None) => {
+            warn!("Sonar isn't running");
+            None
+        }
+        Err(e) => {
+            warn!("Couldn't reach SteelSeries GG: {e}");
+            None
+        }
+    }
+}
+
+/// Answers Sonar requests. A change to the `[sonar]` settings reconnects, and so does a request
+/// while Sonar is unreachable.
+async fn ss_comms(events: Subscription, bus: EventBus, mut config: SonarConfig) {
+    let mut client = sonar_client(&config).await;
 
     loop {
-        let event = rx.recv().await;
-        println!("Got event: {event:?}");
-        let response = match event {
-            Some(Event::SonarRequest(request)) => match request {
-                SonarRequest::FetchDevices => Some(SonarResponse::FetchDevices(
-                    new_client
-                        .list_audio_devices(None, None, None)
-                        .await
-                        .expect("idk")
-                        .to_owned(),
-                )),
-                SonarRequest::FetchClassicRedirections => {
-                    Some(SonarResponse::FetchClassicRedirections(
-                        new_client
-                            .list_classic_redirections()
-                            .await
-                            .expect("idk")
-                            .to_owned(),
-                    ))
-                }
-                SonarRequest::FetchDeviceVolume => Some(SonarResponse::FetchDeviceVolume(
-                    new_client
-                        .get_classic_volume_settings()
-                        .await
-                        .expect("idk vol")
-                        .to_owned(),
-                )),
+        let event = events.recv().await;
+        let request = match &*event {
+            Event::SonarRequest(request) => request,
+            Event::ConfigChanged(changed) if changed.sonar != config => {
+                info!("Sonar settings changed, reconnecting");
+                config = changed.sonar.clone();
+                client = sonar_client(&config).await;
+                continue;
+            }
+            _ => continue,
+        };
+
+        if client.is_none() {
+            client = sonar_client(&config).await;
+        }
+        let Some(new_client) = &client else {
+            warn!(?request, "Dropping Sonar request, Sonar is unreachable");
+            continue;
+        };
+
+        let span = info_span!("sonar_request", ?request);
+        let response = async {
+            debug!("Calling Sonar");
+            let response = match request {
+                SonarRequest::FetchDevices => new_client
+                    .list_audio_devices(None, None, None)
+                    .await
+                    .map(|devices| SonarResponse::FetchDevices(devices.to_owned()))
+                    .map_err(|e| format!("{e:?}")),
+                SonarRequest::FetchClassicRedirections => new_client
+                    .list_classic_redirections()
+                    .await
+                    .map(|redirections| {
+                        SonarResponse::FetchClassicRedirections(redirections.to_owned())
+                    })
+                    .map_err(|e| format!("{e:?}")),
+                SonarRequest::FetchDeviceVolume => new_client
+                    .get_classic_volume_settings()
+                    .await
+                    .map(|volume| SonarResponse::FetchDeviceVolume(volume.to_owned()))
+                    .map_err(|e| format!("{e:?}")),
                 SonarRequest::RedirectDevice {
                     device,
                     redirection,
-                } => Some(SonarResponse::RedirectDevice(
-                    new_client
-                        .set_classic_redirection_device(redirection, &*device)
-                        .await
-                        .expect("redirectdevice")
-                        .to_owned(),
-                )),
+                } => new_client
+                    .set_classic_redirection_device(*redirection, device)
+                    .await
+                    .map(|redirection| SonarResponse::RedirectDevice(redirection.to_owned()))
+                    .map_err(|e| format!("{e:?}")),
                 SonarRequest::GetSonarUrl => {
-                    Some(SonarResponse::GetSonarUrl(new_client.baseurl.clone()))
+                    Ok(SonarResponse::GetSonarUrl(new_client.baseurl.clone()))
                 }
-            },
-            _ => return,
-        };
+            };
+
+            // A failed call leaves the asker without an answer, like a timeout would
+            response
+                .inspect_err(|e| error!("Sonar request failed: {e}"))
+                .ok()
+        }
+        .instrument(span)
+        .await;
 
         if let Some(response) = response {
-            gui_tx
-                .send(Event::SonarResponse(response))
-                .expect("Failed to send response");
+            bus.publish(Event::SonarResponse(response));
         }
     }
 }
 
-fn main() -> ExitCode {
-    // Sending data to GUI
-    let (gui_tx, gui_rx) = mpsc::channel();
-    // Sending data to USB
-    let (usb_tx, usb_rx) = mpsc::channel();
-    let (ss_tx, ss_rx) = tokio::sync::mpsc::unbounded_channel();
+/// Exit status when the keyboard couldn't be found within `device.connect_retries` attempts.
+const EXIT_NO_DEVICE: u8 = 3;
 
-    let gui_tx_for_kbd = gui_tx.clone();
-    let thread = std::thread::spawn(move || {
-        let mut retry = 0;
-        let mut application = Application::new(gui_tx_for_kbd, usb_rx);
+/// Keeps the keyboard connected until shutdown is requested, opening it with `open` and
+/// reconnecting whenever the session drops.
+fn supervise(
+    mut application: Application<Disconnected>,
+    simulate: bool,
+    mut open: impl FnMut(&DeviceConfig) -> Result<HidDeviceChannel, HidError>,
+) -> ExitCode {
+    let mut retry = 0;
 
-        loop {
-            application = match application.connect(0x3434, 0x661, 0xFF60, 0x61) {
-                Ok(app) => app.run(),
-                Err(e) => {
-                    retry += 1;
-                    eprintln!("Error during connect: {:?}", e.state.error);
+    let _span = info_span!("device").entered();
+    loop {
+        if application.shutdown.is_requested() {
+            return ExitCode::SUCCESS;
+        }
 
-                    if retry > 100 {
-                        return ExitCode::FAILURE;
-                    }
-                    sleep(Duration::from_millis(100));
+        application.poll_config();
+        let device = application.config.config().device.clone();
+
+        let connected = info_span!("connect", attempt = retry + 1, simulate)
+            .in_scope(|| application.attach(open(&device)));
+
+        application = match connected {
+            Ok(app) => {
+                info!("Connected");
+                app.bus.publish(Event::DeviceConnected(DeviceInfo {
+                    vendor_id: device.vendor_id,
+                    product_id: device.product_id,
+                    simulated: simulate,
+                }));
+                let app = app.run();
+                app.bus.publish(Event::DeviceDisconnected);
+                app
+            }
+            Err(mut e) => {
+                retry += 1;
+                warn!("Error during connect: {:?}", e.state.error);
+
+                // Records meant for the keyboard would be stale by the time it's back
+                let dropped = e.handle_events().len();
+                if dropped > 0 {
+                    debug!(dropped, "Dropping records sent while disconnected");
+                }
 
-                    e
+                if device.connect_retries != 0 && retry > device.connect_retries {
+                    error!("Giving up on the keyboard after {retry} attempts");
+                    return ExitCode::from(EXIT_NO_DEVICE);
                 }
+                sleep(Duration::from_millis(device.retry_interval_ms));
+
+                e
             }
         }
+    }
+}
+
+fn main() -> ExitCode {
+    let options = match Options::parse(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{e}\n\n{USAGE}");
+            return ExitCode::from(2);
+        }
+    };
+
+    let log_path = options.log_path();
+    let _log_guard = match logging::init(log_path.as_deref(), options.log_to_stderr()) {
+        Ok(guard) => guard,
+        Err(e) => {
+            eprintln!("Failed to set up logging: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    if let Some(path) = &log_path {
+        info!("Logging to {}", path.display());
+    }
+
+    let config = ConfigWatcher::load();
+    if let Some(level) = &config.config().log.level {
+        if let Err(e) = logging::set_filter(Some(level)) {
+            warn!("Failed to change log level: {e}");
+        }
+    }
+    if !matches!(options.command, Command::Run) {
+        return commands::run(&options, config.config());
+    }
+
+    let bus = EventBus::default();
+    let state = StateStore::start(bus.clone());
+    History::start_recording(&bus, config.config().history.clone());
+
+    let gui_config = config.config().clone();
+    let sonar_config = config.config().sonar.clone();
+
+    let ipc = &config.config().ipc;
+    if ipc.enabled {
+        if let Err(e) = IpcServer::start(ipc, bus.clone(), state.clone()) {
+            warn!("Failed to start the IPC server: {e}");
+        }
+    }
+
+    // Subscribe before anything runs, so no early event is missed
+    let ui_events = match options.headless {
+        true => bus.subscribe("headless", &Topic::ALL),
+        false => bus.subscribe("gui", &gui::TOPICS),
+    };
+    let device_events = bus.subscribe(
+        "device",
+        &[
+            Topic::DeviceCommand,
+            Topic::AudioRequest,
+            Topic::BindingsRequest,
+        ],
+    );
+    let sonar_requests = bus.subscribe("sonar", &[Topic::SonarRequest, Topic::Config]);
+    let shutdown = Shutdown::default();
+    let shutdown_on_signal = shutdown.clone();
+    if let Err(e) = ctrlc::set_handler(move || shutdown_on_signal.request()) {
+        warn!("Failed to install signal handler: {e}");
+    }
+
+    let bus_for_kbd = bus.clone();
+    let shutdown_for_kbd = shutdown.clone();
+    let simulate = options.simulate;
+    let thread = std::thread::spawn(move || {
+        let application = Application::new(bus_for_kbd, device_events, config, shutdown_for_kbd);
+        supervise(application, simulate, |device| match simulate {
+            true => Ok(HidDeviceChannel::simulated()),
+            false => HidDeviceChannel::connect(
+                device.vendor_id,
+                device.product_id,
+                device.usage_page,
+                device.usage,
+            ),
+        })
     });
 
+    let bus_for_sonar = bus.clone();
+    let shutdown_for_sonar = shutdown.clone();
     let thread2 = std::thread::spawn(move || {
         let tokio = tokio::runtime::Runtime::new().unwrap();
-        tokio.block_on(ss_comms(ss_rx, gui_tx));
+        tokio.block_on(async {
+            // Sonar calls can hang, so don't wait for the current one to finish
+            tokio::select! {
+                _ = ss_comms(sonar_requests, bus_for_sonar, sonar_config) => {}
+                _ = shutdown_for_sonar.wait() => {}
+            }
+        });
+    });
+
+    let ui_succeeded = if options.headless {
+        info!("Running headless");
+        run_headless(ui_events, &shutdown, &thread);
+        true
+    } else {
+        match init_gui(ui_events, bus, &state, gui_config, shutdown.clone()) {
+            Ok(()) => true,
+            Err(e) => {
+                error!("The window failed: {e}");
+                false
+            }
+        }
+    };
+
+    info!("Shutting down");
+    shutdown.request();
+    let exit_code = thread.join().unwrap_or_else(|_| {
+        error!("Device thread panicked");
+        ExitCode::FAILURE
     });
+    if thread2.join().is_err() {
+        warn!("Sonar task panicked");
+    }
+
+    match ui_succeeded {
+        true => exit_code,
+        false => ExitCode::FAILURE,
+    }
+}
+
+/// Stands in for the GUI: logs what would have been shown, until Ctrl+C, SIGTERM or the device
+/// thread giving up.
+fn run_headless(events: Subscription, shutdown: &Shutdown, device_thread: &JoinHandle<ExitCode>) {
+    while !shutdown.is_requested() && !device_thread.is_finished() {
+        if let Some(event) = events.recv_timeout(Duration::from_millis(100)) {
+            debug!("{event:?}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hid_device_channel::FakeTransport;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn device(id: &str, name: &str) -> AudioDeviceInfo {
+        AudioDeviceInfo {
+            id: id.to_string(),
+            name: name.to_string(),
+            state: None,
+            data_flow: None,
+            form_factor: audio::FormFactor::Unknown,
+        }
+    }
+
+    #[test]
+    fn glob_matches_wildcards() {
+        assert!(glob_matches("speakers", "speakers"));
+        assert!(!glob_matches("speakers", "speakers 2"));
+        assert!(glob_matches("head*", "headphones"));
+        assert!(!glob_matches("head*", "my headphones"));
+        assert!(glob_matches("*phones", "headphones"));
+        assert!(glob_matches("*", ""));
+        assert!(glob_matches("a*b*c", "a-b-c"));
+        assert!(glob_matches("a*b*c", "abc"));
+        assert!(!glob_matches("a*b*c", "a-c-b"));
+        // Parts may not overlap
+        assert!(!glob_matches("ab*ba", "aba"));
+    }
+
+    #[test]
+    fn device_matcher_by_id_and_name() {
+        let headset = device("{0.0.0.00000000}.{1}", "Arctis Nova (USB Audio)");
+
+        assert!(DeviceMatcher::Id("{0.0.0.00000000}.{1}".to_string()).matches(&headset));
+        assert!(!DeviceMatcher::Id("{0.0.0.00000000}.{2}".to_string()).matches(&headset));
+        // Without a wildcard the pattern matches anywhere, ignoring case
+        assert!(DeviceMatcher::NamePattern("nova".to_string()).matches(&headset));
+        assert!(DeviceMatcher::NamePattern("ARCTIS*USB*".to_string()).matches(&headset));
+        assert!(!DeviceMatcher::NamePattern("usb*".to_string()).matches(&headset));
+        assert!(!DeviceMatcher::NamePattern("speakers".to_string()).matches(&headset));
+    }
+
+    #[derive(Default)]
+    struct FakeAudioState {
+        volume: u8,
+        output_muted: bool,
+        input_muted: bool,
+    }
+
+    /// One output and one input, both always the default.
+    #[derive(Clone, Default)]
+    struct FakeAudio(Rc<RefCell<FakeAudioState>>);
+
+    impl FakeAudio {
+        const OUTPUT: &str = "output";
+        const INPUT: &str = "input";
+    }
+
+    impl AudioBackend for FakeAudio {
+        fn devices(
+            &self,
+            data_flow: Option<DataFlow>,
+            _states: DeviceStateFilter,
+        ) -> audio::AudioResult<Vec<AudioDeviceInfo>> {
+            Ok([
+                (DataFlow::Render, device(Self::OUTPUT, "Speakers")),
+                (DataFlow::Capture, device(Self::INPUT, "Microphone")),
+            ]
+            .into_iter()
+            .filter(|(flow, _)| data_flow.is_none_or(|data_flow| data_flow == *flow))
+            .map(|(_, device)| device)
+            .collect())
+        }
+
+        fn default_device(
+            &self,
+            data_flow: DataFlow,
+            _role: EndpointRole,
+        ) -> audio::AudioResult<AudioDeviceInfo> {
+            Ok(match data_flow {
+                DataFlow::Capture => device(Self::INPUT, "Microphone"),
+                _ => device(Self::OUTPUT, "Speakers"),
+            })
+        }
+
+        fn make_default(&self, _device_id: &str, _role: EndpointRole) -> audio::AudioResult<()> {
+            Ok(())
+        }
+
+        fn endpoint(
+            &self,
+            data_flow: DataFlow,
+            _selector: &EndpointSelector,
+        ) -> audio::AudioResult<AudioDeviceInfo> {
+            self.default_device(data_flow, EndpointRole::Multimedia)
+        }
+
+        fn volume(&self, _device_id: &str) -> audio::AudioResult<u8> {
+            Ok(self.0.borrow().volume)
+        }
+
+        fn set_volume(&self, _device_id: &str, percent: u8) -> audio::AudioResult<()> {
+            self.0.borrow_mut().volume = percent;
+            Ok(())
+        }
+
+        fn muted(&self, device_id: &str) -> audio::AudioResult<bool> {
+            let state = self.0.borrow();
+            Ok(match device_id {
+                Self::INPUT => state.input_muted,
+                _ => state.output_muted,
+            })
+        }
+
+        fn set_muted(&self, device_id: &str, muted: bool) -> audio::AudioResult<()> {
+            let mut state = self.0.borrow_mut();
+            match device_id {
+                Self::INPUT => state.input_muted = muted,
+                _ => state.output_muted = muted,
+            }
+            Ok(())
+        }
+
+        fn peak(&self, _device_id: &str) -> audio::AudioResult<u8> {
+            Ok(0)
+        }
 
-    init_gui(gui_rx, usb_tx, ss_tx).expect("wat");
+        fn sessions(&self) -> audio::AudioResult<Vec<AudioSessionInfo>> {
+            Ok(Vec::new())
+        }
+
+        fn set_session_volume(&self, _process_id: u32, _percent: u8) -> audio::AudioResult<()> {
+            Ok(())
+        }
+
+        fn set_session_muted(&self, _process_id: u32, _muted: bool) -> audio::AudioResult<()> {
+            Ok(())
+        }
+
+        fn foreground_process_id(&self) -> Option<u32> {
+            None
+        }
+
+        fn process_executable(&self, _process_id: u32) -> Option<String> {
+            None
+        }
+    }
+
+    fn disconnected(audio: FakeAudio, config: Config) -> Application<Disconnected> {
+        let bus = EventBus::default();
+        Application::<Disconnected> {
+            volume_manager: VolumeManager::new(Box::new(audio)),
+            config: ConfigWatcher::fixed(config),
+            bindings: Bindings::default(),
+            layer: 0,
+            state: Disconnected::default(),
+            events: bus.subscribe("device", &[Topic::DeviceCommand]),
+            bus,
+            shutdown: Shutdown::default(),
+        }
+    }
+
+    fn connected(audio: FakeAudio, transport: FakeTransport) -> Application<Connected> {
+        let device = HidDeviceChannel::from_transport(Box::new(transport));
+        let Ok(app) = disconnected(audio, Config::default()).attach(Ok(device)) else {
+            unreachable!("attaching an open channel can't fail");
+        };
+        app
+    }
+
+    fn leds_off() -> Vec<Record> {
+        [
+            RecordData::SetLedMeter {
+                percent: 0,
+                warning_threshold: 0,
+                danger_threshold: 0,
+                invert: false,
+                linger_time: 0,
+            },
+            RecordData::SetOutputMuteState { muted: false },
+            RecordData::SetInputMuteState { muted: false },
+        ]
+        .into_iter()
+        .map(|data| Record::new(0, data))
+        .collect()
+    }
+
+    #[test]
+    fn toggle_output_mute_flips_backend_and_reports_state() {
+        let audio = FakeAudio::default();
+        let mut app = connected(audio.clone(), FakeTransport::default());
+
+        app.process_record(&Record::new(7, RecordData::ToggleOutputMute));
+        assert!(audio.0.borrow().output_muted);
+        assert_eq!(
+            app.state.writes.pop(u32::MAX),
+            Some((
+                Record::new(8, RecordData::SetOutputMuteState { muted: true }),
+                true
+            ))
+        );
+
+        app.process_record(&Record::new(9, RecordData::ToggleOutputMute));
+        assert!(!audio.0.borrow().output_muted);
+        assert_eq!(
+            app.state.writes.pop(u32::MAX),
+            Some((
+                Record::new(10, RecordData::SetOutputMuteState { muted: false }),
+                true
+            ))
+        );
+    }
+
+    #[test]
+    fn battery_meter_lights_up_for_requests_but_not_polls() {
+        let mut app = connected(FakeAudio::default(), FakeTransport::default());
+        let response = RecordData::BatteryResponse {
+            percent: 50,
+            voltage: 3750,
+        };
+
+        app.process_record(&Record::new(SERIAL_BATTERY_POLL.wrapping_add(1), response));
+        assert_eq!(app.state.writes.pop(u32::MAX), None);
+
+        app.process_record(&Record::new(1, response));
+        assert_eq!(
+            app.state.writes.pop(u32::MAX),
+            Some((
+                Record::new(2, app.config.config().led.battery.meter(50)),
+                false
+            ))
+        );
+    }
+
+    #[test]
+    fn random_records_are_processed_without_panicking() {
+        let mut rng = StdRng::seed_from_u64(0x3434_0661);
+        let transport = FakeTransport::default();
+        let reader = HidDeviceChannel::from_transport(Box::new(transport.clone()));
+        let mut app = connected(FakeAudio::default(), FakeTransport::default());
+
+        for _ in 0..10_000 {
+            let mut report = [0u8; 32];
+            rng.fill(&mut report[..]);
+            let tag: u32 = rng.gen_range(0..=RecordData::first_unknown_tag());
+            report[4..8].copy_from_slice(&tag.to_le_bytes());
+            // Replies count up from the serial they answer
+            if rng.gen_bool(0.1) {
+                report[..4].copy_from_slice(&u32::MAX.to_le_bytes());
+            }
+            transport.reads.borrow_mut().push_back(Ok(report.to_vec()));
 
-    thread2.join().unwrap();
-    thread.join().unwrap();
+            if let Ok(Some(record)) = reader.read_record(None) {
+                app.process_record(&record);
+            }
+        }
+    }
+
+    /// Connected over `transport` with acks on, reconciling whenever there's doubt.
+    fn reconciling(audio: FakeAudio, transport: FakeTransport) -> Application<Connected> {
+        let mut config = Config::default();
+        config.device.acks = true;
+        config.device.reconcile_interval_s = 0;
+        config.device.max_reports_per_s = u32::MAX;
+        let device = HidDeviceChannel::from_transport(Box::new(transport));
+        let Ok(app) = disconnected(audio, config).attach(Ok(device)) else {
+            unreachable!("attaching an open channel can't fail");
+        };
+        app
+    }
+
+    /// Moves the volume to `percent` and returns the meter that shows it.
+    fn change_volume(
+        app: &mut Application<Connected>,
+        audio: &FakeAudio,
+        percent: u8,
+    ) -> RecordData {
+        app.before_read();
+        audio.0.borrow_mut().volume = percent;
+        app.before_read();
+
+        RecordData::SetLedMeter {
+            percent,
+            warning_threshold: 0,
+            danger_threshold: 0,
+            invert: false,
+            linger_time: app.config.config().led.volume_linger_ms,
+        }
+    }
+
+    /// What reconciling writes, after everything queued so far went out.
+    fn reconcile(app: &mut Application<Connected>, transport: &FakeTransport) -> Vec<Record> {
+        app.flush_writes();
+        transport.writes.borrow_mut().clear();
+
+        app.state.acks.doubt();
+        app.flush_writes();
+        transport.written_records()
+    }
+
+    #[test]
+    fn reconciling_resends_mute_states_and_the_led_meter_reliably() {
+        let audio = FakeAudio::default();
+        let transport = FakeTransport::default();
+        let mut app = reconciling(audio.clone(), transport.clone());
+        let volume_meter = change_volume(&mut app, &audio, 40);
+
+        let written = reconcile(&mut app, &transport);
+        assert_eq!(
+            written.iter().map(|record| record.data).collect::<Vec<_>>(),
+            [
+                RecordData::SetOutputMuteState { muted: false },
+                RecordData::SetInputMuteState { muted: false },
+                volume_meter,
+            ]
+        );
+        // Tracked records are written under serials of their own
+        assert!(written.iter().all(|record| record.serial >= 1 << 24));
+    }
 
-    ExitCode::SUCCESS
+    #[test]
+    fn reconciling_leaves_out_transient_meters() {
+        let audio = FakeAudio::default();
+        let transport = FakeTransport::default();
+        let mut app = reconciling(audio.clone(), transport.clone());
+        let volume_meter = change_volume(&mut app, &audio, 40);
+        let flash = RecordData::SetLedMeter {
+            percent: 5,
+            warning_threshold: 20,
+            danger_threshold: 5,
+            invert: false,
+            linger_time: 500,
+        };
+        app.send_record(Record::new(SERIAL_BATTERY_FLASH, flash));
+        app.send_record(Record::new(SERIAL_LEVEL_METER, flash));
+
+        let written = reconcile(&mut app, &transport);
+        assert_eq!(written.last().map(|record| record.data), Some(volume_meter));
+        assert!(!written.iter().any(|record| record.data == flash));
+    }
+
+    #[test]
+    fn shutdown_turns_leds_off_and_exits_cleanly() {
+        let transport = FakeTransport::default();
+        let app = disconnected(FakeAudio::default(), Config::default());
+        let shutdown = app.shutdown.clone();
+
+        let exit_code = supervise(app, false, |_| {
+            shutdown.request();
+            Ok(HidDeviceChannel::from_transport(Box::new(
+                transport.clone(),
+            )))
+        });
+
+        assert_eq!(exit_code, ExitCode::SUCCESS);
+        assert_eq!(transport.written_records(), leds_off());
+    }
+
+    #[test]
+    fn read_error_drops_the_session() {
+        let transport = FakeTransport::default();
+        transport
+            .reads
+            .borrow_mut()
+            .push_back(Err(HidError::InitializationError));
+
+        let app = connected(FakeAudio::default(), transport).run();
+        assert!(matches!(
+            app.state.error,
+            Some(AppError::Read(ReadError::Hid(_)))
+        ));
+    }
+
+    #[test]
+    fn supervisor_gives_up_after_connect_retries() {
+        let mut config = Config::default();
+        config.device.connect_retries = 2;
+        config.device.retry_interval_ms = 0;
+        let app = disconnected(FakeAudio::default(), config);
+
+        let mut attempts = 0;
+        let exit_code = supervise(app, false, |_| {
+            attempts += 1;
+            Err(HidError::InitializationError)
+        });
+
+        assert_eq!(exit_code, ExitCode::from(EXIT_NO_DEVICE));
+        assert_eq!(attempts, 3);
+    }
 }
@@ -1,6 +1,13 @@
-use bincode::{Decode, Encode};
+use bincode::config::legacy;
+use bincode::{encode_to_vec, Decode, Encode};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::sync::OnceLock;
 
-#[derive(Encode, Decode, PartialEq, Debug, Copy, Clone)]
+/// The JSON form is `{"serial": 1, "data": {"type": "set-led-meter", "percent": 50, ...}}`.
+/// Variants are tagged by their kebab-case name and always use named fields, so tooling in
+/// other languages can build records from `misc/record.schema.json`.
+#[derive(Encode, Decode, Serialize, Deserialize, JsonSchema, PartialEq, Debug, Copy, Clone)]
 #[repr(C)]
 pub(crate) struct Record {
     pub(crate) serial: u32,
@@ -13,7 +20,10 @@ impl Record {
     }
 }
 
-#[derive(Encode, Decode, PartialEq, Debug, Copy, Clone)]
+// The wire format is positional, so variants and fields must only ever be appended, just before
+// `Unknown`. Named fields encode exactly like tuple fields.
+#[derive(Encode, Decode, Serialize, Deserialize, JsonSchema, PartialEq, Debug, Copy, Clone)]
+#[serde(tag = "type", rename_all = "kebab-case")]
 pub(crate) enum RecordData {
     Empty,
     Ping,
@@ -30,13 +40,73 @@ pub(crate) enum RecordData {
         invert: bool,
         linger_time: u16,
     },
-    SetOutputMuteState(bool),
-    SetInputMuteState(bool),
+    SetOutputMuteState {
+        muted: bool,
+    },
+    SetInputMuteState {
+        muted: bool,
+    },
     ToggleOutputMute,
     ToggleInputMute,
+    ToggleSessionMute {
+        target: u8,
+    },
+    SetSessionVolume {
+        target: u8,
+        percent: u8,
+    },
+    CycleOutputDevice,
+    TogglePrivacyMode,
+    InputMuteKeyPressed,
+    InputMuteKeyReleased,
+    KeyEvent {
+        keycode: u16,
+        pressed: bool,
+    },
+    LayerChanged {
+        layer: u8,
+    },
+    /// Sent by firmware that confirms records, for the record with this serial.
+    Ack {
+        serial: u32,
+    },
+    /// A variant this build doesn't know, e.g. from newer firmware. Stands for every tag from its
+    /// own index on, so it must stay last; it goes over the wire as `tag` followed by `bytes`.
+    Unknown {
+        tag: u32,
+        bytes: [u8; RecordData::PAYLOAD_SIZE],
+    },
 }
 
 impl RecordData {
+    /// What follows the serial and the variant tag in a 32 byte report.
+    pub(crate) const PAYLOAD_SIZE: usize = 24;
+
+    /// The tag `Unknown` has in the derived encoding, the first one this build doesn't know.
+    pub(crate) fn first_unknown_tag() -> u32 {
+        static TAG: OnceLock<u32> = OnceLock::new();
+
+        *TAG.get_or_init(|| {
+            let unknown = RecordData::Unknown {
+                tag: 0,
+                bytes: [0; Self::PAYLOAD_SIZE],
+            };
+            let encoded =
+                encode_to_vec(unknown, legacy()).expect("Failed to encode an unknown record");
+            u32::from_le_bytes(encoded[..4].try_into().unwrap())
+        })
+    }
+
+    /// Whether the record carries state the host must know arrived. These are sent reliably by
+    /// default: with `device.acks` on, they're retransmitted until the keyboard acknowledges
+    /// them.
+    pub(crate) fn needs_ack(&self) -> bool {
+        matches!(
+            self,
+            RecordData::SetOutputMuteState { .. } | RecordData::SetInputMuteState { .. }
+        )
+    }
+
     fn set_led_meter_no_threshold(percent: u8) -> Self {
         Self::SetLedMeter {
             percent,
@@ -47,3 +117,108 @@ impl RecordData {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bincode::decode_from_slice;
+    use std::collections::BTreeSet;
+
+    /// One of each variant, with fields away from their defaults.
+    fn samples() -> Vec<RecordData> {
+        vec![
+            RecordData::Empty,
+            RecordData::Ping,
+            RecordData::Pong,
+            RecordData::BatteryRequest,
+            RecordData::BatteryResponse {
+                percent: 87,
+                voltage: 3912,
+            },
+            RecordData::SetLedMeter {
+                percent: 40,
+                warning_threshold: 60,
+                danger_threshold: 80,
+                invert: true,
+                linger_time: 1500,
+            },
+            RecordData::SetOutputMuteState { muted: true },
+            RecordData::SetInputMuteState { muted: true },
+            RecordData::ToggleOutputMute,
+            RecordData::ToggleInputMute,
+            RecordData::ToggleSessionMute { target: 2 },
+            RecordData::SetSessionVolume {
+                target: 1,
+                percent: 35,
+            },
+            RecordData::CycleOutputDevice,
+            RecordData::TogglePrivacyMode,
+            RecordData::InputMuteKeyPressed,
+            RecordData::InputMuteKeyReleased,
+            RecordData::KeyEvent {
+                keycode: 0x1E,
+                pressed: true,
+            },
+            RecordData::LayerChanged { layer: 3 },
+            RecordData::Ack {
+                serial: 0xDEAD_BEEF,
+            },
+            RecordData::Unknown {
+                tag: 42,
+                bytes: std::array::from_fn(|i| i as u8 + 1),
+            },
+        ]
+    }
+
+    fn bincode_tag(data: &RecordData) -> u32 {
+        let encoded = encode_to_vec(data, legacy()).unwrap();
+        u32::from_le_bytes(encoded[..4].try_into().unwrap())
+    }
+
+    #[test]
+    fn samples_cover_every_variant() {
+        let tags: BTreeSet<u32> = samples().iter().map(bincode_tag).collect();
+        let expected: BTreeSet<u32> = (0..=RecordData::first_unknown_tag()).collect();
+        assert_eq!(tags, expected, "add new variants to samples()");
+    }
+
+    #[test]
+    fn json_round_trip() {
+        for (serial, data) in samples().into_iter().enumerate() {
+            let record = Record::new(serial as u32, data);
+            let json = serde_json::to_string(&record).unwrap();
+            let decoded: Record = serde_json::from_str(&json).unwrap();
+            assert_eq!(decoded, record, "{json}");
+        }
+    }
+
+    #[test]
+    fn bincode_round_trip() {
+        for (serial, data) in samples().into_iter().enumerate() {
+            let record = Record::new(serial as u32, data);
+            let encoded = encode_to_vec(record, legacy()).unwrap();
+            // `Unknown` goes over the wire in its own form, the rest must fit a report
+            if !matches!(record.data, RecordData::Unknown { .. }) {
+                assert!(
+                    encoded.len() <= 32,
+                    "{record:?} takes {} bytes",
+                    encoded.len()
+                );
+            }
+            let (decoded, size): (Record, _) = decode_from_slice(&encoded, legacy()).unwrap();
+            assert_eq!(decoded, record);
+            assert_eq!(size, encoded.len());
+        }
+    }
+
+    #[test]
+    fn checked_in_schema_is_current() {
+        let current = serde_json::to_value(schemars::schema_for!(Record)).unwrap();
+        let checked_in: serde_json::Value =
+            serde_json::from_str(include_str!("../misc/record.schema.json")).unwrap();
+        assert_eq!(
+            checked_in, current,
+            "misc/record.schema.json is stale, regenerate it with `kbd-companion schema`"
+        );
+    }
+}
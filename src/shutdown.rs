@@ -0,0 +1,33 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::sync::Notify;
+
+/// Shared flag that tells every part of the app to wind down. The device loop polls it, the
+/// Sonar task awaits it and the GUI closes its window once it is set.
+#[derive(Clone, Default)]
+pub(crate) struct Shutdown {
+    requested: Arc<AtomicBool>,
+    notify: Arc<Notify>,
+}
+
+impl Shutdown {
+    pub(crate) fn request(&self) {
+        if !self.requested.swap(true, Ordering::SeqCst) {
+            self.notify.notify_waiters();
+        }
+    }
+
+    pub(crate) fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+
+    /// Resolves once [`Shutdown::request`] has been called, immediately if it already was.
+    pub(crate) async fn wait(&self) {
+        let notified = self.notify.notified();
+        if self.is_requested() {
+            return;
+        }
+
+        notified.await;
+    }
+}
@@ -0,0 +1,78 @@
+use crate::hid_device_channel::HidTransport;
+use crate::record::{Record, RecordData};
+use bincode::config::legacy;
+use bincode::{decode_from_slice, encode_into_slice};
+use hidapi::HidError;
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::thread::sleep;
+use std::time::Duration;
+use tracing::debug;
+
+/// Stand-in for the keyboard when running without hardware. Answers pings and battery
+/// requests like the firmware does, acknowledges records that need it and accepts every other
+/// record.
+pub(crate) struct SimulatedDevice {
+    pending: RefCell<VecDeque<Record>>,
+    battery: Cell<u8>,
+}
+
+impl SimulatedDevice {
+    pub(crate) fn new() -> Self {
+        Self {
+            pending: RefCell::new(VecDeque::new()),
+            battery: Cell::new(100),
+        }
+    }
+
+    fn reply(&self, record: &Record) -> Option<RecordData> {
+        match record.data {
+            RecordData::Ping => Some(RecordData::Pong),
+            RecordData::BatteryRequest => {
+                // Drain slowly and "recharge" once empty so the LEDs go through every colour
+                let percent = self.battery.get();
+                self.battery.set(percent.checked_sub(1).unwrap_or(100));
+
+                Some(RecordData::BatteryResponse {
+                    percent,
+                    voltage: 3300 + percent as u16 * 9,
+                })
+            }
+            // The host only waits for the reliable ones
+            _ => Some(RecordData::Ack {
+                serial: record.serial,
+            }),
+        }
+    }
+}
+
+impl HidTransport for SimulatedDevice {
+    fn read_timeout(&self, buf: &mut [u8], timeout: i32) -> Result<usize, HidError> {
+        let Some(record) = self.pending.borrow_mut().pop_front() else {
+            sleep(Duration::from_millis(timeout.max(0) as u64));
+            return Ok(0);
+        };
+
+        encode_into_slice(record, buf, legacy()).map_err(|e| HidError::HidApiError {
+            message: e.to_string(),
+        })
+    }
+
+    fn write(&self, data: &[u8]) -> Result<usize, HidError> {
+        // Skip the report ID
+        let (record, _) = decode_from_slice::<Record, _>(&data[1..], legacy()).map_err(|e| {
+            HidError::HidApiError {
+                message: e.to_string(),
+            }
+        })?;
+        debug!("Simulated device got {record:?}");
+
+        if let Some(reply) = self.reply(&record) {
+            self.pending
+                .borrow_mut()
+                .push_back(Record::new(record.serial.wrapping_add(1), reply));
+        }
+
+        Ok(data.len())
+    }
+}
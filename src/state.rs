@@ -0,0 +1,191 @@
+use crate::battery::BatteryLevel;
+use crate::bus::{EventBus, Topic};
+use crate::heartbeat::HeartbeatStats;
+use crate::record::{Record, RecordData};
+use crate::steelseries::api::sonar::types::ClassicRedirection;
+use crate::write_queue::WriteQueueStats;
+use crate::{Event, SonarResponse};
+use serde::Serialize;
+use std::sync::{Arc, Mutex};
+
+/// Which keyboard the device thread is talking to.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub(crate) struct DeviceInfo {
+    pub(crate) vendor_id: u16,
+    pub(crate) product_id: u16,
+    pub(crate) simulated: bool,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Battery {
+    pub(crate) percent: u8,
+    pub(crate) voltage: u16,
+}
+
+/// Everything the app knows about the keyboard and Sonar. Values other than the connection are
+/// the last ones seen and stay in place while the keyboard is away.
+#[derive(Serialize, Debug, Clone, Default)]
+pub(crate) struct AppState {
+    pub(crate) connected: bool,
+    pub(crate) device: Option<DeviceInfo>,
+    pub(crate) heartbeat: Option<HeartbeatStats>,
+    pub(crate) write_queue: Option<WriteQueueStats>,
+    pub(crate) battery: Option<Battery>,
+    pub(crate) battery_level: BatteryLevel,
+    pub(crate) output_muted: Option<bool>,
+    pub(crate) input_muted: Option<bool>,
+    pub(crate) led_meter: Option<u8>,
+    pub(crate) sonar_redirections: Vec<ClassicRedirection>,
+}
+
+/// One field of [`AppState`] taking a new value. Changes carry the whole value, so applying one
+/// twice is harmless.
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "field", content = "value", rename_all = "snake_case")]
+pub(crate) enum StateChange {
+    Connected(bool),
+    Device(Option<DeviceInfo>),
+    Heartbeat(Option<HeartbeatStats>),
+    WriteQueue(Option<WriteQueueStats>),
+    Battery(Battery),
+    BatteryLevel(BatteryLevel),
+    OutputMuted(bool),
+    InputMuted(bool),
+    LedMeter(u8),
+    SonarRedirections(Vec<ClassicRedirection>),
+}
+
+impl AppState {
+    pub(crate) fn apply(&mut self, change: &StateChange) {
+        match change {
+            StateChange::Connected(connected) => self.connected = *connected,
+            StateChange::Device(device) => self.device = *device,
+            StateChange::Heartbeat(stats) => self.heartbeat = *stats,
+            StateChange::WriteQueue(stats) => self.write_queue = *stats,
+            StateChange::Battery(battery) => self.battery = Some(*battery),
+            StateChange::BatteryLevel(level) => self.battery_level = *level,
+            StateChange::OutputMuted(muted) => self.output_muted = Some(*muted),
+            StateChange::InputMuted(muted) => self.input_muted = Some(*muted),
+            StateChange::LedMeter(percent) => self.led_meter = Some(*percent),
+            StateChange::SonarRedirections(redirections) => {
+                self.sonar_redirections = redirections.clone()
+            }
+        }
+    }
+
+    /// What `event` changes, without applying it.
+    fn changes(&self, event: &Event) -> Vec<StateChange> {
+        let candidates = match event {
+            Event::DeviceConnected(device) => vec![
+                StateChange::Connected(true),
+                StateChange::Device(Some(*device)),
+            ],
+            Event::DeviceDisconnected => vec![
+                StateChange::Connected(false),
+                StateChange::Device(None),
+                StateChange::Heartbeat(None),
+                StateChange::WriteQueue(None),
+            ],
+            Event::Heartbeat(stats) => vec![StateChange::Heartbeat(Some(*stats))],
+            Event::WriteQueue(stats) => vec![StateChange::WriteQueue(Some(*stats))],
+            Event::RecordFromDevice(Record {
+                data: RecordData::BatteryResponse { percent, voltage },
+                ..
+            }) => vec![StateChange::Battery(Battery {
+                percent: *percent,
+                voltage: *voltage,
+            })],
+            Event::BatteryAlert { level, .. } => vec![StateChange::BatteryLevel(*level)],
+            Event::RecordSentToDevice(record) => match record.data {
+                RecordData::SetOutputMuteState { muted } => vec![StateChange::OutputMuted(muted)],
+                RecordData::SetInputMuteState { muted } => vec![StateChange::InputMuted(muted)],
+                RecordData::SetLedMeter { percent, .. } => vec![StateChange::LedMeter(percent)],
+                _ => vec![],
+            },
+            Event::SonarResponse(SonarResponse::FetchClassicRedirections(redirections)) => {
+                vec![StateChange::SonarRedirections(redirections.clone())]
+            }
+            Event::SonarResponse(SonarResponse::RedirectDevice(redirection)) => {
+                let mut redirections = self.sonar_redirections.clone();
+                match redirections.iter_mut().find(|r| r.id == redirection.id) {
+                    Some(existing) => *existing = redirection.clone(),
+                    None => redirections.push(redirection.clone()),
+                }
+                vec![StateChange::SonarRedirections(redirections)]
+            }
+            _ => vec![],
+        };
+
+        candidates
+            .into_iter()
+            .filter(|change| !self.has(change))
+            .collect()
+    }
+
+    fn has(&self, change: &StateChange) -> bool {
+        match change {
+            StateChange::Connected(connected) => self.connected == *connected,
+            StateChange::Device(device) => self.device == *device,
+            StateChange::Heartbeat(stats) => self.heartbeat == *stats,
+            StateChange::WriteQueue(stats) => self.write_queue == *stats,
+            StateChange::Battery(battery) => self.battery == Some(*battery),
+            StateChange::BatteryLevel(level) => self.battery_level == *level,
+            StateChange::OutputMuted(muted) => self.output_muted == Some(*muted),
+            StateChange::InputMuted(muted) => self.input_muted == Some(*muted),
+            StateChange::LedMeter(percent) => self.led_meter == Some(*percent),
+            StateChange::SonarRedirections(redirections) => {
+                self.sonar_redirections.len() == redirections.len()
+                    && self
+                        .sonar_redirections
+                        .iter()
+                        .zip(redirections)
+                        .all(|(a, b)| a.id == b.id && a.device_id == b.device_id)
+            }
+        }
+    }
+}
+
+/// The single copy of [`AppState`]. It follows the bus on its own thread and announces what
+/// changed as [`Event::StateChanged`], so consumers can start from a snapshot and keep up.
+#[derive(Clone)]
+pub(crate) struct StateStore {
+    state: Arc<Mutex<AppState>>,
+}
+
+impl StateStore {
+    pub(crate) fn start(bus: EventBus) -> Self {
+        let store = Self {
+            state: Arc::new(Mutex::new(AppState::default())),
+        };
+        let events = bus.subscribe(
+            "state",
+            &[
+                Topic::Device,
+                Topic::DeviceSent,
+                Topic::Battery,
+                Topic::SonarResponse,
+            ],
+        );
+
+        let state = store.state.clone();
+        std::thread::spawn(move || loop {
+            let event = events.blocking_recv();
+            let changes = {
+                let mut state = state.lock().unwrap();
+                let changes = state.changes(&event);
+                changes.iter().for_each(|change| state.apply(change));
+                changes
+            };
+
+            if !changes.is_empty() {
+                bus.publish(Event::StateChanged(changes));
+            }
+        });
+
+        store
+    }
+
+    pub(crate) fn snapshot(&self) -> AppState {
+        self.state.lock().unwrap().clone()
+    }
+}
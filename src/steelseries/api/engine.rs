@@ -1,8 +1,9 @@
 use serde::Deserialize;
 use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
 use std::fs::File;
 use std::io::BufReader;
-use std::path::PathBuf;
+use std::path::Path;
 
 #[derive(Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -42,6 +43,23 @@ struct CoreProps {
     address: String,
 }
 
+#[derive(Debug)]
+pub(crate) enum EngineError {
+    ReadCoreProps(std::io::Error),
+    ParseCoreProps(serde_json::Error),
+    Request(reqwest::Error),
+}
+
+impl Display for EngineError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EngineError::ReadCoreProps(e) => write!(f, "Failed to read coreProps.json: {e}"),
+            EngineError::ParseCoreProps(e) => write!(f, "Failed to parse coreProps.json: {e}"),
+            EngineError::Request(e) => write!(f, "SteelSeries GG request failed: {e}"),
+        }
+    }
+}
+
 impl SteelSeriesEngineClient {
     pub(crate) fn new(gg_encrypted_address: String) -> SteelSeriesEngineClient {
         Self {
@@ -49,42 +67,33 @@ impl SteelSeriesEngineClient {
         }
     }
 
-    pub(crate) fn new_autodetect() -> SteelSeriesEngineClient {
-        let default_path = PathBuf::from("C:\\ProgramData\\SteelSeries\\GG\\coreProps.json");
-        let file = File::open(default_path);
+    pub(crate) fn new_autodetect(core_props: &Path) -> Result<Self, EngineError> {
+        let file = File::open(core_props).map_err(EngineError::ReadCoreProps)?;
+        let core_props = serde_json::de::from_reader::<_, CoreProps>(BufReader::new(file))
+            .map_err(EngineError::ParseCoreProps)?;
 
-        match file {
-            Ok(file) => match serde_json::de::from_reader::<_, CoreProps>(BufReader::new(file)) {
-                Ok(coreprops) => Self::new(coreprops.gg_encrypted_address),
-                Err(err) => panic!("{}", err.to_string()),
-            },
-            _ => panic!("Failed to read coreProps.json"),
-        }
+        Ok(Self::new(core_props.gg_encrypted_address))
     }
-    async fn get_sub_apps_async(&self) -> SubAppResponseData {
-        let response = reqwest::Client::builder()
+    async fn get_sub_apps_async(&self) -> Result<SubAppResponseData, EngineError> {
+        reqwest::Client::builder()
             .danger_accept_invalid_certs(true)
             .build()
-            .expect("Failed to build HTTP client for SteelSeriesEngineClient")
+            .map_err(EngineError::Request)?
             .get(format!("https://{}/subApps", self.gg_encrypted_address))
             .send()
-            .await;
-
-        match response {
-            Ok(res) => res
-                .json::<SubAppResponseData>()
-                .await
-                .expect("Failed to decode subapp response data"),
-            Err(e) => panic!("Failed to get subapp response data: {}", e),
-        }
+            .await
+            .map_err(EngineError::Request)?
+            .json::<SubAppResponseData>()
+            .await
+            .map_err(EngineError::Request)
     }
-    pub async fn get_subapp_url(&self, app_name: &str) -> Option<String> {
-        let response = self.get_sub_apps_async().await;
-        let app = response.sub_apps.get(app_name);
-        match app {
-            Some(app) => Some(app.metadata.web_server_address.clone()),
-            None => None,
-        }
+    /// `None` when GG doesn't know the app.
+    pub async fn get_subapp_url(&self, app_name: &str) -> Result<Option<String>, EngineError> {
+        let response = self.get_sub_apps_async().await?;
+        Ok(response
+            .sub_apps
+            .get(app_name)
+            .map(|app| app.metadata.web_server_address.clone()))
     }
 }
 
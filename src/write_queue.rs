@@ -0,0 +1,233 @@
+use crate::record::{Record, RecordData};
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::mem::discriminant;
+use std::time::{Duration, Instant};
+
+/// Records a lane holds before the oldest ones are dropped.
+const CAPACITY: usize = 64;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Default)]
+pub(crate) struct WriteQueueStats {
+    /// Records waiting to be written.
+    pub(crate) depth: usize,
+    /// Deepest the queue got on this connection.
+    pub(crate) peak_depth: usize,
+    pub(crate) sent: u64,
+    /// Records replaced by a newer one of the same kind before they went out.
+    pub(crate) coalesced: u64,
+    /// Records lost to a full queue.
+    pub(crate) dropped: u64,
+}
+
+/// Records on their way to the keyboard. Mute states jump ahead of everything else, a newer
+/// LED meter or mute state replaces a queued one in place, and writes are spread out so the
+/// firmware never sees more than `device.max_reports_per_s`. Each record carries whether it's
+/// reliable, i.e. retransmitted until acked when `device.acks` is on.
+pub(crate) struct WriteQueue {
+    urgent: VecDeque<(Record, bool)>,
+    normal: VecDeque<(Record, bool)>,
+    /// Writes allowed right now, refilled at the configured rate.
+    tokens: f32,
+    last_refill: Instant,
+    stats: WriteQueueStats,
+    reported: Option<(Instant, WriteQueueStats)>,
+}
+
+impl WriteQueue {
+    const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+    pub(crate) fn new() -> Self {
+        Self {
+            urgent: VecDeque::new(),
+            normal: VecDeque::new(),
+            tokens: 1.0,
+            last_refill: Instant::now(),
+            stats: WriteQueueStats::default(),
+            reported: None,
+        }
+    }
+
+    pub(crate) fn push(&mut self, record: Record, reliable: bool) {
+        let lane = match record.data {
+            RecordData::SetOutputMuteState { .. } | RecordData::SetInputMuteState { .. } => {
+                &mut self.urgent
+            }
+            _ => &mut self.normal,
+        };
+
+        // Only the latest state matters, so the queued one takes its value and keeps its place
+        let is_state = matches!(
+            record.data,
+            RecordData::SetLedMeter { .. }
+                | RecordData::SetOutputMuteState { .. }
+                | RecordData::SetInputMuteState { .. }
+        );
+        let queued = lane
+            .iter_mut()
+            .find(|(queued, _)| discriminant(&queued.data) == discriminant(&record.data));
+        match queued {
+            Some(queued) if is_state => {
+                *queued = (record, reliable);
+                self.stats.coalesced += 1;
+            }
+            _ => {
+                if lane.len() >= CAPACITY {
+                    lane.pop_front();
+                    self.stats.dropped += 1;
+                }
+                lane.push_back((record, reliable));
+            }
+        }
+
+        self.stats.depth = self.urgent.len() + self.normal.len();
+        self.stats.peak_depth = self.stats.peak_depth.max(self.stats.depth);
+    }
+
+    /// Queues a retransmission, unless a newer record of the same kind is already waiting.
+    pub(crate) fn push_retry(&mut self, record: Record) {
+        let superseded = self
+            .urgent
+            .iter()
+            .chain(&self.normal)
+            .any(|(queued, _)| discriminant(&queued.data) == discriminant(&record.data));
+        if !superseded {
+            self.push(record, true);
+        }
+    }
+
+    /// The next record to write and whether it's reliable, if there is one and the rate limit
+    /// allows it. Bursts are capped at a tenth of a second's worth of writes.
+    pub(crate) fn pop(&mut self, max_per_s: u32) -> Option<(Record, bool)> {
+        let burst = (max_per_s as f32 / 10.0).max(1.0);
+        self.tokens =
+            (self.tokens + self.last_refill.elapsed().as_secs_f32() * max_per_s as f32).min(burst);
+        self.last_refill = Instant::now();
+        if self.tokens < 1.0 {
+            return None;
+        }
+
+        let queued = self
+            .urgent
+            .pop_front()
+            .or_else(|| self.normal.pop_front())?;
+        self.tokens -= 1.0;
+        self.stats.sent += 1;
+        self.stats.depth -= 1;
+
+        Some(queued)
+    }
+
+    /// The stats, at most once a second and only when they changed.
+    pub(crate) fn report(&mut self) -> Option<WriteQueueStats> {
+        if self
+            .reported
+            .is_some_and(|(at, stats)| at.elapsed() < Self::REPORT_INTERVAL || stats == self.stats)
+        {
+            return None;
+        }
+
+        self.reported = Some((Instant::now(), self.stats));
+        Some(self.stats)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn led_meter(percent: u8) -> Record {
+        Record::new(
+            percent as u32,
+            RecordData::SetLedMeter {
+                percent,
+                warning_threshold: 0,
+                danger_threshold: 0,
+                invert: false,
+                linger_time: 0,
+            },
+        )
+    }
+
+    fn drain(queue: &mut WriteQueue) -> Vec<Record> {
+        std::iter::from_fn(|| queue.pop(u32::MAX))
+            .map(|(record, _)| record)
+            .collect()
+    }
+
+    #[test]
+    fn newer_state_replaces_queued_one_in_place() {
+        let mut queue = WriteQueue::new();
+        let battery = Record::new(1, RecordData::BatteryRequest);
+        queue.push(led_meter(10), false);
+        queue.push(battery, false);
+        queue.push(led_meter(20), false);
+
+        assert_eq!(drain(&mut queue), [led_meter(20), battery]);
+        let stats = queue.report().unwrap();
+        assert_eq!((stats.coalesced, stats.sent, stats.peak_depth), (1, 2, 2));
+    }
+
+    #[test]
+    fn mute_states_jump_the_queue() {
+        let mut queue = WriteQueue::new();
+        let muted = Record::new(2, RecordData::SetInputMuteState { muted: true });
+        queue.push(led_meter(10), false);
+        queue.push(muted, true);
+
+        assert_eq!(queue.pop(u32::MAX), Some((muted, true)));
+        assert_eq!(queue.pop(u32::MAX), Some((led_meter(10), false)));
+    }
+
+    #[test]
+    fn full_lane_drops_its_oldest_record() {
+        let mut queue = WriteQueue::new();
+        (0..=CAPACITY as u32)
+            .for_each(|serial| queue.push(Record::new(serial, RecordData::BatteryRequest), false));
+
+        let written = drain(&mut queue);
+        assert_eq!(written.len(), CAPACITY);
+        assert_eq!(written[0].serial, 1);
+        assert_eq!(queue.report().unwrap().dropped, 1);
+    }
+
+    #[test]
+    fn pop_stops_once_the_burst_is_spent() {
+        let mut queue = WriteQueue::new();
+        (0..20)
+            .for_each(|serial| queue.push(Record::new(serial, RecordData::BatteryRequest), false));
+
+        // A second's worth of tokens, capped at a tenth of a second's worth
+        queue.last_refill = Instant::now() - Duration::from_secs(1);
+        let sent = std::iter::from_fn(|| queue.pop(100)).count();
+        assert_eq!(sent, 10);
+    }
+
+    #[test]
+    fn retry_gives_way_to_a_newer_record() {
+        let mut queue = WriteQueue::new();
+        let newer = Record::new(3, RecordData::SetOutputMuteState { muted: false });
+        let older = Record::new(1, RecordData::SetOutputMuteState { muted: true });
+        queue.push(newer, true);
+        queue.push_retry(older);
+        assert_eq!(drain(&mut queue), [newer]);
+
+        queue.push_retry(older);
+        assert_eq!(queue.pop(u32::MAX), Some((older, true)));
+    }
+
+    #[test]
+    fn report_is_throttled_and_skips_unchanged_stats() {
+        let mut queue = WriteQueue::new();
+        assert!(queue.report().is_some());
+        queue.push(led_meter(10), false);
+        assert_eq!(queue.report(), None);
+
+        let long_ago = Instant::now() - WriteQueue::REPORT_INTERVAL;
+        queue.reported = queue.reported.map(|(_, stats)| (long_ago, stats));
+        assert_eq!(queue.report().map(|stats| stats.depth), Some(1));
+
+        queue.reported = queue.reported.map(|(_, stats)| (long_ago, stats));
+        assert_eq!(queue.report(), None);
+    }
+}